@@ -33,19 +33,21 @@ impl SharedVertexData<'_> {
         let w = T::AO_WIDTH as isize;
         if root.is_detail() {
             let bound = |x| x < 0 || x > T::LAST as isize;
+            // a mask of the sides of the cube that the voxel occludes faces on, where bit `side`
+            // is set if the voxel fully covers the opposite side, see `Shape::is_full`
             let sample_occlusion = |x, y, z| {
-                if bound(x) || bound(y) || bound(z) {
-                    if neighbours.visible(x, y, z) {
-                        1
-                    } else {
-                        0
-                    }
-                } else if root
-                    .get(T::coord_to_index(x as usize, y as usize, z as usize))
-                    .unwrap()
-                    .visible()
-                {
-                    1
+                let (visible, shape) = if bound(x) || bound(y) || bound(z) {
+                    (neighbours.visible(x, y, z), neighbours.shape(x, y, z))
+                } else {
+                    let voxel = root
+                        .get(T::coord_to_index(x as usize, y as usize, z as usize))
+                        .unwrap();
+                    (voxel.visible(), voxel.shape())
+                };
+                if visible {
+                    (0..6)
+                        .filter(|&side| shape.is_full(side ^ 1))
+                        .fold(0u16, |mask, side| mask | 1 << side)
                 } else {
                     0
                 }
@@ -60,18 +62,23 @@ impl SharedVertexData<'_> {
                 }
             };
             let process = |s: [u16; 8]| {
-                let table = |s: [u16; 4]| match s {
+                let table = |side: u16, s: [u16; 4]| match [
+                    (s[0] >> side) & 1,
+                    (s[1] >> side) & 1,
+                    (s[2] >> side) & 1,
+                    (s[3] >> side) & 1,
+                ] {
                     [0, 0, 0, 0] => 0,
                     [1, 0, 0, 0] | [0, 1, 0, 0] | [0, 0, 1, 0] | [0, 0, 0, 1] => 1,
                     [1, 1, 0, 0] | [0, 0, 1, 1] | [0, 1, 0, 1] | [1, 0, 1, 0] => 2,
                     _ => 3,
                 };
-                let neg_x = table([s[0], s[1], s[4], s[5]]);
-                let pos_x = table([s[2], s[3], s[6], s[7]]);
-                let neg_y = table([s[0], s[1], s[2], s[3]]);
-                let pos_y = table([s[4], s[5], s[6], s[7]]);
-                let neg_z = table([s[0], s[2], s[4], s[6]]);
-                let pos_z = table([s[1], s[3], s[5], s[7]]);
+                let neg_x = table(0, [s[0], s[1], s[4], s[5]]);
+                let pos_x = table(1, [s[2], s[3], s[6], s[7]]);
+                let neg_y = table(2, [s[0], s[1], s[2], s[3]]);
+                let pos_y = table(3, [s[4], s[5], s[6], s[7]]);
+                let neg_z = table(4, [s[0], s[2], s[4], s[6]]);
+                let pos_z = table(5, [s[1], s[3], s[5], s[7]]);
 
                 (neg_x << 10) | (pos_x << 8) | (neg_y << 6) | (pos_y << 4) | (neg_z << 2) | (pos_z)
             };
@@ -205,6 +212,39 @@ impl SharedVertexData<'_> {
         }
     }
 
+    /// Shared vertex data of the cube of `stride` subvoxels along every axis starting at the
+    /// subvoxel at x, y, z. Nested detail is ignored.
+    pub fn block(&self, x: usize, y: usize, z: usize, stride: usize) -> SharedVertexData<'_> {
+        match *self {
+            SharedVertexData::Big {
+                ref occlusion,
+                width,
+                ..
+            } => {
+                let index = x + y * width + z * width * width;
+                let x = stride;
+                let y = stride * width;
+                let z = stride * width * width;
+                SharedVertexData::Small {
+                    occlusion: [
+                        occlusion[index],
+                        occlusion[index + x],
+                        occlusion[index + y],
+                        occlusion[index + y + x],
+                        occlusion[index + z],
+                        occlusion[index + z + x],
+                        occlusion[index + z + y],
+                        occlusion[index + z + y + x],
+                    ],
+                }
+            }
+
+            SharedVertexData::Borrowed { target } => target.block(x, y, z, stride),
+
+            SharedVertexData::Small { .. } => unreachable!(),
+        }
+    }
+
     pub fn quad<S: Side>(&self) -> [SharedVertex; 4] {
         let f = |d: Vertex, s: u16| SharedVertex {
             occlusion: 1.0 - f32::from((d.occlusion >> s) & 0x03) / 4.0,
@@ -0,0 +1,600 @@
+//! A compact binary format for voxels.
+//!
+//! An encoded voxel starts with a magic number and the format version, followed by a table of the
+//! materials used by the voxel. Materials are stored by their string id when possible, so that
+//! encoded voxels survive changes to the atlas. The table is followed by the voxel itself, where the
+//! subvoxels of detail voxels are run length encoded. A CRC-32 checksum of all preceding bytes
+//! concludes the encoded voxel.
+//!
+//! User data is stored through `Data::write_payload` and `Data::read_payload`.
+
+use crate::material::{AtlasMaterialHandle, MaterialIds};
+use crate::shape::Shape;
+use crate::storage::{DetailStorage, PaletteStorage};
+use crate::voxel::{Data, NestedVoxel, SimpleVoxel, Voxel};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::iter::repeat;
+use std::mem::replace;
+use std::sync::Arc;
+
+type E = LittleEndian;
+
+const MAGIC: &[u8; 4] = b"AVXL";
+
+/// The version of the binary format written by `encode`.
+pub const VERSION: u16 = 1;
+
+const EMPTY: u8 = 0;
+const MATERIAL: u8 = 1;
+const DETAIL: u8 = 2;
+const PALETTE: u8 = 3;
+const PLACEHOLDER: u8 = 4;
+const SHAPED: u8 = 5;
+
+const MATERIAL_INDEX: u8 = 0;
+const MATERIAL_ID: u8 = 1;
+
+/// A voxel type that can be stored in the binary format.
+pub trait BinaryVoxel: Voxel {
+    /// Write the voxel to an `Encoder`.
+    fn write_binary(&self, encoder: &mut Encoder) -> Result<()>;
+
+    /// Read a voxel that was written by `write_binary` from a `Decoder`.
+    fn read_binary(decoder: &mut Decoder) -> Result<Self>;
+}
+
+/// Writer for the body of the binary format. Keeps track of the materials that are used.
+#[derive(Default)]
+pub struct Encoder {
+    out: Vec<u8>,
+    lookup: HashMap<AtlasMaterialHandle, u64>,
+    table: Vec<AtlasMaterialHandle>,
+}
+
+/// Reader for the body of the binary format.
+pub struct Decoder<'a> {
+    input: &'a [u8],
+    materials: Vec<AtlasMaterialHandle>,
+}
+
+/// Encode a voxel to the binary format.
+/// Materials that have an id in `ids` are stored by that id, others by their raw atlas index.
+pub fn encode<V: BinaryVoxel>(voxel: &V, ids: &MaterialIds) -> Result<Vec<u8>> {
+    let mut body = Encoder::default();
+    voxel.write_binary(&mut body)?;
+
+    let mut out = Vec::with_capacity(body.out.len() + 16);
+    out.extend_from_slice(MAGIC);
+    out.write_u16::<E>(VERSION)?;
+    write_varint(&mut out, body.table.len() as u64);
+    for &material in body.table.iter() {
+        match ids.id(material) {
+            Some(id) => {
+                out.push(MATERIAL_ID);
+                write_varint(&mut out, id.len() as u64);
+                out.extend_from_slice(id.as_bytes());
+            }
+            None => {
+                out.push(MATERIAL_INDEX);
+                write_varint(&mut out, u64::from(material.0));
+            }
+        }
+    }
+    out.extend_from_slice(&body.out);
+
+    let checksum = crc32(&out);
+    out.write_u32::<E>(checksum)?;
+    Ok(out)
+}
+
+/// Decode a voxel from the binary format.
+/// Materials that were stored by id are looked up in `ids`, unknown ids result in an error.
+pub fn decode<V: BinaryVoxel>(bytes: &[u8], ids: &MaterialIds) -> Result<V> {
+    check(bytes.len() >= MAGIC.len() + 6, "voxel data is truncated")?;
+    let (mut input, mut checksum) = bytes.split_at(bytes.len() - 4);
+    check(
+        checksum.read_u32::<E>()? == crc32(input),
+        "voxel data checksum mismatch",
+    )?;
+
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    check(&magic == MAGIC, "not binary voxel data")?;
+    let version = input.read_u16::<E>()?;
+    check(version == VERSION, "unsupported binary voxel version")?;
+
+    let mut materials = Vec::new();
+    for _ in 0..read_varint(&mut input)? {
+        let material = match input.read_u8()? {
+            MATERIAL_INDEX => {
+                let index = read_varint(&mut input)?;
+                check(
+                    index <= u64::from(std::u32::MAX),
+                    "material index out of range",
+                )?;
+                AtlasMaterialHandle(index as u32)
+            }
+            MATERIAL_ID => {
+                let len = read_varint(&mut input)? as usize;
+                check(len <= input.len(), "voxel data is truncated")?;
+                let (id, rest) = input.split_at(len);
+                input = rest;
+                let id = std::str::from_utf8(id)
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "material id is not utf-8"))?;
+                ids.get(id).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unknown material id `{}`", id),
+                    )
+                })?
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "invalid material entry")),
+        };
+        materials.push(material);
+    }
+
+    let mut decoder = Decoder { input, materials };
+    let voxel = V::read_binary(&mut decoder)?;
+    check(decoder.input.is_empty(), "trailing bytes after voxel data")?;
+    Ok(voxel)
+}
+
+impl Encoder {
+    /// Write an unsigned integer using a variable amount of bytes.
+    pub fn write_varint(&mut self, value: u64) {
+        write_varint(&mut self.out, value);
+    }
+
+    /// Write a reference to a material. The material is added to the material table.
+    pub fn write_material(&mut self, material: AtlasMaterialHandle) {
+        let table = &mut self.table;
+        let index = *self.lookup.entry(material).or_insert_with(|| {
+            table.push(material);
+            table.len() as u64 - 1
+        });
+        self.write_varint(index);
+    }
+
+    /// Write the shape of a filled voxel.
+    pub fn write_shape(&mut self, shape: Shape) {
+        self.out.push(shape.to_byte());
+    }
+
+    /// Write user data, prefixed by it's length so that it can be validated when reading.
+    fn write_payload<T: Data>(&mut self, data: &T) -> Result<()> {
+        let mut payload = Vec::new();
+        data.write_payload(&mut payload)?;
+        self.write_varint(payload.len() as u64);
+        self.out.extend_from_slice(&payload);
+        Ok(())
+    }
+
+    /// Write subvoxels as runs of identical subvoxels.
+    fn write_runs<'a, C, I>(&mut self, children: I) -> Result<()>
+    where
+        C: 'a + BinaryVoxel,
+        I: IntoIterator<Item = &'a C>,
+    {
+        let mut current: Option<(Vec<u8>, u64)> = None;
+        for child in children {
+            let outer = replace(&mut self.out, Vec::new());
+            let result = child.write_binary(self);
+            let bytes = replace(&mut self.out, outer);
+            result?;
+
+            match current {
+                Some((ref previous, ref mut run)) if *previous == bytes => *run += 1,
+                _ => {
+                    if let Some((previous, run)) = current.replace((bytes, 1)) {
+                        self.write_varint(run);
+                        self.out.extend_from_slice(&previous);
+                    }
+                }
+            }
+        }
+        if let Some((previous, run)) = current {
+            self.write_varint(run);
+            self.out.extend_from_slice(&previous);
+        }
+        Ok(())
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.out.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Decoder<'a> {
+    /// Read an unsigned integer written by `Encoder::write_varint`.
+    pub fn read_varint(&mut self) -> Result<u64> {
+        read_varint(&mut self.input)
+    }
+
+    /// Read a material written by `Encoder::write_material`.
+    pub fn read_material(&mut self) -> Result<AtlasMaterialHandle> {
+        let index = self.read_varint()?;
+        self.materials
+            .get(index as usize)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "material not in material table"))
+    }
+
+    /// Read a shape written by `Encoder::write_shape`.
+    pub fn read_shape(&mut self) -> Result<Shape> {
+        Shape::from_byte(self.read_u8()?)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid voxel shape"))
+    }
+
+    fn read_payload<T: Data>(&mut self) -> Result<T> {
+        let len = self.read_varint()? as usize;
+        check(len <= self.input.len(), "voxel data is truncated")?;
+        let (mut payload, rest) = self.input.split_at(len);
+        self.input = rest;
+        let data = T::read_payload(&mut payload)?;
+        check(payload.is_empty(), "payload was not read completely")?;
+        Ok(data)
+    }
+
+    fn read_runs<C: BinaryVoxel>(&mut self, count: usize) -> Result<Vec<C>> {
+        let mut children = Vec::with_capacity(count);
+        while children.len() < count {
+            let run = self.read_varint()? as usize;
+            check(
+                run > 0 && run <= count - children.len(),
+                "invalid subvoxel run",
+            )?;
+            let child = C::read_binary(self)?;
+            children.extend(repeat(child).take(run));
+        }
+        Ok(children)
+    }
+}
+
+impl<'a> Read for Decoder<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl<T: Data> BinaryVoxel for NestedVoxel<T>
+where
+    T::Child: BinaryVoxel + PartialEq,
+{
+    fn write_binary(&self, encoder: &mut Encoder) -> Result<()> {
+        match *self {
+            NestedVoxel::Empty { ref data } => {
+                encoder.out.push(EMPTY);
+                encoder.write_payload(data)
+            }
+            NestedVoxel::Material {
+                material,
+                shape,
+                ref data,
+            } => {
+                if shape.is_cube() {
+                    encoder.out.push(MATERIAL);
+                    encoder.write_material(material);
+                } else {
+                    encoder.out.push(SHAPED);
+                    encoder.write_material(material);
+                    encoder.write_shape(shape);
+                }
+                encoder.write_payload(data)
+            }
+            NestedVoxel::Detail {
+                ref detail,
+                ref data,
+            } => match **detail {
+                DetailStorage::Plain(ref plain) => {
+                    encoder.out.push(DETAIL);
+                    encoder.write_payload(data)?;
+                    encoder.write_runs(plain.iter())
+                }
+                DetailStorage::Palette(ref palette) => {
+                    encoder.out.push(PALETTE);
+                    encoder.write_payload(data)?;
+                    encoder.write_varint(palette.entries().len() as u64);
+                    for entry in palette.entries() {
+                        entry.write_binary(encoder)?;
+                    }
+                    let mut indices = palette.indices().peekable();
+                    while let Some(index) = indices.next() {
+                        let mut run = 1;
+                        while indices.peek() == Some(&index) {
+                            indices.next();
+                            run += 1;
+                        }
+                        encoder.write_varint(run);
+                        encoder.write_varint(index as u64);
+                    }
+                    Ok(())
+                }
+            },
+            NestedVoxel::Placeholder => {
+                encoder.out.push(PLACEHOLDER);
+                Ok(())
+            }
+        }
+    }
+
+    fn read_binary(decoder: &mut Decoder) -> Result<Self> {
+        match decoder.read_u8()? {
+            EMPTY => Ok(NestedVoxel::Empty {
+                data: decoder.read_payload()?,
+            }),
+            MATERIAL => Ok(NestedVoxel::Material {
+                material: decoder.read_material()?,
+                shape: Shape::default(),
+                data: decoder.read_payload()?,
+            }),
+            SHAPED => Ok(NestedVoxel::Material {
+                material: decoder.read_material()?,
+                shape: decoder.read_shape()?,
+                data: decoder.read_payload()?,
+            }),
+            DETAIL => {
+                let data = decoder.read_payload()?;
+                let detail = decoder.read_runs(Self::COUNT)?;
+                Ok(NestedVoxel::from_iter_packed(data, detail))
+            }
+            PALETTE => {
+                let data = decoder.read_payload()?;
+                let entries = decoder.read_varint()? as usize;
+                check(entries <= Self::COUNT, "palette is too large")?;
+                let palette = (0..entries)
+                    .map(|_| T::Child::read_binary(decoder))
+                    .collect::<Result<Vec<_>>>()?;
+                let mut indices = Vec::with_capacity(Self::COUNT);
+                while indices.len() < Self::COUNT {
+                    let run = decoder.read_varint()? as usize;
+                    check(
+                        run > 0 && run <= Self::COUNT - indices.len(),
+                        "invalid subvoxel run",
+                    )?;
+                    let index = decoder.read_varint()? as usize;
+                    indices.extend(repeat(index).take(run));
+                }
+                let palette = PaletteStorage::from_parts(palette, &indices).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, "palette index out of range")
+                })?;
+                let mut voxel = Self::Detail {
+                    detail: Arc::new(DetailStorage::Palette(palette)),
+                    data,
+                };
+                voxel.pack();
+                Ok(voxel)
+            }
+            PLACEHOLDER => Ok(NestedVoxel::Placeholder),
+            _ => Err(Error::new(ErrorKind::InvalidData, "invalid voxel kind")),
+        }
+    }
+}
+
+impl BinaryVoxel for SimpleVoxel {
+    fn write_binary(&self, encoder: &mut Encoder) -> Result<()> {
+        match self.material() {
+            Some(material) if self.shape().is_cube() => {
+                encoder.out.push(MATERIAL);
+                encoder.write_material(material);
+            }
+            Some(material) => {
+                encoder.out.push(SHAPED);
+                encoder.write_material(material);
+                encoder.write_shape(self.shape());
+            }
+            None => encoder.out.push(EMPTY),
+        }
+        Ok(())
+    }
+
+    fn read_binary(decoder: &mut Decoder) -> Result<Self> {
+        match decoder.read_u8()? {
+            EMPTY => Ok(SimpleVoxel::new_empty(())),
+            MATERIAL => Ok(SimpleVoxel::new_filled((), decoder.read_material()?)),
+            SHAPED => {
+                let material = decoder.read_material()?;
+                Ok(SimpleVoxel::new_shaped((), material, decoder.read_shape()?))
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, "invalid voxel kind")),
+        }
+    }
+}
+
+fn check(b: bool, message: &'static str) -> Result<()> {
+    if b {
+        Ok(())
+    } else {
+        Err(Error::new(ErrorKind::InvalidData, message))
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = input.read_u8()?;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "varint is too long"))
+}
+
+lazy_static::lazy_static! {
+    static ref CRC_TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            }
+            *entry = crc;
+        }
+        table
+    };
+}
+
+/// CRC-32 (IEEE) checksum of `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::ShapeKind;
+
+    #[derive(Clone, Default, PartialEq)]
+    struct Inner;
+
+    impl Data for Inner {
+        const SUBDIV: usize = 1;
+        type Child = SimpleVoxel;
+    }
+
+    #[derive(Clone, Default, PartialEq)]
+    struct Outer(u32);
+
+    impl Data for Outer {
+        const SUBDIV: usize = 1;
+        type Child = NestedVoxel<Inner>;
+
+        fn write_payload(&self, writer: &mut dyn Write) -> Result<()> {
+            writer.write_u32::<E>(self.0)
+        }
+
+        fn read_payload(reader: &mut dyn Read) -> Result<Self> {
+            reader.read_u32::<E>().map(Outer)
+        }
+    }
+
+    const STONE: AtlasMaterialHandle = AtlasMaterialHandle(0);
+    const DIRT: AtlasMaterialHandle = AtlasMaterialHandle(3);
+
+    fn ids() -> MaterialIds {
+        vec![("stone", STONE)].into_iter().collect()
+    }
+
+    fn roundtrip<V: BinaryVoxel + PartialEq>(voxel: &V) -> V {
+        let bytes = encode(voxel, &ids()).unwrap();
+        let decoded: V = decode(&bytes, &ids()).unwrap();
+        assert!(decoded == *voxel);
+        decoded
+    }
+
+    fn inner(offset: usize) -> NestedVoxel<Inner> {
+        NestedVoxel::from_iter(
+            Inner,
+            (0..8).map(|i| match (i + offset) % 3 {
+                0 => SimpleVoxel::new_filled((), STONE),
+                1 => SimpleVoxel::new_filled((), DIRT),
+                _ => SimpleVoxel::new_empty(()),
+            }),
+        )
+    }
+
+    #[test]
+    fn empty() {
+        roundtrip(&NestedVoxel::new_empty(Inner));
+        roundtrip(&NestedVoxel::<Inner>::Placeholder);
+    }
+
+    #[test]
+    fn material() {
+        // stone is stored by it's id, dirt by it's atlas index
+        roundtrip(&NestedVoxel::new_filled(Inner, STONE));
+        roundtrip(&NestedVoxel::new_filled(Inner, DIRT));
+
+        let bytes = encode(&NestedVoxel::new_filled(Inner, STONE), &ids()).unwrap();
+        let moved = vec![("stone", DIRT)].into_iter().collect();
+        let decoded: NestedVoxel<Inner> = decode(&bytes, &moved).unwrap();
+        assert!(decoded.material() == Some(DIRT));
+    }
+
+    #[test]
+    fn nested_detail() {
+        let voxel = NestedVoxel::from_iter(
+            Outer(7),
+            (0..8).map(|i| match i {
+                0 => NestedVoxel::new_empty(Inner),
+                1 => NestedVoxel::new_filled(Inner, STONE),
+                _ => inner(i),
+            }),
+        );
+        let decoded = roundtrip(&voxel);
+        assert!(decoded.get(5).unwrap().is_detail());
+        assert!(decoded.get(5).unwrap().get(1) == inner(5).get(1));
+    }
+
+    #[test]
+    fn shapes() {
+        let stairs = Shape::new(ShapeKind::Stairs, 13);
+        let slope = Shape::new(ShapeKind::Slope, 5);
+        let decoded = roundtrip(&NestedVoxel::new_shaped(Inner, DIRT, stairs));
+        assert_eq!(decoded.shape(), stairs);
+
+        let voxel = NestedVoxel::from_iter(
+            Inner,
+            (0..8).map(|i| match i % 3 {
+                0 => SimpleVoxel::new_shaped((), STONE, slope),
+                1 => SimpleVoxel::new_filled((), STONE),
+                _ => SimpleVoxel::new_empty(()),
+            }),
+        );
+        let decoded = roundtrip(&voxel);
+        assert_eq!(decoded.get(3).unwrap().shape(), slope);
+        assert!(decoded.get(1).unwrap().shape().is_cube());
+    }
+
+    #[test]
+    fn palette() {
+        let voxel = NestedVoxel::from_iter_packed(Outer(0), (0..8).map(|i| inner(i % 2)));
+        assert!(voxel.detail().unwrap().is_packed());
+        let decoded = roundtrip(&voxel);
+        assert!(decoded.detail().unwrap().is_packed());
+    }
+
+    #[test]
+    fn payload() {
+        let voxel = NestedVoxel::new_empty(Outer(0xdead_beef));
+        let decoded = roundtrip(&voxel);
+        match decoded {
+            NestedVoxel::Empty { data } => assert_eq!(data.0, 0xdead_beef),
+            _ => panic!("decoded voxel is not empty"),
+        }
+    }
+
+    #[test]
+    fn corrupted_checksum() {
+        let voxel = NestedVoxel::from_iter(Outer(1), (0..8).map(inner));
+        let bytes = encode(&voxel, &ids()).unwrap();
+        for &position in &[MAGIC.len() + 2, bytes.len() / 2, bytes.len() - 1] {
+            let mut corrupted = bytes.clone();
+            corrupted[position] ^= 0x10;
+            let error = decode::<NestedVoxel<Outer>>(&corrupted, &ids())
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), "voxel data checksum mismatch");
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::material::AtlasProcessor;
+use crate::origin::{FloatingOrigin, FloatingOriginSystem};
 use crate::{mesh::*, voxel::Data, world::VoxelSource, world::VoxelWorld};
 use amethyst::{
     core::bundle::SystemBundle,
@@ -15,9 +16,14 @@ type SystemRegistrator = dyn for<'a, 'b> FnOnce(&mut World, &mut DispatcherBuild
 /// Main bundle for supporting voxels in your amethyst project.
 /// Before any `Voxel<T>` type will work,
 ///  you have to specify which `Data` and `Source` implementations you plan to use.
+///
+/// When using a floating origin, add this bundle before the `TransformBundle`, so that the origin
+/// is moved before the transforms are propagated.
 pub struct VoxelBundle {
     triangulation_limit: usize,
+    floating_origin: Option<FloatingOrigin>,
     systems: Vec<Box<SystemRegistrator>>,
+    world_systems: Vec<&'static str>,
     pool: Arc<ThreadPool>,
 }
 
@@ -25,7 +31,9 @@ impl VoxelBundle {
     pub fn new(triangulation_limit: usize) -> Self {
         VoxelBundle {
             triangulation_limit,
+            floating_origin: None,
             systems: Vec::new(),
+            world_systems: Vec::new(),
             pool: Arc::new(
                 ThreadPoolBuilder::new()
                     .num_threads(2)
@@ -42,6 +50,7 @@ impl VoxelBundle {
         S: for<'s> VoxelSource<'s, V> + Component + Send + Sync,
     {
         let pool = self.pool.clone();
+        self.world_systems.push("world_sourcing");
         self.systems.push(Box::new(|_world, builder| {
             builder.add(
                 crate::world::WorldSystem::<V, S>::new(pool),
@@ -52,6 +61,12 @@ impl VoxelBundle {
         self
     }
 
+    /// Move the origin of the render space along with the camera, see `FloatingOrigin`.
+    pub fn with_floating_origin(mut self, chunk_size: f32, threshold: f32) -> Self {
+        self.floating_origin = Some(FloatingOrigin::new(chunk_size, threshold));
+        self
+    }
+
     /// Configure systems that work with `Data` `V`.
     pub fn with_voxel<B: Backend, V: Data + Default>(mut self) -> Self {
         self.systems.push(Box::new({
@@ -80,6 +95,11 @@ impl<'a, 'b> SystemBundle<'a, 'b> for VoxelBundle {
         for sys in self.systems.into_iter() {
             sys(world, builder);
         }
+        // the worlds load chunks around the viewers before the origin moves them
+        if let Some(floating_origin) = self.floating_origin {
+            world.insert(floating_origin);
+            builder.add(FloatingOriginSystem, "floating_origin", &self.world_systems);
+        }
         Ok(())
     }
 }
@@ -0,0 +1,240 @@
+use crate::mesh::VoxelMesh;
+use crate::storage::DetailStorage;
+use crate::voxel::{Data, NestedVoxel, Voxel};
+
+use amethyst::assets::Handle;
+
+use std::collections::{HashMap, VecDeque};
+use std::mem::size_of_val;
+
+/// Statistics on the recently unloaded chunks cached by a `VoxelWorld`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The amount of chunks in the cache.
+    pub chunks: usize,
+    /// The estimated memory used by the chunks and their meshes in the cache.
+    pub bytes: usize,
+    /// The amount of chunks that were taken from the cache instead of being loaded.
+    pub hits: u64,
+    /// The amount of chunks that were loaded because they were not in the cache.
+    pub misses: u64,
+}
+
+/// A chunk that was unloaded, along with it's last built mesh and the level of detail of that mesh.
+pub(crate) struct CachedChunk<T: Data> {
+    pub voxel: NestedVoxel<T>,
+    pub mesh: Option<Handle<VoxelMesh>>,
+    pub lod: usize,
+    /// The generations of the meshes of the 26 neighbours when the chunk was unloaded, `None` for
+    /// neighbours that were not loaded. `None` if the mesh was already outdated.
+    neighbours: Option<Vec<Option<u64>>>,
+    bytes: usize,
+}
+
+/// A least recently used cache of unloaded chunks.
+pub(crate) struct ChunkCache<T: Data> {
+    chunks: HashMap<[i64; 3], CachedChunk<T>>,
+    /// Coordinates of the cached chunks, from least to most recently unloaded.
+    order: VecDeque<[i64; 3]>,
+    max_chunks: usize,
+    max_bytes: usize,
+    stats: CacheStats,
+}
+
+impl<T: Data> CachedChunk<T> {
+    /// Create a cached chunk, `mesh_bytes` is the size of the vertex and index data of `mesh`.
+    /// `neighbours` are the generations of the meshes of the neighbours that `mesh` was built with,
+    /// or `None` if it is outdated.
+    pub fn new(
+        voxel: NestedVoxel<T>,
+        mesh: Option<Handle<VoxelMesh>>,
+        mesh_bytes: usize,
+        lod: usize,
+        neighbours: Option<Vec<Option<u64>>>,
+    ) -> Self {
+        let bytes = size_of_val(&voxel) + memory(&voxel) + mesh_bytes;
+        CachedChunk {
+            voxel,
+            mesh,
+            lod,
+            neighbours,
+            bytes,
+        }
+    }
+
+    /// Returns for each neighbour whether it changed since the chunk was unloaded, given the
+    /// current generations of their meshes. Every neighbour changed if the mesh is outdated.
+    pub fn changed(&self, neighbours: &[Option<u64>]) -> Vec<bool> {
+        match self.neighbours {
+            Some(ref cached) => cached
+                .iter()
+                .zip(neighbours.iter())
+                .map(|(cached, current)| cached != current)
+                .collect(),
+            None => vec![true; neighbours.len()],
+        }
+    }
+}
+
+impl<T: Data> ChunkCache<T> {
+    pub fn new(max_chunks: usize, max_bytes: usize) -> Self {
+        ChunkCache {
+            chunks: HashMap::new(),
+            order: VecDeque::new(),
+            max_chunks,
+            max_bytes,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Returns whether chunks are cached at all.
+    pub fn enabled(&self) -> bool {
+        self.max_chunks > 0 && self.max_bytes > 0
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Add a chunk to the cache, evicting the least recently unloaded chunks if the cache is full.
+    pub fn insert(&mut self, coord: [i64; 3], chunk: CachedChunk<T>) {
+        if !self.enabled() {
+            return;
+        }
+
+        self.remove(coord);
+        self.stats.bytes += chunk.bytes;
+        self.chunks.insert(coord, chunk);
+        self.order.push_back(coord);
+
+        while self.chunks.len() > self.max_chunks || self.stats.bytes > self.max_bytes {
+            match self.order.front().cloned() {
+                Some(oldest) => {
+                    self.remove(oldest);
+                }
+                None => break,
+            }
+        }
+        self.stats.chunks = self.chunks.len();
+    }
+
+    /// Take a chunk out of the cache, counting a hit if it was cached.
+    pub fn take(&mut self, coord: [i64; 3]) -> Option<CachedChunk<T>> {
+        let chunk = self.remove(coord);
+        if chunk.is_some() {
+            self.stats.hits += 1;
+        }
+        chunk
+    }
+
+    /// Count a chunk that had to be loaded.
+    pub fn miss(&mut self) {
+        self.stats.misses += 1;
+    }
+
+    fn remove(&mut self, coord: [i64; 3]) -> Option<CachedChunk<T>> {
+        let chunk = self.chunks.remove(&coord)?;
+        if let Some(position) = self.order.iter().position(|&c| c == coord) {
+            self.order.remove(position);
+        }
+        self.stats.bytes -= chunk.bytes;
+        self.stats.chunks = self.chunks.len();
+        Some(chunk)
+    }
+}
+
+/// Estimate the memory used by the subvoxels of a chunk. Palette compressed subvoxels are
+/// estimated from their palette entries and packed indices.
+fn memory<T: Data>(voxel: &NestedVoxel<T>) -> usize {
+    match voxel.detail() {
+        Some(DetailStorage::Plain(plain)) => plain
+            .iter()
+            .map(|child| size_of_val(child) + nested_memory(child))
+            .sum(),
+        Some(DetailStorage::Palette(palette)) => {
+            palette.index_bytes()
+                + palette
+                    .entries()
+                    .iter()
+                    .map(|entry| size_of_val(entry) + nested_memory(entry))
+                    .sum::<usize>()
+        }
+        None => 0,
+    }
+}
+
+/// Estimate the memory used by the subvoxels of a voxel.
+/// Subvoxels that are shared between voxels are counted for every voxel.
+fn nested_memory<V: Voxel>(voxel: &V) -> usize {
+    if voxel.is_detail() {
+        (0..V::COUNT)
+            .filter_map(|i| voxel.get(i))
+            .map(|child| size_of_val(child) + nested_memory(child))
+            .sum()
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::AtlasMaterialHandle;
+    use crate::voxel::SimpleVoxel;
+    use std::iter::repeat;
+
+    #[derive(Clone, Default, PartialEq)]
+    struct Chunk;
+
+    impl Data for Chunk {
+        const SUBDIV: usize = 4;
+        type Child = SimpleVoxel;
+    }
+
+    #[test]
+    fn mesh_counts_towards_budget() {
+        let chunk =
+            |mesh_bytes| CachedChunk::new(NestedVoxel::new_empty(()), None, mesh_bytes, 0, None);
+        let voxel_bytes = chunk(0).bytes;
+
+        let mut cache = ChunkCache::new(8, voxel_bytes * 2 + 1500);
+        cache.insert([0, 0, 0], chunk(0));
+        cache.insert([1, 0, 0], chunk(1000));
+        assert_eq!(cache.stats().bytes, voxel_bytes * 2 + 1000);
+
+        // the chunk that was unloaded first is evicted to make room for the next mesh
+        cache.insert([2, 0, 0], chunk(500));
+        assert_eq!(cache.stats().chunks, 2);
+        assert!(cache.take([0, 0, 0]).is_none());
+        assert!(cache.take([1, 0, 0]).is_some());
+        assert_eq!(cache.stats().bytes, voxel_bytes + 500);
+    }
+
+    #[test]
+    fn palette_chunks_are_estimated_from_their_palette() {
+        let stone = SimpleVoxel::new_filled((), AtlasMaterialHandle(1));
+        let plain = NestedVoxel::<Chunk>::from_iter(Chunk, repeat(stone.clone()));
+        let packed = NestedVoxel::<Chunk>::from_iter_packed(Chunk, repeat(stone.clone()));
+        assert_eq!(memory(&plain), 4096 * size_of_val(&stone));
+        assert!(memory(&packed) < 64);
+    }
+
+    #[test]
+    fn unchanged_neighbours_keep_the_mesh() {
+        let voxel = NestedVoxel::new_empty(());
+        let mut neighbours = vec![None; 26];
+        neighbours[4] = Some(7);
+        let outdated = CachedChunk::new(voxel.clone(), None, 0, 0, None);
+        assert_eq!(outdated.changed(&neighbours), vec![true; 26]);
+
+        let chunk = CachedChunk::new(voxel, None, 0, 0, Some(neighbours.clone()));
+        assert_eq!(chunk.changed(&neighbours), vec![false; 26]);
+
+        // a neighbour that was rebuilt, and one that was loaded since the chunk was unloaded
+        neighbours[4] = Some(8);
+        neighbours[5] = Some(9);
+        let changed = chunk.changed(&neighbours);
+        assert_eq!(changed.iter().filter(|&&changed| changed).count(), 2);
+        assert!(changed[4] && changed[5]);
+    }
+}
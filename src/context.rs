@@ -1,5 +1,7 @@
+use crate::material::AtlasMaterialHandle;
 use crate::mesh::DynamicVoxelMesh;
-use crate::voxel::{Data, NestedVoxel, Voxel, ChildOf};
+use crate::shape::Shape;
+use crate::voxel::{surface_material, Data, NestedVoxel, Voxel, ChildOf};
 use crate::world::VoxelWorld;
 
 use amethyst::core::ecs::storage::GenericReadStorage;
@@ -15,6 +17,26 @@ pub trait Context<T: Voxel> {
     /// Same as `Voxel::skin`, but accepts a relative coordinate for selecting a child voxel.
     fn skin(&self, x: isize, y: isize, z: isize) -> Option<u8>;
 
+    /// Same as `Voxel::density`, but accepts a relative coordinate for selecting a child voxel.
+    fn density(&self, x: isize, y: isize, z: isize) -> f32 {
+        if self.render(x, y, z) {
+            0.0
+        } else {
+            1.0
+        }
+    }
+
+    /// The material of the child voxel at the relative coordinate, or the first material found in
+    /// it's subvoxels. Used to texture the surfaces of `MeshingMode::Smooth`.
+    fn material(&self, _x: isize, _y: isize, _z: isize) -> Option<AtlasMaterialHandle> {
+        None
+    }
+
+    /// Same as `Voxel::shape`, but accepts a relative coordinate for selecting a child voxel.
+    fn shape(&self, _x: isize, _y: isize, _z: isize) -> Shape {
+        Shape::default()
+    }
+
     /// Returns a Context for the child at the relative coordinate
     fn child<'a>(
         &'a self,
@@ -40,7 +62,8 @@ pub struct DetailContext<'a, P: Voxel> {
 
 /// Context sampling the chunks of a world.
 pub struct WorldContext<'a, V: Data, S: 'a + GenericReadStorage<Component = DynamicVoxelMesh<V>>> {
-    coord: [isize; 3],
+    coord: [i64; 3],
+    lod: usize,
     world: &'a VoxelWorld<V>,
     chunks: &'a S,
 }
@@ -84,6 +107,10 @@ impl<'a, T: Voxel> Context<T> for VoxelContext<'a, T> {
         }
     }
 
+    fn density(&self, _: isize, _: isize, _: isize) -> f32 {
+        0.0
+    }
+
     fn child<'b>(
         &'b self,
         x: isize,
@@ -159,6 +186,18 @@ impl<'a, P: Voxel> Context<ChildOf<P>> for DetailContext<'a, P> {
         self.find(x, y, z).and_then(|v| v.skin())
     }
 
+    fn density(&self, x: isize, y: isize, z: isize) -> f32 {
+        self.find(x, y, z).map(|v| v.density()).unwrap_or(0.0)
+    }
+
+    fn material(&self, x: isize, y: isize, z: isize) -> Option<AtlasMaterialHandle> {
+        self.find(x, y, z).and_then(surface_material)
+    }
+
+    fn shape(&self, x: isize, y: isize, z: isize) -> Shape {
+        self.find(x, y, z).map(|v| v.shape()).unwrap_or_default()
+    }
+
     fn child<'b>(&'b self, x: isize, y: isize, z: isize) -> DetailContext<'b, ChildOf<P>> {
         DetailContext::new(self, [x, y, z], self.find(x, y, z))
     }
@@ -169,45 +208,47 @@ where
     V: Data,
     S: 'a + GenericReadStorage<Component = DynamicVoxelMesh<V>>,
 {
-    pub fn new(coord: [isize; 3], world: &'a VoxelWorld<V>, chunks: &'a S) -> Self {
+    pub fn new(coord: [i64; 3], world: &'a VoxelWorld<V>, chunks: &'a S) -> Self {
+        let lod = world
+            .entity(coord)
+            .and_then(|e| chunks.get(e))
+            .map(|chunk| chunk.lod)
+            .unwrap_or(0);
         Self {
             coord,
+            lod,
             world,
             chunks,
         }
     }
 
-    fn find(&self, x: isize, y: isize, z: isize) -> Option<&'a V::Child> {
+    /// Find the chunk containing a relative coordinate, and the index of the subvoxel in that chunk.
+    fn chunk(&self, x: isize, y: isize, z: isize) -> Option<(&'a DynamicVoxelMesh<V>, usize)> {
         let size = NestedVoxel::<V>::WIDTH as isize;
-        let grid = |x| if x >= 0 { x / size } else { (x + 1) / size - 1 };
+        let grid = |x| if x >= 0 { x / size } else { (x + 1) / size - 1 } as i64;
         let coord = [
             self.coord[0] + grid(x),
             self.coord[1] + grid(y),
             self.coord[2] + grid(z),
         ];
-        let within_bounds = |b, i| b && coord[i] >= 0 && coord[i] < self.world.dims[i] as isize;
-
-        if (0..3).fold(true, within_bounds) {
-            let index = coord[0] as usize
-                + coord[1] as usize * self.world.dims[0]
-                + coord[2] as usize * self.world.dims[0] * self.world.dims[1];
-            if let Some(voxel) = self.world.data[index]
-                .get()
-                .and_then(|e| self.chunks.get(e))
-            {
-                let grid_mod = |x: isize| if x%size >= 0 { x%size } else { x%size + size } as usize;
-                voxel.get(
-                    grid_mod(x) * NestedVoxel::<V>::DX
-                        + grid_mod(y) * NestedVoxel::<V>::DY
-                        + grid_mod(z) * NestedVoxel::<V>::DZ,
-                )
-            } else {
-                None
-            }
+
+        if let Some(voxel) = self.world.entity(coord).and_then(|e| self.chunks.get(e)) {
+            let grid_mod = |x: isize| if x%size >= 0 { x%size } else { x%size + size } as usize;
+            Some((
+                voxel,
+                grid_mod(x) * NestedVoxel::<V>::DX
+                    + grid_mod(y) * NestedVoxel::<V>::DY
+                    + grid_mod(z) * NestedVoxel::<V>::DZ,
+            ))
         } else {
             None
         }
     }
+
+    fn find(&self, x: isize, y: isize, z: isize) -> Option<&'a V::Child> {
+        self.chunk(x, y, z)
+            .and_then(|(chunk, index)| chunk.get(index))
+    }
 }
 
 impl<'a, V, S> Context<NestedVoxel<V>> for WorldContext<'a, V, S>
@@ -220,13 +261,42 @@ where
     }
 
     fn render(&self, x: isize, y: isize, z: isize) -> bool {
-        self.find(x, y, z).map(|c| c.render()).unwrap_or(false)
+        match self.chunk(x, y, z) {
+            // faces towards chunks with another level of detail are kept to prevent cracks
+            Some((chunk, _)) if chunk.lod != self.lod => true,
+            Some((chunk, index)) => chunk.get(index).map(|c| c.render()).unwrap_or(false),
+            None => false,
+        }
     }
 
     fn skin(&self, x: isize, y: isize, z: isize) -> Option<u8> {
         self.find(x, y, z).and_then(|v| v.skin())
     }
 
+    fn density(&self, x: isize, y: isize, z: isize) -> f32 {
+        match self.chunk(x, y, z) {
+            Some((chunk, index)) => chunk
+                .get(index)
+                .map(|c| c.density())
+                .unwrap_or_else(|| chunk.density()),
+            None => 0.0,
+        }
+    }
+
+    fn material(&self, x: isize, y: isize, z: isize) -> Option<AtlasMaterialHandle> {
+        match self.chunk(x, y, z) {
+            Some((chunk, index)) => chunk
+                .get(index)
+                .and_then(surface_material)
+                .or_else(|| chunk.material()),
+            None => None,
+        }
+    }
+
+    fn shape(&self, x: isize, y: isize, z: isize) -> Shape {
+        self.find(x, y, z).map(|v| v.shape()).unwrap_or_default()
+    }
+
     fn child<'b>(&'b self, x: isize, y: isize, z: isize) -> DetailContext<'b, NestedVoxel<V>> {
         DetailContext::new(self, [x, y, z], self.find(x, y, z))
     }
@@ -240,6 +310,7 @@ where
     fn clone(&self) -> Self {
         Self {
             coord: self.coord,
+            lod: self.lod,
             world: self.world,
             chunks: self.chunks,
         }
@@ -0,0 +1,459 @@
+use crate::material::AtlasMaterialHandle;
+use crate::shape::Shape;
+use crate::voxel::{ChildOf, Data, NestedVoxel, Voxel};
+use crate::world::VoxelWorldAccess;
+
+use std::iter::repeat;
+use std::mem::replace;
+use std::ops::DerefMut;
+
+/// The contents of a single voxel in a `VoxelWorld`, as used by the editing functions of
+/// `VoxelWorldAccess`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Fill {
+    /// An empty voxel.
+    Empty,
+    /// A voxel filled with a single material.
+    Material(AtlasMaterialHandle),
+    /// A voxel filled with a single material in a shape other than a cube.
+    Shaped(AtlasMaterialHandle, Shape),
+    /// A voxel with subvoxels. Setting a voxel to `Detail` expands it without changing the way it looks.
+    Detail,
+}
+
+impl Fill {
+    /// Retrieve the `Fill` that describes a voxel.
+    pub fn of<T: Voxel>(voxel: &T) -> Self {
+        if voxel.is_detail() {
+            Fill::Detail
+        } else if let Some(material) = voxel.material() {
+            Fill::shaped(material, voxel.shape())
+        } else {
+            Fill::Empty
+        }
+    }
+
+    /// A voxel filled with a single material in `shape`. Cubes are filled with `Fill::Material`.
+    pub fn shaped(material: AtlasMaterialHandle, shape: Shape) -> Self {
+        if shape.is_cube() {
+            Fill::Material(material)
+        } else {
+            Fill::Shaped(material, shape)
+        }
+    }
+
+    /// Construct a voxel with these contents and default user data.
+    fn create<T: Voxel>(self) -> Option<T> {
+        match self {
+            Fill::Empty => Some(T::new_empty(Default::default())),
+            Fill::Material(material) => Some(T::new_filled(Default::default(), material)),
+            Fill::Shaped(material, shape) => {
+                Some(T::new_shaped(Default::default(), material, shape))
+            }
+            Fill::Detail => None,
+        }
+    }
+}
+
+/// Editing functions for voxels in a `VoxelWorld`.
+///
+/// Voxels are addressed by absolute integer coordinates at a nesting `depth`.
+/// A depth of 0 addresses whole chunks, so the coordinates are chunk coordinates.
+/// A depth of 1 addresses the subvoxels of chunks, a depth of 2 the subvoxels of those and so on.
+/// Voxels that are not loaded are skipped, every chunk that is modified is marked dirty.
+/// Neighbouring chunks are marked dirty as well if a modified voxel lies on their border.
+impl<'a, 'b, V: Data> VoxelWorldAccess<'a, 'b, V> {
+    /// Retrieve the contents of the voxel at `coord` on nesting level `depth`.
+    /// If the voxel lies within an empty or filled voxel of a lower depth, the contents of that voxel
+    /// are returned. Returns `None` if the chunk is not loaded.
+    pub fn get_voxel(&self, coord: [i64; 3], depth: usize) -> Option<Fill> {
+        let (chunk, local) = locate::<V>(coord, depth);
+        self.get(chunk).map(|voxel| get_nested(voxel, local, depth))
+    }
+
+    /// Set the contents of the voxel at `coord` on nesting level `depth`.
+    /// Empty and filled voxels of a lower depth are expanded as needed, the user data of the voxel
+    /// that is set is reset to it's default. Chunks are never collapsed, setting a chunk fills all of
+    /// it's subvoxels instead. Nothing is modified if the voxel already has the given contents.
+    /// Voxels with a shape other than a cube are not expanded, see `Voxel::expand`.
+    /// Returns `false` if the chunk is not loaded, if `depth` is deeper than the voxels nest or if
+    /// the voxel lies within a shaped voxel of a lower depth.
+    pub fn set_voxel(&mut self, coord: [i64; 3], depth: usize, fill: Fill) -> bool {
+        let (chunk, local) = locate::<V>(coord, depth);
+        match self.get(chunk) {
+            Some(voxel) if unchanged(voxel, local, depth, fill) => return true,
+            Some(_) => (),
+            None => return false,
+        }
+
+        let set = match self.get_mut(chunk) {
+            Some(voxel) if depth == 0 => {
+                let child = match fill.create::<ChildOf<NestedVoxel<V>>>() {
+                    Some(child) => child,
+                    None => return voxel.expand(),
+                };
+                let data = replace(voxel.deref_mut(), V::default());
+                *voxel = NestedVoxel::from_iter(data, repeat(child));
+                true
+            }
+            Some(voxel) => set_nested(voxel, local, depth, fill),
+            None => false,
+        };
+        if set {
+            self.touch(chunk, local, depth);
+        }
+        set
+    }
+
+    /// Set all voxels within the box from `min` to `max` (inclusive) on nesting level `depth`.
+    pub fn fill_box(&mut self, min: [i64; 3], max: [i64; 3], depth: usize, fill: Fill) {
+        for z in min[2]..=max[2] {
+            for y in min[1]..=max[1] {
+                for x in min[0]..=max[0] {
+                    self.set_voxel([x, y, z], depth, fill);
+                }
+            }
+        }
+    }
+
+    /// Set all voxels within `radius` of `center` on nesting level `depth`.
+    pub fn fill_sphere(&mut self, center: [i64; 3], radius: f32, depth: usize, fill: Fill) {
+        let r = radius.floor() as i64;
+        for z in -r..=r {
+            for y in -r..=r {
+                for x in -r..=r {
+                    if ((x * x + y * y + z * z) as f32) <= radius * radius {
+                        self.set_voxel([center[0] + x, center[1] + y, center[2] + z], depth, fill);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Set all voxels within a vertical cylinder on nesting level `depth`.
+    /// The cylinder is centered on `base`, and extends `height` voxels upwards along the y axis.
+    pub fn fill_cylinder(
+        &mut self,
+        base: [i64; 3],
+        radius: f32,
+        height: usize,
+        depth: usize,
+        fill: Fill,
+    ) {
+        let r = radius.floor() as i64;
+        for z in -r..=r {
+            for x in -r..=r {
+                if ((x * x + z * z) as f32) <= radius * radius {
+                    for y in 0..height as i64 {
+                        self.set_voxel([base[0] + x, base[1] + y, base[2] + z], depth, fill);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Set all voxels on the line from `from` to `to` (inclusive) on nesting level `depth`.
+    pub fn draw_line(&mut self, from: [i64; 3], to: [i64; 3], depth: usize, fill: Fill) {
+        // 3d bresenham, stepping along the axis with the largest delta
+        let delta = [to[0] - from[0], to[1] - from[1], to[2] - from[2]];
+        let step = [delta[0].signum(), delta[1].signum(), delta[2].signum()];
+        let delta = [delta[0].abs(), delta[1].abs(), delta[2].abs()];
+        let major = if delta[0] >= delta[1] && delta[0] >= delta[2] {
+            0
+        } else if delta[1] >= delta[2] {
+            1
+        } else {
+            2
+        };
+        let (a, b) = ((major + 1) % 3, (major + 2) % 3);
+
+        let mut current = from;
+        let mut error_a = 2 * delta[a] - delta[major];
+        let mut error_b = 2 * delta[b] - delta[major];
+        for _ in 0..=delta[major] {
+            self.set_voxel(current, depth, fill);
+            if error_a > 0 {
+                current[a] += step[a];
+                error_a -= 2 * delta[major];
+            }
+            if error_b > 0 {
+                current[b] += step[b];
+                error_b -= 2 * delta[major];
+            }
+            error_a += 2 * delta[a];
+            error_b += 2 * delta[b];
+            current[major] += step[major];
+        }
+    }
+
+    /// Replace the material `from` by `to` within the box from `min` to `max` (inclusive) on
+    /// nesting level `depth`. Subvoxels of the voxels in the box are replaced as well.
+    pub fn replace_material(
+        &mut self,
+        min: [i64; 3],
+        max: [i64; 3],
+        depth: usize,
+        from: AtlasMaterialHandle,
+        to: AtlasMaterialHandle,
+    ) {
+        for z in min[2]..=max[2] {
+            for y in min[1]..=max[1] {
+                for x in min[0]..=max[0] {
+                    let (chunk, local) = locate::<V>([x, y, z], depth);
+                    let replaced = self
+                        .get_mut(chunk)
+                        .map(|voxel| replace_nested(voxel, local, depth, from, to))
+                        .unwrap_or(false);
+                    if replaced {
+                        self.touch(chunk, local, depth);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Mark the neighbours of `chunk` dirty if the modified voxel at `local` touches them.
+    fn touch(&mut self, chunk: [i64; 3], local: [i64; 3], depth: usize) {
+        let last = extent::<NestedVoxel<V>>(depth) - 1;
+        let f = |i: usize| [local[i] == 0, local[i] == last];
+        self.world
+            .dirty_neighbours(chunk, [f(0), f(1), f(2)], self.chunks);
+    }
+}
+
+/// The amount of voxels on nesting level `depth` along a single axis of a voxel of type `T`.
+fn extent<T: Voxel>(depth: usize) -> i64 {
+    if depth == 0 {
+        1
+    } else {
+        T::WIDTH as i64 * extent::<ChildOf<T>>(depth - 1)
+    }
+}
+
+/// Split a world coordinate on nesting level `depth` into a chunk coordinate and a coordinate within
+/// that chunk.
+fn locate<V: Data>(coord: [i64; 3], depth: usize) -> ([i64; 3], [i64; 3]) {
+    let size = extent::<NestedVoxel<V>>(depth);
+    let f = |i: usize| (coord[i].div_euclid(size), coord[i].rem_euclid(size));
+    let (x, y, z) = (f(0), f(1), f(2));
+    ([x.0, y.0, z.0], [x.1, y.1, z.1])
+}
+
+/// Split a coordinate within a voxel of type `T` into the index of a subvoxel and a coordinate
+/// within that subvoxel.
+fn split<T: Voxel>(coord: [i64; 3], depth: usize) -> (usize, [i64; 3]) {
+    let size = extent::<ChildOf<T>>(depth - 1);
+    let index = T::coord_to_index(
+        (coord[0] / size) as usize,
+        (coord[1] / size) as usize,
+        (coord[2] / size) as usize,
+    );
+    (index, [coord[0] % size, coord[1] % size, coord[2] % size])
+}
+
+fn get_nested<T: Voxel>(voxel: &T, coord: [i64; 3], depth: usize) -> Fill {
+    if depth == 0 || !voxel.is_detail() {
+        return Fill::of(voxel);
+    }
+    let (index, coord) = split::<T>(coord, depth);
+    voxel
+        .get(index)
+        .map(|child| get_nested(child, coord, depth - 1))
+        .unwrap_or(Fill::Empty)
+}
+
+/// Returns whether setting the voxel at `coord` on nesting level `depth` to `fill` would leave it
+/// unchanged. Empty and filled voxels of a lower depth already look like any of their subvoxels.
+fn unchanged<T: Voxel>(voxel: &T, coord: [i64; 3], depth: usize, fill: Fill) -> bool {
+    let same = |voxel: &ChildOf<T>| !voxel.is_detail() && Fill::of(voxel) == fill;
+    match fill {
+        Fill::Detail if depth == 0 => voxel.is_detail(),
+        // setting a chunk fills all of it's subvoxels
+        _ if depth == 0 => (0..T::COUNT).all(|i| voxel.get(i).map(same).unwrap_or(false)),
+        _ => unchanged_nested(voxel, coord, depth, fill),
+    }
+}
+
+fn unchanged_nested<T: Voxel>(voxel: &T, coord: [i64; 3], depth: usize, fill: Fill) -> bool {
+    if !voxel.is_detail() {
+        // a shaped voxel of a lower depth does not look like any of it's subvoxels
+        return Fill::of(voxel) == fill && (depth == 0 || voxel.shape().is_cube());
+    }
+    if depth == 0 {
+        return fill == Fill::Detail;
+    }
+    let (index, coord) = split::<T>(coord, depth);
+    voxel
+        .get(index)
+        .map(|child| unchanged_nested(child, coord, depth - 1, fill))
+        .unwrap_or(false)
+}
+
+/// Set the voxel at `coord` on nesting level `depth`, which is at least 1.
+/// The depth is checked first, so that a rejected edit does not expand any voxels.
+fn set_nested<T: Voxel>(voxel: &mut T, coord: [i64; 3], depth: usize, fill: Fill) -> bool {
+    settable(voxel, coord, depth) && set_expanded(voxel, coord, depth, fill)
+}
+
+/// Returns whether the voxel at `coord` on nesting level `depth` can be reached by expanding
+/// voxels, without expanding them.
+fn settable<T: Voxel>(voxel: &T, coord: [i64; 3], depth: usize) -> bool {
+    if depth == 0 {
+        return true;
+    }
+    if !voxel.is_detail() {
+        return voxel.shape().is_cube() && nests::<T>(depth);
+    }
+    let (index, coord) = split::<T>(coord, depth);
+    voxel
+        .get(index)
+        .map(|child| settable(child, coord, depth - 1))
+        .unwrap_or(false)
+}
+
+/// Returns whether empty voxels of type `T` can be expanded down to nesting level `depth`.
+fn nests<T: Voxel>(depth: usize) -> bool {
+    depth == 0 || T::new_empty(Default::default()).expand() && nests::<ChildOf<T>>(depth - 1)
+}
+
+/// Expand voxels down to nesting level `depth` and replace the voxel at `coord` through
+/// `Voxel::set`, so that palette compressed subvoxels stay compressed.
+fn set_expanded<T: Voxel>(voxel: &mut T, coord: [i64; 3], depth: usize, fill: Fill) -> bool {
+    if !voxel.expand() {
+        return false;
+    }
+    let (index, coord) = split::<T>(coord, depth);
+    if depth > 1 {
+        return voxel
+            .get_mut(index)
+            .map(|child| set_expanded(child, coord, depth - 1, fill))
+            .unwrap_or(false);
+    }
+
+    let child = match (fill.create(), voxel.get(index)) {
+        (Some(created), _) => created,
+        (None, Some(child)) => {
+            let mut child = child.clone();
+            if !child.expand() {
+                return false;
+            }
+            child
+        }
+        (None, None) => return false,
+    };
+    voxel.set(index, child)
+}
+
+fn replace_nested<T: Voxel>(
+    voxel: &mut T,
+    coord: [i64; 3],
+    depth: usize,
+    from: AtlasMaterialHandle,
+    to: AtlasMaterialHandle,
+) -> bool {
+    if depth == 0 {
+        return voxel.replace_material(from, to);
+    }
+    // only expand filled voxels that will actually change
+    if voxel.material() == Some(from) {
+        if !settable(voxel, coord, depth) {
+            return false;
+        }
+        voxel.expand();
+    }
+    if !voxel.is_detail() {
+        return false;
+    }
+    let (index, coord) = split::<T>(coord, depth);
+    if depth == 1 {
+        let mut child = match voxel.get(index) {
+            Some(child) if child.material() == Some(from) || child.is_detail() => child.clone(),
+            _ => return false,
+        };
+        return child.replace_material(from, to) && voxel.set(index, child);
+    }
+    voxel
+        .get_mut(index)
+        .map(|child| replace_nested(child, coord, depth - 1, from, to))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::ShapeKind;
+    use crate::storage::DetailStorage;
+    use crate::voxel::SimpleVoxel;
+
+    #[derive(Clone, Default, PartialEq)]
+    struct Inner;
+
+    impl Data for Inner {
+        const SUBDIV: usize = 1;
+        type Child = SimpleVoxel;
+    }
+
+    #[derive(Clone, Default, PartialEq)]
+    struct Outer;
+
+    impl Data for Outer {
+        const SUBDIV: usize = 1;
+        type Child = NestedVoxel<Inner>;
+    }
+
+    #[test]
+    fn shaped_fill() {
+        let stone = AtlasMaterialHandle(1);
+        let slab = Fill::Shaped(stone, Shape::new(ShapeKind::Slab, 0));
+        assert!(Fill::shaped(stone, Shape::default()) == Fill::Material(stone));
+
+        let mut voxel = NestedVoxel::<Outer>::new_filled(Outer, stone);
+        assert!(!unchanged(&voxel, [1, 1, 1], 2, slab));
+        assert!(set_nested(&mut voxel, [1, 1, 1], 2, slab));
+        assert!(get_nested(&voxel, [1, 1, 1], 2) == slab);
+        assert!(get_nested(&voxel, [0, 1, 1], 2) == Fill::Material(stone));
+        assert!(unchanged(&voxel, [1, 1, 1], 2, slab));
+
+        // a shaped voxel does not look like any of it's subvoxels
+        assert!(set_nested(&mut voxel, [0, 0, 0], 1, slab));
+        assert!(unchanged(&voxel, [0, 0, 0], 1, slab));
+        assert!(!unchanged(&voxel, [0, 0, 0], 2, slab));
+        assert!(!set_nested(&mut voxel, [0, 0, 0], 2, slab));
+    }
+
+    #[test]
+    fn rejected_edits_do_not_expand() {
+        let stone = AtlasMaterialHandle(1);
+        let dirt = AtlasMaterialHandle(2);
+        let mut voxel = NestedVoxel::<Outer>::new_filled(Outer, stone);
+        assert!(!set_nested(&mut voxel, [0, 0, 0], 3, Fill::Material(dirt)));
+        assert!(!replace_nested(&mut voxel, [0, 0, 0], 3, stone, dirt));
+        assert!(!voxel.is_detail());
+
+        let slab = Fill::Shaped(stone, Shape::new(ShapeKind::Slab, 0));
+        assert!(set_nested(&mut voxel, [0, 0, 0], 1, slab));
+        assert!(!set_nested(&mut voxel, [0, 0, 0], 2, Fill::Material(dirt)));
+        assert!(!voxel.get(0).unwrap().is_detail());
+    }
+
+    #[test]
+    fn leaf_writes_keep_palette_compact() {
+        let stone = AtlasMaterialHandle(1);
+        let dirt = AtlasMaterialHandle(2);
+        let mut voxel = NestedVoxel::<Outer>::new_filled(Outer, stone);
+        voxel.expand();
+        voxel.pack();
+        for i in 0..8 {
+            let coord = [i & 1, (i >> 1) & 1, i >> 2];
+            assert!(set_nested(&mut voxel, coord, 1, Fill::Material(dirt)));
+        }
+        assert!(replace_nested(&mut voxel, [1, 1, 1], 1, dirt, stone));
+
+        match voxel.detail() {
+            Some(DetailStorage::Palette(palette)) => assert_eq!(palette.palette().count(), 2),
+            _ => panic!("subvoxels are no longer packed"),
+        }
+        assert!(get_nested(&voxel, [1, 1, 1], 1) == Fill::Material(stone));
+        assert!(get_nested(&voxel, [0, 1, 1], 1) == Fill::Material(dirt));
+    }
+}
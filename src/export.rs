@@ -0,0 +1,666 @@
+use crate::context::{VoxelContext, WorldContext};
+use crate::material::{AtlasAccess, AtlasData, AtlasTextures};
+use crate::mesh::{build_voxel, DynamicVoxelMesh, MeshingMode};
+use crate::model::ModelData;
+use crate::triangulate::VoxelMeshData;
+use crate::voxel::{Data, NestedVoxel, Voxel};
+use crate::world::VoxelWorld;
+use amethyst::ecs::storage::GenericReadStorage;
+use byteorder::{LittleEndian, WriteBytesExt};
+use image::{png::PNGEncoder, ColorType};
+use nalgebra_glm::*;
+use serde_json::{json, Map, Value};
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+/// Triangulated voxels that can be written to glTF 2.0 or Wavefront OBJ files, to use them in other
+/// tools. One unit in the exported files is the size of a single subvoxel, except for exported
+/// regions of a `VoxelWorld`, which use the units of the world.
+/// Faces are never merged like `VoxelMeshData::build_greedy` does, the exported textures are
+/// clamped, so every texture coordinate has to stay within the tile of it's material.
+pub struct VoxelExport {
+    nodes: Vec<Node>,
+    textures: Option<AtlasTextures>,
+}
+
+struct Node {
+    name: String,
+    mesh: VoxelMeshData,
+    transform: Mat4x4,
+}
+
+/// The binary buffer of a glTF document.
+#[derive(Default)]
+struct Buffer {
+    data: Vec<u8>,
+    views: Vec<Value>,
+    accessors: Vec<Value>,
+}
+
+const GLTF_ARRAY_BUFFER: u32 = 34962;
+const GLTF_ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const GLTF_FLOAT: u32 = 5126;
+const GLTF_UNSIGNED_INT: u32 = 5125;
+const GLTF_NEAREST: u32 = 9728;
+const GLTF_CLAMP_TO_EDGE: u32 = 33071;
+
+impl VoxelExport {
+    /// Triangulate a single voxel, with the materials of `atlas`.
+    pub fn from_voxel<V: Voxel, A: AtlasAccess>(
+        voxel: &V,
+        atlas: &A,
+        meshing: MeshingMode,
+    ) -> Self {
+        let context = VoxelContext::new(voxel);
+        VoxelExport {
+            nodes: vec![Node {
+                name: "voxel".into(),
+                mesh: VoxelMeshData::build(voxel, &context, atlas, meshing),
+                transform: scale(&identity(), &(vec3(1.0, 1.0, 1.0) * V::WIDTH as f32)),
+            }],
+            textures: atlas.textures(),
+        }
+    }
+
+    /// Triangulate the submodels of a `ModelData`, for example a model loaded with `VoxFormat`.
+    /// The submodels are converted to `NestedVoxel<V>`, like they are for a `VoxelMesh`.
+    pub fn from_model<V: Data>(model: &ModelData, meshing: MeshingMode) -> Self {
+        let mut atlas = AtlasData::default();
+        let voxels = model
+            .submodels
+            .iter()
+            .map(|sub| build_voxel::<V>(model, sub, &mut atlas))
+            .collect::<Vec<_>>();
+
+        let size = scale(
+            &identity(),
+            &(vec3(1.0, 1.0, 1.0) * NestedVoxel::<V>::WIDTH as f32),
+        );
+        let nodes = voxels
+            .iter()
+            .zip(model.submodels.iter())
+            .enumerate()
+            .map(|(i, (voxel, sub))| {
+                let context = VoxelContext::new(voxel);
+                Node {
+                    name: format!("submodel_{}", i),
+                    mesh: VoxelMeshData::build(voxel, &context, &atlas, meshing),
+                    transform: size * sub.offset,
+                }
+            })
+            .collect();
+
+        VoxelExport {
+            nodes,
+            textures: atlas.textures(),
+        }
+    }
+
+    /// Triangulate the loaded chunks of a `VoxelWorld` in the region `from..to`, using the materials
+    /// of `atlas`, which should be the atlas of the world. The faces between chunks are culled as
+    /// they are in the world. Chunks are positioned relative to the chunk at `from`.
+    pub fn from_region<V, S, A>(
+        world: &VoxelWorld<V>,
+        chunks: &S,
+        atlas: &A,
+        from: [i64; 3],
+        to: [i64; 3],
+        meshing: MeshingMode,
+    ) -> Self
+    where
+        V: Data,
+        S: GenericReadStorage<Component = DynamicVoxelMesh<V>>,
+        A: AtlasAccess,
+    {
+        let mut nodes = Vec::new();
+        for x in from[0]..to[0] {
+            for y in from[1]..to[1] {
+                for z in from[2]..to[2] {
+                    let coord = [x, y, z];
+                    if let Some(voxel) = world.get(coord, chunks) {
+                        let context = WorldContext::new(coord, world, chunks);
+                        let offset = |i: usize| (coord[i] - from[i]) as f32 * world.scale;
+                        let transform =
+                            translate(&identity(), &vec3(offset(0), offset(1), offset(2)));
+                        nodes.push(Node {
+                            name: format!("chunk_{}_{}_{}", x, y, z),
+                            mesh: VoxelMeshData::build(voxel, &context, atlas, meshing),
+                            transform: scale(&transform, &(vec3(1.0, 1.0, 1.0) * world.scale)),
+                        });
+                    }
+                }
+            }
+        }
+
+        VoxelExport {
+            nodes,
+            textures: atlas.textures(),
+        }
+    }
+
+    /// Write a glTF 2.0 document to `path`. The binary data and the textures are written to separate
+    /// files next to it, named after `path`.
+    pub fn write_gltf<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let stem = file_stem(path)?;
+        let textures = self.write_textures(path, &stem)?;
+
+        let bin = format!("{}.bin", stem);
+        let (document, buffer) = self.gltf(Some(bin.clone()), textures)?;
+        std::fs::write(path.with_file_name(bin), &buffer.data)?;
+
+        let file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(file, &document)?;
+        Ok(())
+    }
+
+    /// Write a binary glTF 2.0 file to `path`, with the textures embedded.
+    pub fn write_glb<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let (document, buffer) = self.gltf(None, None)?;
+
+        let mut json = serde_json::to_vec(&document)?;
+        while json.len() % 4 != 0 {
+            json.push(b' ');
+        }
+        let bin = buffer.data;
+
+        let mut length = 12 + 8 + json.len();
+        if !bin.is_empty() {
+            length += 8 + bin.len();
+        }
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"glTF")?;
+        file.write_u32::<LittleEndian>(2)?;
+        file.write_u32::<LittleEndian>(length as u32)?;
+        file.write_u32::<LittleEndian>(json.len() as u32)?;
+        file.write_all(b"JSON")?;
+        file.write_all(&json)?;
+        if !bin.is_empty() {
+            file.write_u32::<LittleEndian>(bin.len() as u32)?;
+            file.write_all(b"BIN\0")?;
+            file.write_all(&bin)?;
+        }
+        file.flush()
+    }
+
+    /// Write a Wavefront OBJ file to `path`. The MTL file and the albedo and emission textures are
+    /// written to separate files next to it, named after `path`.
+    /// Ambient occlusion is not exported, as OBJ has no vertex colors.
+    pub fn write_obj<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let stem = file_stem(path)?;
+        let textures = self.write_textures(path, &stem)?;
+        let mtl = format!("{}.mtl", stem);
+
+        let mut file = BufWriter::new(File::create(path.with_file_name(&mtl))?);
+        writeln!(file, "newmtl voxels")?;
+        writeln!(file, "Kd 1 1 1")?;
+        writeln!(file, "Ks 0 0 0")?;
+        if let Some([albedo, emission, _]) = textures {
+            writeln!(file, "Ke 1 1 1")?;
+            writeln!(file, "map_Kd {}", albedo)?;
+            writeln!(file, "map_Ke {}", emission)?;
+        }
+        file.flush()?;
+
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "mtllib {}", mtl)?;
+        let mut offset = 1;
+        for node in self.nodes.iter().filter(|node| !node.mesh.is_empty()) {
+            writeln!(file, "o {}", node.name)?;
+            for p in node.mesh.positions.iter() {
+                let p = node.transform * vec4(p[0], p[1], p[2], 1.0);
+                writeln!(file, "v {} {} {}", p.x, p.y, p.z)?;
+            }
+            for t in node.mesh.tex_ao.iter() {
+                writeln!(file, "vt {} {}", t[0], 1.0 - t[1])?;
+            }
+            for n in node.mesh.normals.iter() {
+                let n = normalize(&(node.transform * vec4(n[0], n[1], n[2], 0.0)).xyz());
+                writeln!(file, "vn {} {} {}", n.x, n.y, n.z)?;
+            }
+            writeln!(file, "usemtl voxels")?;
+            for triangle in node.mesh.indices.chunks(3) {
+                write!(file, "f")?;
+                for &i in triangle {
+                    let i = i as usize + offset;
+                    write!(file, " {}/{}/{}", i, i, i)?;
+                }
+                writeln!(file)?;
+            }
+            offset += node.mesh.positions.len();
+        }
+        file.flush()
+    }
+
+    /// Write the textures as PNG files next to `path`. Returns the file names of the albedo,
+    /// emission and metallic/roughness textures.
+    fn write_textures(&self, path: &Path, stem: &str) -> Result<Option<[String; 3]>> {
+        match self.textures {
+            Some(ref textures) => {
+                let names = [
+                    format!("{}_albedo.png", stem),
+                    format!("{}_emission.png", stem),
+                    format!("{}_metallic_roughness.png", stem),
+                ];
+                for (name, png) in names.iter().zip(encode_textures(textures)?.iter()) {
+                    std::fs::write(path.with_file_name(name), png)?;
+                }
+                Ok(Some(names))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Build the glTF document and it's binary buffer. The buffer is referenced by `bin` and the
+    /// textures by `textures` if they are given, otherwise they are embedded in the buffer.
+    fn gltf(&self, bin: Option<String>, textures: Option<[String; 3]>) -> Result<(Value, Buffer)> {
+        let mut buffer = Buffer::default();
+        let mut nodes = Vec::new();
+        let mut meshes = Vec::new();
+
+        for node in self.nodes.iter().filter(|node| !node.mesh.is_empty()) {
+            let mesh = &node.mesh;
+            let (min, max) = bounds(&mesh.positions);
+            let position = buffer.accessor(&flatten(&mesh.positions), "VEC3", 3);
+            buffer.accessors[position]["min"] = json!(min);
+            buffer.accessors[position]["max"] = json!(max);
+            let normal = buffer.accessor(&flatten(&mesh.normals), "VEC3", 3);
+            let tangent = buffer.accessor(&flatten(&mesh.tangents), "VEC4", 4);
+            let tex: Vec<f32> = mesh.tex_ao.iter().flat_map(|t| vec![t[0], t[1]]).collect();
+            let tex = buffer.accessor(&tex, "VEC2", 2);
+            let ao: Vec<f32> = mesh.tex_ao.iter().flat_map(|t| vec![t[2]; 3]).collect();
+            let ao = buffer.accessor(&ao, "VEC3", 3);
+            let indices = buffer.indices(&mesh.indices);
+
+            nodes.push(json!({
+                "name": node.name,
+                "mesh": meshes.len(),
+                "matrix": node.transform.as_slice(),
+            }));
+            meshes.push(json!({
+                "name": node.name,
+                "primitives": [{
+                    "attributes": {
+                        "POSITION": position,
+                        "NORMAL": normal,
+                        "TANGENT": tangent,
+                        "TEXCOORD_0": tex,
+                        "COLOR_0": ao,
+                    },
+                    "indices": indices,
+                    "material": 0,
+                }],
+            }));
+        }
+
+        let mut material = json!({ "name": "voxels" });
+        let mut images = Vec::new();
+        let mut samplers = Vec::new();
+        let mut texture_refs = Vec::new();
+        if let Some(ref baked) = self.textures {
+            match textures {
+                Some(names) => images.extend(names.iter().map(|name| json!({ "uri": name }))),
+                None => {
+                    for png in encode_textures(baked)?.iter() {
+                        let view = buffer.view(png, None);
+                        images.push(json!({ "bufferView": view, "mimeType": "image/png" }));
+                    }
+                }
+            }
+            samplers.push(json!({
+                "magFilter": GLTF_NEAREST,
+                "minFilter": GLTF_NEAREST,
+                "wrapS": GLTF_CLAMP_TO_EDGE,
+                "wrapT": GLTF_CLAMP_TO_EDGE,
+            }));
+            texture_refs.extend((0..3).map(|i| json!({ "source": i, "sampler": 0 })));
+            material = json!({
+                "name": "voxels",
+                "pbrMetallicRoughness": {
+                    "baseColorTexture": { "index": 0 },
+                    "metallicRoughnessTexture": { "index": 2 },
+                },
+                "emissiveTexture": { "index": 1 },
+                "emissiveFactor": [1.0, 1.0, 1.0],
+            });
+        }
+
+        let mut document = Map::new();
+        document.insert(
+            "asset".into(),
+            json!({ "version": "2.0", "generator": "amethyst_voxel" }),
+        );
+        document.insert("scene".into(), json!(0));
+        let scene = if nodes.is_empty() {
+            json!({})
+        } else {
+            json!({ "nodes": (0..nodes.len()).collect::<Vec<_>>() })
+        };
+        document.insert("scenes".into(), json!([scene]));
+
+        let mut buffers = Vec::new();
+        if !buffer.data.is_empty() {
+            let mut entry = json!({ "byteLength": buffer.data.len() });
+            if let Some(bin) = bin {
+                entry["uri"] = json!(bin);
+            }
+            buffers.push(entry);
+        }
+
+        // glTF doesn't allow empty arrays
+        let mut insert = |key: &str, values: Vec<Value>| {
+            if !values.is_empty() {
+                document.insert(key.into(), Value::Array(values));
+            }
+        };
+        insert("nodes", nodes);
+        insert("meshes", meshes);
+        insert("materials", vec![material]);
+        insert("images", images);
+        insert("samplers", samplers);
+        insert("textures", texture_refs);
+        insert("accessors", buffer.accessors.clone());
+        insert("bufferViews", buffer.views.clone());
+        insert("buffers", buffers);
+
+        Ok((Value::Object(document), buffer))
+    }
+}
+
+impl Buffer {
+    /// Append a buffer view containing `bytes`, returns the index of the view.
+    fn view(&mut self, bytes: &[u8], target: Option<u32>) -> usize {
+        let mut view = json!({
+            "buffer": 0,
+            "byteOffset": self.data.len(),
+            "byteLength": bytes.len(),
+        });
+        if let Some(target) = target {
+            view["target"] = json!(target);
+        }
+        self.data.extend_from_slice(bytes);
+        while self.data.len() % 4 != 0 {
+            self.data.push(0);
+        }
+        self.views.push(view);
+        self.views.len() - 1
+    }
+
+    /// Append a vertex attribute with `components` floats per vertex, returns the index of the accessor.
+    fn accessor(&mut self, values: &[f32], kind: &str, components: usize) -> usize {
+        let mut bytes = Vec::with_capacity(values.len() * 4);
+        for &value in values {
+            bytes.write_f32::<LittleEndian>(value).unwrap();
+        }
+        let view = self.view(&bytes, Some(GLTF_ARRAY_BUFFER));
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": GLTF_FLOAT,
+            "count": values.len() / components,
+            "type": kind,
+        }));
+        self.accessors.len() - 1
+    }
+
+    /// Append triangle indices, returns the index of the accessor.
+    fn indices(&mut self, indices: &[u32]) -> usize {
+        let mut bytes = Vec::with_capacity(indices.len() * 4);
+        for &index in indices {
+            bytes.write_u32::<LittleEndian>(index).unwrap();
+        }
+        let view = self.view(&bytes, Some(GLTF_ELEMENT_ARRAY_BUFFER));
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": GLTF_UNSIGNED_INT,
+            "count": indices.len(),
+            "type": "SCALAR",
+        }));
+        self.accessors.len() - 1
+    }
+}
+
+fn file_stem(path: &Path) -> Result<String> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(|stem| stem.to_string())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "the export path has no file name"))
+}
+
+/// Encode the albedo, emission and metallic/roughness textures as PNG.
+fn encode_textures(textures: &AtlasTextures) -> Result<[Vec<u8>; 3]> {
+    let png = |data: &[u8], width, height, color| -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        PNGEncoder::new(&mut bytes).encode(data, width, height, color)?;
+        Ok(bytes)
+    };
+    let (width, height) = textures.albedo_alpha.dimensions();
+    Ok([
+        png(&textures.albedo_alpha, width, height, ColorType::RGBA(8))?,
+        png(&textures.emission, width, height, ColorType::RGB(8))?,
+        png(
+            &textures.metallic_roughness,
+            width,
+            height,
+            ColorType::RGB(8),
+        )?,
+    ])
+}
+
+fn flatten<T: AsRef<[f32]>>(values: &[T]) -> Vec<f32> {
+    values.iter().flat_map(|v| v.as_ref().to_vec()).collect()
+}
+
+fn bounds(positions: &[[f32; 3]]) -> ([f32; 3], [f32; 3]) {
+    let mut min = [std::f32::INFINITY; 3];
+    let mut max = [std::f32::NEG_INFINITY; 3];
+    for p in positions {
+        for i in 0..3 {
+            min[i] = min[i].min(p[i]);
+            max[i] = max[i].max(p[i]);
+        }
+    }
+    (min, max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{ColoredMaterial, VoxelMaterial};
+    use crate::voxel::SimpleVoxel;
+    use byteorder::ByteOrder;
+    use std::fs::{create_dir_all, read, read_to_string, remove_dir_all};
+    use std::iter::repeat;
+    use std::path::PathBuf;
+
+    #[derive(Clone, Default)]
+    struct Cube;
+
+    impl Data for Cube {
+        const SUBDIV: usize = 1;
+        type Child = SimpleVoxel;
+    }
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "amethyst_voxel_export_{}_{}",
+            name,
+            std::process::id()
+        ));
+        remove_dir_all(&directory).ok();
+        create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// An export of two filled cubes side by side, the first one smooth.
+    fn export() -> VoxelExport {
+        let mut atlas = AtlasData::default();
+        let stone = atlas.create(
+            "stone",
+            Box::new(ColoredMaterial::default()) as Box<dyn VoxelMaterial>,
+        );
+        let voxel = NestedVoxel::from_iter(Cube, repeat(SimpleVoxel::new_filled((), stone)));
+        let mut export = VoxelExport::from_voxel(&voxel, &atlas, MeshingMode::Smooth);
+        let mut second = VoxelExport::from_voxel(&voxel, &atlas, MeshingMode::Cubes);
+        for node in second.nodes.iter_mut() {
+            node.name = "second".into();
+            node.transform = translate(&node.transform, &vec3(1.0, 0.0, 0.0));
+        }
+        export.nodes.append(&mut second.nodes);
+        export
+    }
+
+    #[test]
+    fn glb_layout() {
+        let directory = directory("glb");
+        let path = directory.join("cubes.glb");
+        export().write_glb(&path).unwrap();
+        let glb = read(&path).unwrap();
+
+        // header
+        assert_eq!(&glb[0..4], b"glTF");
+        assert_eq!(LittleEndian::read_u32(&glb[4..8]), 2);
+        assert_eq!(LittleEndian::read_u32(&glb[8..12]) as usize, glb.len());
+        assert_eq!(glb.len() % 4, 0);
+
+        // the json chunk
+        let json_length = LittleEndian::read_u32(&glb[12..16]) as usize;
+        assert_eq!(&glb[16..20], b"JSON");
+        assert_eq!(json_length % 4, 0);
+        let document: Value = serde_json::from_slice(&glb[20..20 + json_length]).unwrap();
+
+        // the binary chunk directly follows the json chunk
+        let bin = 20 + json_length;
+        let bin_length = LittleEndian::read_u32(&glb[bin..bin + 4]) as usize;
+        assert_eq!(&glb[bin + 4..bin + 8], b"BIN\0");
+        assert_eq!(bin_length % 4, 0);
+        assert_eq!(bin + 8 + bin_length, glb.len());
+        assert_eq!(document["buffers"][0]["byteLength"], json!(bin_length));
+        assert!(document["buffers"][0].get("uri").is_none());
+
+        for view in document["bufferViews"].as_array().unwrap() {
+            let offset = view["byteOffset"].as_u64().unwrap() as usize;
+            let length = view["byteLength"].as_u64().unwrap() as usize;
+            assert_eq!(offset % 4, 0);
+            assert!(offset + length <= bin_length);
+        }
+        assert_eq!(document["meshes"].as_array().unwrap().len(), 2);
+        assert_eq!(document["images"].as_array().unwrap().len(), 3);
+
+        remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn obj_indices_across_nodes() {
+        let directory = directory("obj");
+        let path = directory.join("cubes.obj");
+        let export = export();
+        export.write_obj(&path).unwrap();
+        let obj = read_to_string(&path).unwrap();
+
+        // the amount of vertices in each object, and the indices of the faces of each object
+        let mut objects: Vec<(usize, Vec<usize>)> = Vec::new();
+        for line in obj.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("o") => objects.push((0, Vec::new())),
+                Some("v") => objects.last_mut().unwrap().0 += 1,
+                Some("f") => {
+                    for corner in words {
+                        // position, texture coordinate and normal share the same index
+                        let indices = corner
+                            .split('/')
+                            .map(|i| i.parse().unwrap())
+                            .collect::<Vec<usize>>();
+                        assert_eq!(indices.len(), 3);
+                        assert!(indices.iter().all(|&i| i == indices[0]));
+                        objects.last_mut().unwrap().1.push(indices[0]);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        assert_eq!(objects.len(), 2);
+        let mut offset = 1;
+        for ((vertices, indices), node) in objects.iter().zip(export.nodes.iter()) {
+            assert_eq!(*vertices, node.mesh.positions.len());
+            let expected = node.mesh.indices.iter().map(|&i| i as usize + offset);
+            assert!(indices.iter().cloned().eq(expected));
+            assert_eq!(*indices.iter().min().unwrap(), offset);
+            assert_eq!(*indices.iter().max().unwrap(), offset + vertices - 1);
+            offset += vertices;
+        }
+
+        remove_dir_all(&directory).unwrap();
+    }
+
+    /// Returns whether the texture coordinate lies inside the tile of it's material, the samplers
+    /// of the exported textures don't repeat them.
+    fn inside(uv: [f32; 2], tile: [f32; 4]) -> bool {
+        let e = 1e-5;
+        uv[0] >= tile[0] - e
+            && uv[0] <= tile[0] + tile[2] + e
+            && uv[1] >= tile[1] - e
+            && uv[1] <= tile[1] + tile[3] + e
+    }
+
+    #[test]
+    fn uvs_inside_tiles() {
+        let directory = directory("uv");
+        let export = export();
+        let tiles = export
+            .nodes
+            .iter()
+            .map(|node| node.mesh.tiles.clone())
+            .collect::<Vec<_>>();
+
+        // the texture coordinates of every mesh in the binary chunk of the glb file
+        let path = directory.join("cubes.glb");
+        export.write_glb(&path).unwrap();
+        let glb = read(&path).unwrap();
+        let json_length = LittleEndian::read_u32(&glb[12..16]) as usize;
+        let document: Value = serde_json::from_slice(&glb[20..20 + json_length]).unwrap();
+        let bin = &glb[20 + json_length + 8..];
+        let meshes = document["meshes"].as_array().unwrap();
+        assert_eq!(meshes.len(), tiles.len());
+        for (mesh, tiles) in meshes.iter().zip(tiles.iter()) {
+            let accessor = &document["accessors"][mesh["primitives"][0]["attributes"]["TEXCOORD_0"]
+                .as_u64()
+                .unwrap() as usize];
+            let view = &document["bufferViews"][accessor["bufferView"].as_u64().unwrap() as usize];
+            let offset = view["byteOffset"].as_u64().unwrap() as usize;
+            assert_eq!(accessor["count"].as_u64().unwrap() as usize, tiles.len());
+            for (i, &tile) in tiles.iter().enumerate() {
+                let u = LittleEndian::read_f32(&bin[offset + i * 8..]);
+                let v = LittleEndian::read_f32(&bin[offset + i * 8 + 4..]);
+                assert!(inside([u, v], tile));
+            }
+        }
+
+        // obj flips the vertical texture coordinate
+        let path = directory.join("cubes.obj");
+        export.write_obj(&path).unwrap();
+        let obj = read_to_string(&path).unwrap();
+        let uvs = obj
+            .lines()
+            .filter(|line| line.starts_with("vt "))
+            .map(|line| {
+                let mut words = line.split_whitespace().skip(1);
+                let mut next = || words.next().unwrap().parse::<f32>().unwrap();
+                let u = next();
+                [u, 1.0 - next()]
+            })
+            .collect::<Vec<_>>();
+        let tiles = tiles.concat();
+        assert_eq!(uvs.len(), tiles.len());
+        assert!(uvs
+            .iter()
+            .zip(tiles.iter())
+            .all(|(&uv, &tile)| inside(uv, tile)));
+
+        remove_dir_all(&directory).unwrap();
+    }
+}
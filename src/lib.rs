@@ -7,18 +7,28 @@
 #[macro_use]
 extern crate derivative;
 
+pub mod binary;
+pub mod edit;
+pub mod export;
 pub mod material;
 pub mod model;
 pub mod prefab;
 pub mod raycast;
+pub mod region;
+pub mod shape;
+pub mod storage;
+pub mod structure;
+pub mod terrain;
 pub mod vox;
 pub mod voxel;
 pub mod world;
 
 mod ambient_occlusion;
 mod bundle;
+mod cache;
 mod context;
 mod mesh;
+mod origin;
 mod pass;
 mod plugin;
 mod side;
@@ -13,8 +13,11 @@ use amethyst::{
     },
 };
 use core::num::NonZeroU8;
+use image::{Rgb, RgbImage, Rgba, RgbaImage};
+use serde::{de, ser, Deserializer, Serializer};
 use serde_derive::*;
 use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::iter::repeat;
 use std::ops::Deref;
@@ -46,12 +49,46 @@ pub trait AtlasAccess {
 
     /// Retrieve material handle for the given id.
     fn get(&self, id: &str) -> Option<AtlasMaterialHandle>;
+
+    /// Retrieve the ids of all materials that were created with an id.
+    fn ids(&self) -> MaterialIds {
+        MaterialIds::default()
+    }
+
+    /// Bake the textures of the atlas into images, to use the texture coordinates outside of
+    /// amethyst. Returns `None` if the atlas has no materials or can't be baked.
+    fn textures(&self) -> Option<AtlasTextures> {
+        None
+    }
+}
+
+/// The baked textures of an atlas, see `AtlasAccess::textures`.
+pub struct AtlasTextures {
+    /// The albedo and alpha of the materials.
+    pub albedo_alpha: RgbaImage,
+    /// The emission of the materials.
+    pub emission: RgbImage,
+    /// The roughness of the materials in the green channel and the metalness in the blue channel,
+    /// as used by glTF.
+    pub metallic_roughness: RgbImage,
 }
 
 /// A material handle issued by an `Atlas`.
-#[derive(Clone, Copy, Eq, PartialEq)]
+///
+/// Handles can only be (de)serialized within `with_atlas` or `with_material_ids`. They are serialized
+/// as the string id they were created with and deserialized by looking that id up again.
+/// Materials created without an id are stored by their raw atlas index, which is only valid for an
+/// atlas that was built in the exact same order.
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
 pub struct AtlasMaterialHandle(pub(crate) u32);
 
+/// A two way mapping between material handles and the string ids they were created with.
+#[derive(Clone, Default)]
+pub struct MaterialIds {
+    ids: HashMap<AtlasMaterialHandle, String>,
+    handles: HashMap<String, AtlasMaterialHandle>,
+}
+
 /// A storage resource for `VoxelMaterial`s.
 pub struct Atlas {
     materials: Vec<Box<dyn VoxelMaterial>>,
@@ -155,6 +192,17 @@ impl AtlasAccess for Atlas {
     fn get(&self, id: &str) -> Option<AtlasMaterialHandle> {
         self.lookup.get(id).cloned()
     }
+
+    fn ids(&self) -> MaterialIds {
+        self.lookup
+            .iter()
+            .map(|(id, &handle)| (id.clone(), handle))
+            .collect()
+    }
+
+    fn textures(&self) -> Option<AtlasTextures> {
+        bake_textures(self.size, self.grid, &self.materials)
+    }
 }
 
 impl Asset for Atlas {
@@ -245,6 +293,117 @@ impl AtlasAccess for AtlasData {
     fn get(&self, id: &str) -> Option<AtlasMaterialHandle> {
         self.lookup.get(id).cloned()
     }
+
+    fn ids(&self) -> MaterialIds {
+        self.lookup
+            .iter()
+            .map(|(id, &handle)| (id.clone(), handle))
+            .collect()
+    }
+
+    fn textures(&self) -> Option<AtlasTextures> {
+        bake_textures(self.size, self.grid, &self.materials)
+    }
+}
+
+impl MaterialIds {
+    /// Add a mapping between a string id and a material handle.
+    pub fn insert<S: Into<String>>(&mut self, id: S, handle: AtlasMaterialHandle) {
+        let id = id.into();
+        self.ids.insert(handle, id.clone());
+        self.handles.insert(id, handle);
+    }
+
+    /// Retrieve the string id of a material handle.
+    pub fn id(&self, handle: AtlasMaterialHandle) -> Option<&str> {
+        self.ids.get(&handle).map(|id| id.as_str())
+    }
+
+    /// Retrieve the material handle for a string id.
+    pub fn get(&self, id: &str) -> Option<AtlasMaterialHandle> {
+        self.handles.get(id).cloned()
+    }
+
+    /// Iterate over all string ids and their material handles.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, AtlasMaterialHandle)> {
+        self.handles
+            .iter()
+            .map(|(id, &handle)| (id.as_str(), handle))
+    }
+}
+
+impl<S: Into<String>> std::iter::FromIterator<(S, AtlasMaterialHandle)> for MaterialIds {
+    fn from_iter<I: IntoIterator<Item = (S, AtlasMaterialHandle)>>(iter: I) -> Self {
+        let mut ids = MaterialIds::default();
+        for (id, handle) in iter {
+            ids.insert(id, handle);
+        }
+        ids
+    }
+}
+
+thread_local! {
+    static MATERIAL_IDS: RefCell<Option<Arc<MaterialIds>>> = RefCell::new(None);
+}
+
+/// Run `f` with material handles being (de)serialized by the string ids of `atlas`.
+/// Use this when saving or loading voxels, so that the saved voxels survive changes to the atlas.
+pub fn with_atlas<A: AtlasAccess + ?Sized, R, F: FnOnce() -> R>(atlas: &A, f: F) -> R {
+    with_material_ids(Arc::new(atlas.ids()), f)
+}
+
+/// Run `f` with material handles being (de)serialized by the string ids in `ids`.
+pub fn with_material_ids<R, F: FnOnce() -> R>(ids: Arc<MaterialIds>, f: F) -> R {
+    // restores the previous ids, even when `f` panics
+    struct Restore(Option<Arc<MaterialIds>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            MATERIAL_IDS.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+
+    let _restore = Restore(MATERIAL_IDS.with(|current| current.replace(Some(ids))));
+    f()
+}
+
+/// The serialized form of an `AtlasMaterialHandle`.
+#[derive(Serialize, Deserialize)]
+enum SerializedHandle<'a> {
+    /// The string id the material was created with.
+    Id(Cow<'a, str>),
+    /// The raw atlas index, for materials that were created without an id.
+    Index(u32),
+}
+
+fn material_ids<E, F: FnOnce(&'static str) -> E>(error: F) -> Result<Arc<MaterialIds>, E> {
+    MATERIAL_IDS
+        .with(|current| current.borrow().clone())
+        .ok_or_else(|| error("material handles can only be (de)serialized within `with_atlas`"))
+}
+
+impl serde::Serialize for AtlasMaterialHandle {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ids = material_ids(ser::Error::custom)?;
+        let handle = match ids.id(*self) {
+            Some(id) => SerializedHandle::Id(Cow::Borrowed(id)),
+            None => SerializedHandle::Index(self.0),
+        };
+        serde::Serialize::serialize(&handle, serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for AtlasMaterialHandle {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ids = material_ids(de::Error::custom)?;
+        match <SerializedHandle as serde::Deserialize>::deserialize(deserializer)? {
+            SerializedHandle::Id(id) => ids
+                .get(&id)
+                .ok_or_else(|| de::Error::custom(format!("unknown material id `{}`", id))),
+            SerializedHandle::Index(index) => Ok(AtlasMaterialHandle(index)),
+        }
+    }
 }
 
 impl Default for ColoredMaterial {
@@ -427,29 +586,7 @@ fn build_material(
     material_storage: &AssetStorage<Material>,
     defaults: &MaterialDefaults,
 ) -> Handle<Material> {
-    let slots = size / grid;
-
-    let find_material = |x, y| {
-        let texture_x = x as usize - (x as usize / grid) * grid;
-        let texture_y = y as usize - (y as usize / grid) * grid;
-        materials
-            .get((y as usize / grid) * slots + x as usize / grid)
-            .map(|m| {
-                let border = (grid - m.dimension()) / 2;
-                let border = |x, tile| match (x < border, tile) {
-                    (true, true) => ((x + m.dimension()) - border) % m.dimension(),
-                    (true, false) => 0,
-                    (false, true) => (x - border) % m.dimension(),
-                    (false, false) => (m.dimension() - 1).min(x - border),
-                };
-                let t = m.tiling();
-                (
-                    m,
-                    border(texture_x, t.horizontal()),
-                    border(texture_y, t.vertical()),
-                )
-            })
-    };
+    let find_material = |x, y| find_material(size, grid, materials, x, y);
 
     let mips = {
         let mut i = 1;
@@ -507,6 +644,69 @@ fn build_material(
     loader.load_from_data(mat, (), material_storage)
 }
 
+/// Find the material at pixel `x, y` of an atlas, and the pixel within that material.
+fn find_material(
+    size: usize,
+    grid: usize,
+    materials: &[Box<dyn VoxelMaterial>],
+    x: usize,
+    y: usize,
+) -> Option<(&dyn VoxelMaterial, usize, usize)> {
+    let slots = size / grid;
+    let texture_x = x - (x / grid) * grid;
+    let texture_y = y - (y / grid) * grid;
+    materials.get((y / grid) * slots + x / grid).map(|m| {
+        let border = (grid - m.dimension()) / 2;
+        let border = |x, tile| match (x < border, tile) {
+            (true, true) => ((x + m.dimension()) - border) % m.dimension(),
+            (true, false) => 0,
+            (false, true) => (x - border) % m.dimension(),
+            (false, false) => (m.dimension() - 1).min(x - border),
+        };
+        let t = m.tiling();
+        (
+            m.as_ref(),
+            border(texture_x, t.horizontal()),
+            border(texture_y, t.vertical()),
+        )
+    })
+}
+
+fn bake_textures(
+    size: usize,
+    grid: usize,
+    materials: &[Box<dyn VoxelMaterial>],
+) -> Option<AtlasTextures> {
+    if materials.is_empty() {
+        return None;
+    }
+
+    let find_material =
+        |x: u32, y: u32| find_material(size, grid, materials, x as usize, y as usize);
+    let size = size as u32;
+
+    Some(AtlasTextures {
+        albedo_alpha: RgbaImage::from_fn(size, size, |x, y| {
+            Rgba(
+                find_material(x, y)
+                    .map(|(m, x, y)| m.albedo_alpha(x, y))
+                    .unwrap_or([255, 0, 255, 255]),
+            )
+        }),
+        emission: RgbImage::from_fn(size, size, |x, y| {
+            Rgb(find_material(x, y)
+                .map(|(m, x, y)| m.emission(x, y))
+                .unwrap_or([0, 0, 0]))
+        }),
+        metallic_roughness: RgbImage::from_fn(size, size, |x, y| {
+            let [metallic, roughness] = find_material(x, y)
+                .map(|(m, x, y)| m.metallic_roughness(x, y))
+                .unwrap_or([240, 8]);
+            Rgb([0, roughness, metallic])
+        }),
+    })
+}
+
 fn build_texture<'a, F: Fn(usize, usize) -> [u8; 4]>(
     width: usize,
     mips: NonZeroU8,
@@ -537,3 +737,45 @@ fn build_texture<'a, F: Fn(usize, usize) -> [u8; 4]>(
                 .collect::<Vec<_>>(),
         ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atlas() -> MaterialIds {
+        vec![
+            ("stone", AtlasMaterialHandle(0)),
+            ("dirt", AtlasMaterialHandle(4)),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn handles_roundtrip_by_id() {
+        let ids = Arc::new(atlas());
+        let handles = vec![AtlasMaterialHandle(4), AtlasMaterialHandle(7)];
+        let json = with_material_ids(ids, || serde_json::to_string(&handles).unwrap());
+        assert_eq!(json, r#"[{"Id":"dirt"},{"Index":7}]"#);
+
+        let moved: MaterialIds = vec![("dirt", AtlasMaterialHandle(2))].into_iter().collect();
+        let loaded: Vec<AtlasMaterialHandle> =
+            with_material_ids(Arc::new(moved), || serde_json::from_str(&json).unwrap());
+        assert!(loaded == vec![AtlasMaterialHandle(2), AtlasMaterialHandle(7)]);
+    }
+
+    #[test]
+    fn unknown_ids_are_rejected() {
+        let ids = Arc::new(atlas());
+        let result: Result<AtlasMaterialHandle, _> =
+            with_material_ids(ids, || serde_json::from_str(r#"{"Id":"water"}"#));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn handles_require_ids_in_scope() {
+        assert!(serde_json::to_string(&AtlasMaterialHandle(0)).is_err());
+        let result: Result<AtlasMaterialHandle, _> = serde_json::from_str(r#"{"Index":0}"#);
+        assert!(result.is_err());
+    }
+}
@@ -6,6 +6,7 @@ use amethyst::{
         rendy::{command::QueueId, factory::Factory},
         types::Backend,
     },
+    shrev::EventChannel,
 };
 
 use nalgebra_glm::*;
@@ -14,19 +15,35 @@ use crate::ambient_occlusion::*;
 use crate::context::*;
 use crate::material::*;
 use crate::model::*;
+use crate::storage::DetailStorage;
 use crate::triangulate::Triangulation;
 use crate::voxel::{Data, NestedVoxel, Voxel};
-use crate::world::VoxelWorld;
+use crate::world::{ChunkEvent, ChunkEventType, VoxelWorld};
 
 use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::mem::replace;
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 /// Asset for voxelmesh rendering
 pub struct VoxelMesh {
     pub(crate) inner: Option<amethyst::renderer::types::Mesh>,
     pub(crate) atlas: Handle<Atlas>,
+    /// The size of the vertex and index data of the mesh in bytes.
+    pub(crate) bytes: usize,
+}
+
+/// How the faces of voxels are turned into triangles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MeshingMode {
+    /// Every visible face of a voxel is a separate quad.
+    Cubes,
+    /// A smooth surface through the subvoxels, using their density as a scalar field.
+    /// See `Data::density`. Chunks of a `VoxelWorld` connect seamlessly, so distant chunks are
+    /// meshed with full detail, see `VoxelWorld::with_lod`.
+    Smooth,
 }
 
 /// A component that manages a dynamic voxelmesh
@@ -34,8 +51,27 @@ pub struct DynamicVoxelMesh<T: Data> {
     pub(crate) data: NestedVoxel<T>,
     pub(crate) atlas: Handle<Atlas>,
     pub(crate) transform: Mat4x4,
-    pub(crate) parent: Option<(Entity, [isize; 3])>,
+    /// The world entity and absolute chunk coordinate, if this mesh is a chunk in a `VoxelWorld`.
+    pub(crate) parent: Option<(Entity, [i64; 3])>,
     pub(crate) dirty: bool,
+    /// Set when the voxel is accessed mutably, cleared when a `ChunkEventType::Modified` is sent.
+    pub(crate) modified: bool,
+    /// Set when the voxel is accessed mutably, passed to `VoxelSource::drop_voxel` when a world
+    /// chunk is unloaded.
+    pub(crate) unsaved: bool,
+    pub(crate) auto_collapse: bool,
+    /// The level of detail the mesh is built with, chosen by the `WorldSystem` for world chunks.
+    pub(crate) lod: usize,
+    pub(crate) meshing: MeshingMode,
+    /// Replaced whenever the voxel, the level of detail or the mesh changes, see `next_generation`.
+    pub(crate) generation: u64,
+}
+
+/// A generation that no `DynamicVoxelMesh` had before. Chunks in the cache of a `VoxelWorld`
+/// compare the generations of their neighbours to find out whether those changed.
+pub(crate) fn next_generation() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
 }
 
 pub struct DynamicVoxelMeshData<T: Data> {
@@ -62,6 +98,7 @@ pub struct TriangulatorSystemData<'a, B: Backend, V: Data> {
     queue_id: ReadExpect<'a, QueueId>,
     factory: ReadExpect<'a, Factory<B>>,
     atlas_storage: Read<'a, AssetStorage<Atlas>>,
+    events: Write<'a, EventChannel<ChunkEvent>>,
 }
 
 #[derive(SystemData)]
@@ -105,6 +142,12 @@ impl<T: Data> DynamicVoxelMesh<T> {
             ),
             parent: None,
             dirty: true,
+            modified: false,
+            unsaved: false,
+            auto_collapse: false,
+            lod: 0,
+            meshing: MeshingMode::Cubes,
+            generation: next_generation(),
         }
     }
 
@@ -122,8 +165,32 @@ impl<T: Data> DynamicVoxelMesh<T> {
             ),
             parent: None,
             dirty: true,
+            modified: false,
+            unsaved: false,
+            auto_collapse: false,
+            lod: 0,
+            meshing: MeshingMode::Cubes,
+            generation: next_generation(),
         }
     }
+
+    /// Collapse uniform subvoxels before the mesh is triangulated, see `Voxel::collapse`.
+    /// The root voxel itself is never collapsed, so that it always stays a detail voxel.
+    pub fn with_auto_collapse(mut self, auto_collapse: bool) -> Self {
+        self.auto_collapse = auto_collapse;
+        self
+    }
+
+    /// Set how the faces of the voxel are turned into triangles. The default is `MeshingMode::Cubes`.
+    pub fn with_meshing_mode(mut self, meshing: MeshingMode) -> Self {
+        self.meshing = meshing;
+        self
+    }
+
+    /// The level of detail the mesh is built with, see `NestedVoxel::downsample`.
+    pub fn lod(&self) -> usize {
+        self.lod
+    }
 }
 
 impl<T: Data> Deref for DynamicVoxelMesh<T> {
@@ -137,6 +204,9 @@ impl<T: Data> Deref for DynamicVoxelMesh<T> {
 impl<T: Data> DerefMut for DynamicVoxelMesh<T> {
     fn deref_mut(&mut self) -> &mut NestedVoxel<T> {
         self.dirty = true;
+        self.modified = true;
+        self.unsaved = true;
+        self.generation = next_generation();
         &mut self.data
     }
 }
@@ -161,7 +231,11 @@ impl<'a, B: Backend, V: Data + Default> System<'a> for TriangulatorSystem<B, V>
                 move |(e, dynamic_mesh)| {
                     if dynamic_mesh.dirty && atlas_storage.contains(&dynamic_mesh.atlas) {
                         dynamic_mesh.dirty = false;
-                        Some(e)
+                        dynamic_mesh.generation = next_generation();
+                        if dynamic_mesh.auto_collapse {
+                            dynamic_mesh.data.collapse_detail();
+                        }
+                        Some((e, replace(&mut dynamic_mesh.modified, false)))
                     } else {
                         None
                     }
@@ -170,27 +244,50 @@ impl<'a, B: Backend, V: Data + Default> System<'a> for TriangulatorSystem<B, V>
             .take(self.triangulation_limit)
             .collect::<Vec<_>>();
 
-        for dirty in dirty_meshes {
+        for (dirty, modified) in dirty_meshes {
             let dynamic_mesh = data.dynamic_mesh_storage.get(dirty).unwrap();
+            let event = |event_type| {
+                dynamic_mesh.parent.map(|(world, coord)| ChunkEvent {
+                    event_type,
+                    world,
+                    coord,
+                    chunk: Some(dirty),
+                })
+            };
+            if modified {
+                data.events.iter_write(event(ChunkEventType::Modified));
+            }
+
             let atlas = data.atlas_storage.get(&dynamic_mesh.atlas).unwrap();
             // triangulate the mesh
-            let mesh = dynamic_mesh
+            let (mesh, bytes) = dynamic_mesh
                 .parent
                 .map(|(world, coord)| {
                     let world = data
                         .world_storage
                         .get(world)
                         .expect("DynamicVoxelMesh parent invalid");
-                    build_mesh(
-                        Some((
-                            &dynamic_mesh.data,
-                            &WorldContext::new(coord, world, &data.dynamic_mesh_storage),
+                    let context = WorldContext::new(coord, world, &data.dynamic_mesh_storage);
+                    if dynamic_mesh.lod > 0 {
+                        build_lod_mesh(
+                            &dynamic_mesh.data.downsample(dynamic_mesh.lod),
+                            &context,
+                            1 << dynamic_mesh.lod,
                             &dynamic_mesh.transform,
-                        )),
-                        atlas,
-                        *data.queue_id,
-                        &data.factory,
-                    )
+                            dynamic_mesh.meshing,
+                            atlas,
+                            *data.queue_id,
+                            &data.factory,
+                        )
+                    } else {
+                        build_mesh(
+                            Some((&dynamic_mesh.data, &context, &dynamic_mesh.transform)),
+                            dynamic_mesh.meshing,
+                            atlas,
+                            *data.queue_id,
+                            &data.factory,
+                        )
+                    }
                 })
                 .unwrap_or_else(|| {
                     build_mesh(
@@ -199,6 +296,7 @@ impl<'a, B: Backend, V: Data + Default> System<'a> for TriangulatorSystem<B, V>
                             &VoxelContext::new(&dynamic_mesh.data),
                             &dynamic_mesh.transform,
                         )),
+                        dynamic_mesh.meshing,
                         atlas,
                         *data.queue_id,
                         &data.factory,
@@ -210,10 +308,12 @@ impl<'a, B: Backend, V: Data + Default> System<'a> for TriangulatorSystem<B, V>
             let handle = data.mesh_storage.insert(VoxelMesh {
                 inner: mesh,
                 atlas: dynamic_mesh.atlas.clone(),
+                bytes,
             });
 
             // add the handle to the entity
             data.handle_storage.insert(dirty, handle.clone()).ok();
+            data.events.iter_write(event(ChunkEventType::Meshed));
         }
     }
 }
@@ -269,11 +369,12 @@ impl<'a, B: Backend, V: Data + Default> System<'a> for VoxelMeshProcessor<B, V>
                         .map(|(_, voxel)| VoxelContext::new(voxel))
                         .collect::<Vec<_>>();
 
-                    let mesh = build_mesh(
+                    let (mesh, bytes) = build_mesh(
                         voxels
                             .iter()
                             .zip(context.iter())
                             .map(|((sub, voxel), context)| (voxel, context, &sub.offset)),
+                        MeshingMode::Cubes,
                         &atlas,
                         **queue_id,
                         factory,
@@ -282,6 +383,7 @@ impl<'a, B: Backend, V: Data + Default> System<'a> for VoxelMeshProcessor<B, V>
                     Ok(ProcessingState::Loaded(VoxelMesh {
                         inner: mesh,
                         atlas: loader.load_from_data(atlas, (), atlas_storage),
+                        bytes,
                     }))
                 }
             },
@@ -292,7 +394,7 @@ impl<'a, B: Backend, V: Data + Default> System<'a> for VoxelMeshProcessor<B, V>
     }
 }
 
-fn build_voxel<V: Data>(
+pub(crate) fn build_voxel<V: Data>(
     model: &ModelData,
     submodel: &SubModelData,
     atlas: &mut AtlasData,
@@ -334,16 +436,17 @@ fn build_voxel<V: Data>(
 
     NestedVoxel::Detail {
         data: Default::default(),
-        detail: Arc::new(detail),
+        detail: Arc::new(DetailStorage::Plain(detail)),
     }
 }
 
 fn build_mesh<'a, 'c, B, V, C, A, I>(
     iter: I,
+    meshing: MeshingMode,
     atlas: &A,
     queue: QueueId,
     factory: &Factory<B>,
-) -> Option<amethyst::renderer::types::Mesh>
+) -> (Option<amethyst::renderer::types::Mesh>, usize)
 where
     B: Backend,
     V: Voxel,
@@ -352,11 +455,43 @@ where
     I: IntoIterator<Item = (&'a V, &'c C, &'a Mat4x4)>,
 {
     let mut tri = Triangulation::new(false);
-
     for (voxel, context, transform) in iter {
-        let shared = SharedVertexData::build(voxel, context);
-        tri.append(voxel, &shared, context, vec3(0.0, 0.0, 0.0), 1.0, transform);
+        if meshing == MeshingMode::Smooth {
+            tri.append_smooth(voxel, context, 1, transform);
+        } else {
+            let shared = SharedVertexData::build(voxel, context);
+            tri.append(voxel, &shared, context, vec3(0.0, 0.0, 0.0), 1.0, transform);
+        }
     }
 
-    tri.to_mesh(atlas, queue, factory)
+    let data = tri.to_data(atlas);
+    (data.to_mesh(queue, factory), data.bytes())
+}
+
+/// Build a mesh from a downsampled voxel, in cubes of `stride` subvoxels.
+fn build_lod_mesh<B, V, C, A>(
+    voxel: &NestedVoxel<V>,
+    context: &C,
+    stride: usize,
+    transform: &Mat4x4,
+    meshing: MeshingMode,
+    atlas: &A,
+    queue: QueueId,
+    factory: &Factory<B>,
+) -> (Option<amethyst::renderer::types::Mesh>, usize)
+where
+    B: Backend,
+    V: Data,
+    C: Context<NestedVoxel<V>>,
+    A: AtlasAccess,
+{
+    let mut tri = Triangulation::new(false);
+    if meshing == MeshingMode::Smooth {
+        tri.append_smooth(voxel, context, stride, transform);
+    } else {
+        let shared = SharedVertexData::build(voxel, context);
+        tri.append_blocks(voxel, &shared, context, stride, transform);
+    }
+    let data = tri.to_data(atlas);
+    (data.to_mesh(queue, factory), data.bytes())
 }
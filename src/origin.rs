@@ -0,0 +1,138 @@
+use amethyst::{
+    core::transform::{Parent, Transform},
+    ecs::prelude::*,
+    renderer::{ActiveCamera, Camera},
+};
+use nalgebra_glm::*;
+
+/// A resource that keeps the camera close to the origin of the render space, to prevent the loss
+/// of precision that causes meshes to jitter far away from the origin.
+///
+/// Whenever the camera moves further than the threshold away from the origin, the origin is moved
+/// by a whole amount of chunks. All entities with a `Transform` and without a `Parent` with a
+/// `Transform` are moved back by the same amount.
+///
+/// `VoxelWorld`s without a `Transform` are aligned to the absolute origin, their chunks are moved
+/// along instead and are positioned relative to the origin, so that they stay precise.
+/// `VoxelWorld`s with a `Transform` are moved as a whole, their chunks keep their position relative
+/// to the world. Because the offset is applied before the transform of the world, chunks far away
+/// from the origin of such a world don't gain any precision. Leave the `Transform` off of large
+/// worlds that use a floating origin.
+pub struct FloatingOrigin {
+    chunk_size: f32,
+    threshold: f32,
+    origin: [i64; 3],
+    rebased: bool,
+}
+
+/// Moves the origin of the `FloatingOrigin` resource along with the camera.
+pub(crate) struct FloatingOriginSystem;
+
+impl FloatingOrigin {
+    /// Create a new `FloatingOrigin` that moves in steps of `chunk_size`, usually the scale of the
+    /// chunks of the `VoxelWorld`s. The origin is moved when the camera is more than `threshold`
+    /// away from it along any axis.
+    pub fn new(chunk_size: f32, threshold: f32) -> Self {
+        FloatingOrigin {
+            chunk_size,
+            threshold: threshold.max(chunk_size),
+            origin: [0; 3],
+            rebased: false,
+        }
+    }
+
+    /// The location of the origin of the render space, in chunks of `chunk_size`.
+    pub fn origin(&self) -> [i64; 3] {
+        self.origin
+    }
+
+    /// The absolute position of the origin of the render space.
+    pub fn offset(&self) -> [f64; 3] {
+        let f = |i: usize| self.origin[i] as f64 * self.chunk_size as f64;
+        [f(0), f(1), f(2)]
+    }
+
+    /// Returns whether the origin was moved during the current frame.
+    pub fn rebased(&self) -> bool {
+        self.rebased
+    }
+
+    /// Convert a position in render space to an absolute position.
+    pub fn to_absolute(&self, position: &Vec3) -> [f64; 3] {
+        let offset = self.offset();
+        let f = |i: usize| position[i] as f64 + offset[i];
+        [f(0), f(1), f(2)]
+    }
+
+    /// Convert an absolute position to a position in render space.
+    pub fn to_render(&self, position: [f64; 3]) -> Vec3 {
+        let offset = self.offset();
+        let f = |i: usize| (position[i] - offset[i]) as f32;
+        vec3(f(0), f(1), f(2))
+    }
+}
+
+impl<'s> System<'s> for FloatingOriginSystem {
+    type SystemData = (
+        Entities<'s>,
+        Option<Write<'s, FloatingOrigin>>,
+        Read<'s, ActiveCamera>,
+        ReadStorage<'s, Camera>,
+        ReadStorage<'s, Parent>,
+        WriteStorage<'s, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, floating_origin, active_camera, cameras, parents, mut transforms): Self::SystemData,
+    ) {
+        let mut floating_origin = match floating_origin {
+            Some(floating_origin) => floating_origin,
+            None => return,
+        };
+        floating_origin.rebased = false;
+
+        let position = active_camera
+            .entity
+            .as_ref()
+            .and_then(|ac| transforms.get(*ac))
+            .or_else(|| (&cameras, &transforms).join().next().map(|(_c, t)| t))
+            .map(|t| t.global_matrix().column(3).xyz());
+        let position = match position {
+            Some(position) => position,
+            None => return,
+        };
+
+        if (0..3).all(|i| position[i].abs() <= floating_origin.threshold) {
+            return;
+        }
+
+        let size = floating_origin.chunk_size;
+        let shift = [
+            (position[0] / size).floor() as i64,
+            (position[1] / size).floor() as i64,
+            (position[2] / size).floor() as i64,
+        ];
+        for i in 0..3 {
+            floating_origin.origin[i] += shift[i];
+        }
+        floating_origin.rebased = true;
+
+        // entities of which the parent has no transform are positioned in render space as well,
+        // like the chunks of worlds without a transform
+        let translation = -vec3(shift[0] as f32, shift[1] as f32, shift[2] as f32) * size;
+        let roots = (&entities, &transforms)
+            .join()
+            .filter(|&(e, _)| match parents.get(e) {
+                Some(parent) => !transforms.contains(parent.entity),
+                None => true,
+            })
+            .map(|(e, _)| e)
+            .collect::<Vec<_>>();
+        for e in roots {
+            if let Some(transform) = transforms.get_mut(e) {
+                transform.prepend_translation(translation);
+            }
+        }
+    }
+}
@@ -1,15 +1,31 @@
 pub use crate::{
+    binary::BinaryVoxel,
     bundle::VoxelBundle,
+    cache::CacheStats,
+    context::{Context, DetailContext, VoxelContext, WorldContext},
+    edit::Fill,
+    export::VoxelExport,
     material::{
-        Atlas, AtlasAccess, AtlasData, AtlasMaterialHandle, ColoredMaterial, TexturedMaterial,
-        Tiling, VoxelMaterial,
+        with_atlas, with_material_ids, Atlas, AtlasAccess, AtlasData, AtlasMaterialHandle,
+        AtlasTextures, ColoredMaterial, MaterialIds, TexturedMaterial, Tiling, VoxelMaterial,
     },
-    mesh::{DynamicVoxelMesh, VoxelMesh},
+    mesh::{DynamicVoxelMesh, MeshingMode, VoxelMesh},
+    origin::FloatingOrigin,
     prefab::{DynamicVoxelMeshPrefab, VoxelMeshPrefab},
     raycast::{Raycast, RaycastBase},
+    region::RegionSource,
+    shape::{Shape, ShapeKind},
+    storage::{DetailStorage, PaletteStorage},
+    structure::{Structure, StructureLayer, StructureSource},
+    terrain::TerrainSource,
+    triangulate::VoxelMeshData,
     vox::VoxFormat,
     voxel::{Data, NestedVoxel, SimpleVoxel, Voxel},
-    world::{Limits, VoxelSource, VoxelSourceResult, VoxelWorld, VoxelWorldAccess},
+    world::{
+        CancellationToken, ChunkEvent, ChunkEventType, ChunkFailure, Limits, QueueStats,
+        RetryPolicy, ViewShape, VoxelSource, VoxelSourceResult, VoxelViewer, VoxelWorld,
+        VoxelWorldAccess,
+    },
 };
 
 pub type RenderVoxelPbr =
@@ -90,7 +90,7 @@ impl<'a, 'b, V: Data> RaycastBase for VoxelWorldAccess<'a, 'b, V> {
         Ray {
             origin,
             direction,
-            transform: Mat4::identity(),
+            transform: self.world.transform,
             length: None,
         }
     }
@@ -100,16 +100,18 @@ impl<'a, 'b, V: Data> Raycast for VoxelWorldAccess<'a, 'b, V> {
     type Child = NestedVoxel<V>;
 
     fn cast(&self, ray: &Ray) -> Option<Intersection> {
-        let origin = vec3(
-            self.world.origin[0] as f32,
-            self.world.origin[1] as f32,
-            self.world.origin[2] as f32,
-        );
-        // the current location being checked on the ray
-        let current = ray.origin * (1.0 / self.world.scale) - origin;
-        cast(self, ray, current, ray.direction, 30).map(|mut intersection| {
-            intersection.position = intersection.position + origin;
-            intersection.position = intersection.position * self.world.scale;
+        // the current location being checked on the ray, in the space of the world,
+        //  relative to the chunk that contains the origin
+        let (_, origin) = self.world.origin_chunk();
+        let transform = inverse(&ray.transform);
+        let current = transform * vec4(ray.origin[0], ray.origin[1], ray.origin[2], 1.0);
+        let current = (vec4_to_vec3(&current) + origin) * (1.0 / self.world.scale);
+        let current_direction = transform.transform_vector(&ray.direction);
+        cast(self, ray, current, current_direction, 30).map(|mut intersection| {
+            let mut pos = vec3_to_vec4(&(intersection.position * self.world.scale - origin));
+            pos.w = 1.0;
+            pos = ray.transform * pos;
+            intersection.position = vec4_to_vec3(&pos);
             intersection
         })
     }
@@ -121,21 +123,18 @@ impl<'a, 'b, V: Data> Raycast for VoxelWorldAccess<'a, 'b, V> {
         coord: [isize; 3],
         normal: Vec3,
     ) -> Option<Intersection> {
-        if (0..3).fold(true, |b, i| {
-            b && coord[i] >= 0 && coord[i] < self.world.dims[i] as isize
-        }) {
-            let i = coord[0] as usize
-                + coord[1] as usize * self.world.dims[0]
-                + coord[2] as usize * self.world.dims[0] * self.world.dims[1];
+        let (origin, _) = self.world.origin_chunk();
+        let coord = [
+            origin[0] + coord[0] as i64,
+            origin[1] + coord[1] as i64,
+            origin[2] + coord[2] as i64,
+        ];
+        if let Some(i) = self.world.index(coord) {
             if let Some(voxel) = self.world.data[i].get().and_then(|e| self.chunks.get(e)) {
                 if voxel.visible() {
                     let sc = self.world.scale;
                     let s = scaling(&vec3(sc, sc, sc));
-                    let t = translation(&vec3(
-                        (self.world.origin[0] + coord[0]) as f32 * sc,
-                        (self.world.origin[1] + coord[1]) as f32 * sc,
-                        (self.world.origin[2] + coord[2]) as f32 * sc,
-                    ));
+                    let t = translation(&self.world.chunk_translation(coord));
                     let r = Ray {
                         transform: ray.transform * t * s,
                         origin: ray.origin,
@@ -154,7 +153,7 @@ impl<'a, 'b, V: Data> Raycast for VoxelWorldAccess<'a, 'b, V> {
             } else {
                 return Some(Intersection {
                     inner: None,
-                    index: 0,
+                    index: i,
                     position: current,
                     normal,
                 });
@@ -258,6 +257,19 @@ impl<T: Voxel> Raycast for T {
                                 normal,
                             });
                         }
+                    } else if !voxel.shape().is_cube() {
+                        // hit the shape inside of the voxel, or continue with the next voxel
+                        let direction = inverse(&ray.transform).transform_vector(&ray.direction);
+                        let local =
+                            current - vec3(coord[0] as f32, coord[1] as f32, coord[2] as f32);
+                        if let Some((t, shape_normal)) = voxel.shape().raycast(local, direction) {
+                            return Some(Intersection {
+                                inner: None,
+                                index: i,
+                                position: current + direction * t,
+                                normal: shape_normal,
+                            });
+                        }
                     } else {
                         return Some(Intersection {
                             inner: None,
@@ -0,0 +1,452 @@
+use crate::binary::{decode, encode, BinaryVoxel};
+use crate::material::MaterialIds;
+use crate::voxel::{Data, NestedVoxel};
+use crate::world::{Limits, VoxelSource, VoxelSourceResult};
+
+use amethyst::{ecs::prelude::*, error::Error};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::mem::replace;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+type E = LittleEndian;
+
+const MAGIC: &[u8; 4] = b"AVRG";
+const VERSION: u16 = 1;
+
+/// The size of the header of a region file, excluding the offset table.
+const HEADER: u64 = 8;
+/// The size of a single entry in the offset table. Every entry is an offset and a length.
+const ENTRY: u64 = 12;
+
+/// A generator for chunks that were never saved.
+pub type Generator<T> = dyn Fn([i64; 3]) -> NestedVoxel<T> + Send + Sync;
+
+/// A `VoxelSource` that persists chunks in region files on disk.
+///
+/// Every region file stores a cube of chunks, 32 chunks wide by default. Chunks are loaded on the
+/// background pool and written back when they are dropped by the `VoxelWorld`, if they were modified.
+/// Chunks that were never saved are created by a user supplied generator. Chunks that can't be read
+/// fail to load and are retried according to the `RetryPolicy` of the world, they are never replaced
+/// by generated chunks.
+///
+/// Chunks are stored in the binary format of the `binary` module. Materials are stored by the ids
+/// in the `MaterialIds` passed to `RegionSource::new`, usually obtained through `AtlasAccess::ids`.
+pub struct RegionSource<T: Data> {
+    directory: PathBuf,
+    region_size: usize,
+    limits: Limits,
+    ids: Arc<MaterialIds>,
+    generator: Arc<Generator<T>>,
+    /// Serializes all file access, so that chunks are never read while being written.
+    io: Arc<Mutex<()>>,
+    /// Dropped chunks that are not written to disk yet, so that they can be loaded again meanwhile.
+    pending: Arc<Mutex<HashMap<[i64; 3], (u64, NestedVoxel<T>)>>>,
+    /// Errors of chunks that failed to save, see `RegionSource::take_errors`.
+    errors: Arc<Mutex<Vec<([i64; 3], io::Error)>>>,
+    generation: u64,
+}
+
+impl<T: Data> RegionSource<T>
+where
+    NestedVoxel<T>: BinaryVoxel,
+{
+    /// Create a new `RegionSource` that stores it's region files in `directory`.
+    /// Chunks that are not saved in the region files are created by `generator`.
+    pub fn new<P, G>(directory: P, ids: MaterialIds, generator: G) -> Self
+    where
+        P: Into<PathBuf>,
+        G: 'static + Fn([i64; 3]) -> NestedVoxel<T> + Send + Sync,
+    {
+        RegionSource {
+            directory: directory.into(),
+            region_size: 32,
+            limits: Limits {
+                from: [None; 3],
+                to: [None; 3],
+            },
+            ids: Arc::new(ids),
+            generator: Arc::new(generator),
+            io: Arc::new(Mutex::new(())),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            errors: Arc::new(Mutex::new(Vec::new())),
+            generation: 0,
+        }
+    }
+
+    /// Set the amount of chunks along each axis of a region file.
+    /// Region files that were written with a different size can't be read.
+    pub fn with_region_size(mut self, region_size: usize) -> Self {
+        assert!(region_size > 0, "region size must be at least 1");
+        self.region_size = region_size;
+        self
+    }
+
+    /// Set the limits in chunks of this source.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Take the errors of the chunks that failed to save since the last call.
+    /// Chunks that failed to save are kept in memory, they are written again when they are dropped
+    /// again.
+    pub fn take_errors(&self) -> Vec<([i64; 3], io::Error)> {
+        replace(&mut *self.errors.lock().unwrap(), Vec::new())
+    }
+
+    fn region(&self) -> Region {
+        Region {
+            directory: self.directory.clone(),
+            size: self.region_size,
+        }
+    }
+}
+
+impl<'s, T: Data> VoxelSource<'s, T> for RegionSource<T>
+where
+    NestedVoxel<T>: BinaryVoxel,
+{
+    type SystemData = ();
+
+    fn load_voxel(&mut self, _: &mut (), coord: [i64; 3]) -> VoxelSourceResult<T> {
+        if let Some((_, voxel)) = self.pending.lock().unwrap().get(&coord) {
+            return VoxelSourceResult::Ok(voxel.clone());
+        }
+
+        let region = self.region();
+        let ids = self.ids.clone();
+        let generator = self.generator.clone();
+        let io = self.io.clone();
+        VoxelSourceResult::Loading(Box::new(move |_| {
+            let loaded = {
+                let _io = io.lock().unwrap();
+                region.read(coord)
+            };
+            match loaded.and_then(|bytes| bytes.map(|bytes| decode(&bytes, &ids)).transpose()) {
+                Ok(Some(voxel)) => Ok(voxel),
+                Ok(None) => Ok(generator(coord)),
+                Err(e) => Err(Error::from(e)),
+            }
+        }))
+    }
+
+    fn drop_voxel(
+        &mut self,
+        _: &mut (),
+        coord: [i64; 3],
+        voxel: NestedVoxel<T>,
+        modified: bool,
+    ) -> Box<dyn FnOnce() + Send> {
+        // unmodified chunks are stored already or can be generated again, unless they failed to save
+        if !modified && !self.pending.lock().unwrap().contains_key(&coord) {
+            return Box::new(|| ());
+        }
+
+        self.generation += 1;
+        let generation = self.generation;
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(coord, (generation, voxel.clone()));
+
+        let region = self.region();
+        let ids = self.ids.clone();
+        let io = self.io.clone();
+        let pending = self.pending.clone();
+        let errors = self.errors.clone();
+        Box::new(move || {
+            let written = encode(&voxel, &ids).and_then(|bytes| {
+                let _io = io.lock().unwrap();
+                region.write(coord, &bytes)
+            });
+
+            // the chunk might have been loaded and dropped again in the meantime
+            let mut pending = pending.lock().unwrap();
+            match written {
+                Ok(()) if pending.get(&coord).map(|&(g, _)| g == generation) == Some(true) => {
+                    pending.remove(&coord);
+                }
+                Ok(()) => (),
+                Err(e) => errors.lock().unwrap().push((coord, e)),
+            }
+        })
+    }
+
+    fn limits(&self) -> Limits {
+        self.limits.clone()
+    }
+}
+
+impl<T: Data> Component for RegionSource<T> {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Location of the region files.
+struct Region {
+    directory: PathBuf,
+    size: usize,
+}
+
+impl Region {
+    /// Returns the path to the region file of a chunk and the index of the chunk within that file.
+    fn locate(&self, coord: [i64; 3]) -> (PathBuf, u64) {
+        let size = self.size as i64;
+        let r = |i: usize| coord[i].div_euclid(size);
+        let l = |i: usize| coord[i].rem_euclid(size) as u64;
+        let path = self
+            .directory
+            .join(format!("r.{}.{}.{}.bin", r(0), r(1), r(2)));
+        let index = l(0) + l(1) * self.size as u64 + l(2) * (self.size * self.size) as u64;
+        (path, index)
+    }
+
+    fn table_size(&self) -> u64 {
+        (self.size * self.size * self.size) as u64 * ENTRY
+    }
+
+    /// Read the stored bytes of a chunk, or `None` if the chunk was never written.
+    fn read(&self, coord: [i64; 3]) -> Result<Option<Vec<u8>>> {
+        let (path, index) = self.locate(coord);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        self.check_header(&mut file)?;
+
+        file.seek(SeekFrom::Start(HEADER + index * ENTRY))?;
+        let offset = file.read_u64::<E>()?;
+        let len = file.read_u32::<E>()? as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let mut bytes = vec![0; len];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut bytes)?;
+        Ok(Some(bytes))
+    }
+
+    /// Write the bytes of a chunk to the first gap in the region file that is large enough.
+    /// Space that is no longer used by any chunk is reused before the file grows. The old location
+    /// of this chunk stays in use until the offset table points to the new location, so that an
+    /// interrupted write leaves the old bytes intact. Unused space at the end of the file is
+    /// truncated afterwards.
+    fn write(&self, coord: [i64; 3], bytes: &[u8]) -> Result<()> {
+        let (path, index) = self.locate(coord);
+        let mut file = self.open(&path)?;
+
+        let mut table = vec![0; self.table_size() as usize];
+        file.seek(SeekFrom::Start(HEADER))?;
+        file.read_exact(&mut table)?;
+        let entries = table
+            .chunks(ENTRY as usize)
+            .map(|entry| {
+                (
+                    E::read_u64(&entry[..8]),
+                    u64::from(E::read_u32(&entry[8..])),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut used = entries
+            .iter()
+            .cloned()
+            .filter(|&(_, len)| len > 0)
+            .collect::<Vec<_>>();
+        used.sort();
+
+        // first fit between the chunks that are in use
+        let len = bytes.len() as u64;
+        let mut offset = HEADER + self.table_size();
+        for &(start, used_len) in used.iter() {
+            if start >= offset + len {
+                break;
+            }
+            offset = offset.max(start + used_len);
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(bytes)?;
+
+        file.seek(SeekFrom::Start(HEADER + index * ENTRY))?;
+        file.write_u64::<E>(offset)?;
+        file.write_u32::<E>(bytes.len() as u32)?;
+
+        // the old location of this chunk is free now
+        let end = entries
+            .iter()
+            .enumerate()
+            .filter(|&(i, &(_, used_len))| i as u64 != index && used_len > 0)
+            .map(|(_, &(start, used_len))| start + used_len)
+            .max()
+            .unwrap_or(0)
+            .max(offset + len);
+        file.set_len(end)?;
+        file.flush()
+    }
+
+    /// Open a region file for writing, creating it if it doesn't exist.
+    fn open(&self, path: &Path) -> Result<File> {
+        create_dir_all(&self.directory)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+
+        if file.metadata()?.len() == 0 {
+            file.write_all(MAGIC)?;
+            file.write_u16::<E>(VERSION)?;
+            file.write_u16::<E>(self.size as u16)?;
+            file.set_len(HEADER + self.table_size())?;
+        } else {
+            self.check_header(&mut file)?;
+        }
+        Ok(file)
+    }
+
+    fn check_header(&self, file: &mut File) -> Result<()> {
+        let mut magic = [0; 4];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut magic)?;
+        let version = file.read_u16::<E>()?;
+        let size = file.read_u16::<E>()?;
+        if &magic != MAGIC || version != VERSION || size as usize != self.size {
+            Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "incompatible region file",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::AtlasMaterialHandle;
+    use crate::voxel::Voxel;
+    use std::fs::remove_dir_all;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "amethyst_voxel_region_{}_{}",
+            name,
+            std::process::id()
+        ));
+        remove_dir_all(&directory).ok();
+        directory
+    }
+
+    fn source(directory: &Path) -> RegionSource<()> {
+        RegionSource::new(directory, MaterialIds::default(), |_| {
+            NestedVoxel::new_empty(())
+        })
+        .with_region_size(2)
+    }
+
+    fn load(source: &mut RegionSource<()>, coord: [i64; 3]) -> Result<NestedVoxel<()>> {
+        match source.load_voxel(&mut (), coord) {
+            VoxelSourceResult::Ok(voxel) => Ok(voxel),
+            VoxelSourceResult::Loading(job) => job(&Default::default())
+                .map_err(|_| io::Error::new(ErrorKind::InvalidData, "load failed")),
+            _ => panic!("unexpected load result"),
+        }
+    }
+
+    #[test]
+    fn rewrites_reuse_space() {
+        let directory = directory("reuse");
+        let region = Region {
+            directory: directory.clone(),
+            size: 2,
+        };
+        let (path, _) = region.locate([0, 0, 0]);
+        let start = HEADER + region.table_size();
+
+        for i in 0..16 {
+            let (a, b) = if i % 2 == 0 { (100, 200) } else { (200, 100) };
+            region.write([0, 0, 0], &vec![1; a]).unwrap();
+            region.write([1, 0, 0], &vec![2; b]).unwrap();
+            // room for both chunks and the old location of the chunk that is rewritten
+            assert!(std::fs::metadata(&path).unwrap().len() <= start + 600);
+        }
+        assert_eq!(region.read([0, 0, 0]).unwrap(), Some(vec![1; 200]));
+        assert_eq!(region.read([1, 0, 0]).unwrap(), Some(vec![2; 100]));
+
+        // the chunk written last moves into the gap at the start, truncating the file
+        region.write([1, 0, 0], &[3; 10]).unwrap();
+        region.write([0, 0, 0], &[4; 10]).unwrap();
+        region.write([1, 0, 0], &[3; 10]).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), start + 20);
+        assert_eq!(region.read([0, 0, 0]).unwrap(), Some(vec![4; 10]));
+        assert_eq!(region.read([1, 0, 0]).unwrap(), Some(vec![3; 10]));
+        assert_eq!(region.read([0, 1, 0]).unwrap(), None);
+        remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rewrites_keep_old_location() {
+        let directory = directory("old_location");
+        let region = Region {
+            directory: directory.clone(),
+            size: 2,
+        };
+        let (path, _) = region.locate([0, 0, 0]);
+        let offset = || {
+            let mut file = File::open(&path).unwrap();
+            file.seek(SeekFrom::Start(HEADER)).unwrap();
+            file.read_u64::<E>().unwrap()
+        };
+
+        region.write([0, 0, 0], &[1; 100]).unwrap();
+        let old = offset();
+        region.write([0, 0, 0], &[2; 50]).unwrap();
+        assert!(offset() >= old + 100);
+        assert_eq!(region.read([0, 0, 0]).unwrap(), Some(vec![2; 50]));
+
+        // the old location is reused by the next write
+        region.write([0, 0, 0], &[3; 50]).unwrap();
+        assert_eq!(offset(), old);
+        remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn only_modified_chunks_are_written() {
+        let directory = directory("modified");
+        let mut source = source(&directory);
+        let chunk = NestedVoxel::new_filled((), AtlasMaterialHandle(0));
+        source.drop_voxel(&mut (), [0, 0, 0], chunk.clone(), false)();
+        assert!(!directory.exists());
+
+        source.drop_voxel(&mut (), [0, 0, 0], chunk.clone(), true)();
+        assert!(load(&mut source, [0, 0, 0]).unwrap() == chunk);
+        assert!(source.take_errors().is_empty());
+        remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn corrupted_chunks_fail_to_load() {
+        let directory = directory("corrupted");
+        let mut source = source(&directory);
+        let chunk = NestedVoxel::new_filled((), AtlasMaterialHandle(0));
+        source.drop_voxel(&mut (), [0, 0, 0], chunk, true)();
+
+        let (path, _) = source.region().locate([0, 0, 0]);
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(HEADER + source.region().table_size() + 6))
+            .unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+
+        assert!(load(&mut source, [0, 0, 0]).is_err());
+        assert!(load(&mut source, [1, 0, 0]).unwrap().is_empty());
+        remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,532 @@
+use nalgebra_glm::*;
+use serde_derive::*;
+
+/// The shape of a voxel that is filled with a single material, see `Voxel::shape`.
+/// Shapes other than `ShapeKind::Cube` can be placed in 24 orientations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Shape {
+    kind: ShapeKind,
+    orientation: u8,
+}
+
+/// The kinds of shapes a voxel can have. The kinds are described in their default orientation,
+/// with the top facing +y and the back facing +z.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ShapeKind {
+    /// A full cube.
+    Cube,
+    /// The bottom half of a cube.
+    Slab,
+    /// A slab with a half step on the back half.
+    Stairs,
+    /// A 45 degree slope rising towards the back.
+    Slope,
+    /// The outer corner between two slopes, rising towards the back and the +x side.
+    Corner,
+    /// The inner corner between two slopes, a cube without the corner at the top, front and -x side.
+    Wedge,
+}
+
+/// A convex face of a shape, in the unit cube of the voxel.
+pub(crate) struct Polygon {
+    /// The corners of the face, counter clockwise seen from the outside.
+    pub points: Vec<Vec3>,
+    pub normal: Vec3,
+    /// The side of the cube this face is triangulated with, see `Side::SIDE`.
+    pub side: usize,
+    /// Whether the face lies on the side of the cube, so that it can be hidden by a neighbour.
+    pub boundary: bool,
+    /// Whether the face covers the complete side of the cube.
+    pub full: bool,
+}
+
+/// The geometry of a shape in a specific orientation.
+struct Geometry {
+    polygons: Vec<Polygon>,
+    /// The shape is the union of these convex pieces, which are the unit cube clipped by a number
+    /// of planes. A point `p` is inside of a plane `(n, d)` if `dot(n, p) <= d`.
+    pieces: Vec<Vec<(Vec3, f32)>>,
+}
+
+const KINDS: [ShapeKind; 6] = [
+    ShapeKind::Cube,
+    ShapeKind::Slab,
+    ShapeKind::Stairs,
+    ShapeKind::Slope,
+    ShapeKind::Corner,
+    ShapeKind::Wedge,
+];
+
+lazy_static::lazy_static! {
+    static ref GEOMETRY: Vec<Geometry> = KINDS
+        .iter()
+        .flat_map(|&kind| (0..24).map(move |orientation| Geometry::new(kind, orientation)))
+        .collect();
+}
+
+impl Shape {
+    /// Create a new shape. `orientation / 4` selects the direction the top of the shape faces:
+    /// +y, -y, +x, -x, +z or -z. `orientation % 4` is the amount of quarter turns around that
+    /// direction. The orientation is wrapped to the range 0..24.
+    pub fn new(kind: ShapeKind, orientation: u8) -> Self {
+        let orientation = if kind == ShapeKind::Cube {
+            0
+        } else {
+            orientation % 24
+        };
+        Shape { kind, orientation }
+    }
+
+    /// The kind of this shape.
+    pub fn kind(&self) -> ShapeKind {
+        self.kind
+    }
+
+    /// The orientation of this shape, see `Shape::new`.
+    pub fn orientation(&self) -> u8 {
+        self.orientation
+    }
+
+    /// Returns whether this shape is a full cube.
+    pub fn is_cube(&self) -> bool {
+        self.kind == ShapeKind::Cube
+    }
+
+    /// Returns whether a side of the cube is completely covered by this shape.
+    /// The sides are numbered -x, +x, -y, +y, -z and +z, starting at 0.
+    pub fn is_full(&self, side: usize) -> bool {
+        self.is_cube()
+            || self
+                .polygons()
+                .iter()
+                .any(|p| p.boundary && p.side == side && p.full)
+    }
+
+    /// Cast a ray on this shape, in the unit cube of the voxel.
+    /// Returns the distance along `direction` to the nearest hit and the normal of the hit.
+    pub fn raycast(&self, origin: Vec3, direction: Vec3) -> Option<(f32, Vec3)> {
+        let cube = [
+            (vec3(1.0, 0.0, 0.0), 1.0),
+            (vec3(-1.0, 0.0, 0.0), 0.0),
+            (vec3(0.0, 1.0, 0.0), 1.0),
+            (vec3(0.0, -1.0, 0.0), 0.0),
+            (vec3(0.0, 0.0, 1.0), 1.0),
+            (vec3(0.0, 0.0, -1.0), 0.0),
+        ];
+
+        let mut nearest: Option<(f32, Vec3)> = None;
+        for piece in self.geometry().pieces.iter() {
+            let mut enter = (std::f32::NEG_INFINITY, vec3(0.0, 0.0, 0.0));
+            let mut exit = std::f32::INFINITY;
+            for &(normal, d) in cube.iter().chain(piece.iter()) {
+                let distance = d - dot(&normal, &origin);
+                let speed = dot(&normal, &direction);
+                if speed.abs() < std::f32::EPSILON {
+                    if distance < -1e-5 {
+                        exit = std::f32::NEG_INFINITY;
+                    }
+                } else if speed < 0.0 {
+                    let t = distance / speed;
+                    if t > enter.0 {
+                        enter = (t, normal);
+                    }
+                } else {
+                    exit = exit.min(distance / speed);
+                }
+            }
+
+            if enter.0 <= exit && exit >= 0.0 {
+                let hit = (enter.0.max(0.0), enter.1.normalize());
+                if nearest.map(|n| hit.0 < n.0).unwrap_or(true) {
+                    nearest = Some(hit);
+                }
+            }
+        }
+        nearest
+    }
+
+    /// Returns whether a face of another voxel with `points` is hidden by this shape, if this
+    /// shape is the neighbour on side `side` of that voxel. `points` are in the unit cube of this
+    /// shape, on the side `side`.
+    pub(crate) fn covers(&self, side: usize, points: &[Vec3]) -> bool {
+        self.is_cube()
+            || self.polygons().iter().any(|p| {
+                p.boundary
+                    && p.side == side
+                    && (p.full
+                        || (p.points.len() == points.len()
+                            && points
+                                .iter()
+                                .all(|a| p.points.iter().any(|b| distance(a, b) < 1e-4))))
+            })
+    }
+
+    /// The shape as a single byte, for the binary chunk format.
+    pub(crate) fn to_byte(self) -> u8 {
+        let kind = KINDS.iter().position(|&k| k == self.kind).unwrap();
+        kind as u8 * 24 + self.orientation
+    }
+
+    /// Read a shape written by `to_byte`. Returns `None` if the byte is not a valid shape.
+    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+        KINDS
+            .get(byte as usize / 24)
+            .map(|&kind| Shape::new(kind, byte % 24))
+    }
+
+    /// The faces of this shape.
+    pub(crate) fn polygons(&self) -> &'static [Polygon] {
+        &self.geometry().polygons
+    }
+
+    fn geometry(&self) -> &'static Geometry {
+        let kind = KINDS.iter().position(|&k| k == self.kind).unwrap();
+        &GEOMETRY[kind * 24 + self.orientation as usize % 24]
+    }
+}
+
+impl Default for Shape {
+    fn default() -> Self {
+        Shape::new(ShapeKind::Cube, 0)
+    }
+}
+
+impl Geometry {
+    fn new(kind: ShapeKind, orientation: u8) -> Self {
+        let h = 0.5;
+        let (x, y, z) = (
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+        );
+        // a face from a few corners, or an axis aligned rectangle from two opposite corners
+        let face = |points: &[[f32; 3]], normal: Vec3| {
+            let points = points.iter().map(|&p| Vec3::from(p)).collect::<Vec<_>>();
+            (points, normal)
+        };
+        let rect = |a: [f32; 3], b: [f32; 3], normal: Vec3| {
+            let (a, b) = (Vec3::from(a), Vec3::from(b));
+            let flat = (0..3).find(|&i| a[i] == b[i]).unwrap();
+            let (u, v) = ((flat + 1) % 3, (flat + 2) % 3);
+            let mut points = vec![a; 4];
+            points[1][u] = b[u];
+            points[2][u] = b[u];
+            points[2][v] = b[v];
+            points[3][v] = b[v];
+            (points, normal)
+        };
+        let bottom = rect([0.0, 0.0, 0.0], [1.0, 0.0, 1.0], -y);
+
+        // faces and pieces in the default orientation
+        let (faces, pieces) = match kind {
+            ShapeKind::Cube => (
+                vec![
+                    rect([0.0, 0.0, 0.0], [0.0, 1.0, 1.0], -x),
+                    rect([1.0, 0.0, 0.0], [1.0, 1.0, 1.0], x),
+                    bottom,
+                    rect([0.0, 1.0, 0.0], [1.0, 1.0, 1.0], y),
+                    rect([0.0, 0.0, 0.0], [1.0, 1.0, 0.0], -z),
+                    rect([0.0, 0.0, 1.0], [1.0, 1.0, 1.0], z),
+                ],
+                vec![vec![]],
+            ),
+            ShapeKind::Slab => (
+                vec![
+                    bottom,
+                    rect([0.0, h, 0.0], [1.0, h, 1.0], y),
+                    rect([0.0, 0.0, 0.0], [0.0, h, 1.0], -x),
+                    rect([1.0, 0.0, 0.0], [1.0, h, 1.0], x),
+                    rect([0.0, 0.0, 0.0], [1.0, h, 0.0], -z),
+                    rect([0.0, 0.0, 1.0], [1.0, h, 1.0], z),
+                ],
+                vec![vec![(y, h)]],
+            ),
+            ShapeKind::Stairs => (
+                vec![
+                    bottom,
+                    rect([0.0, 0.0, 1.0], [1.0, 1.0, 1.0], z),
+                    rect([0.0, 0.0, 0.0], [1.0, h, 0.0], -z),
+                    rect([0.0, h, 0.0], [1.0, h, h], y),
+                    rect([0.0, h, h], [1.0, 1.0, h], -z),
+                    rect([0.0, 1.0, h], [1.0, 1.0, 1.0], y),
+                    rect([0.0, 0.0, 0.0], [0.0, h, 1.0], -x),
+                    rect([0.0, h, h], [0.0, 1.0, 1.0], -x),
+                    rect([1.0, 0.0, 0.0], [1.0, h, 1.0], x),
+                    rect([1.0, h, h], [1.0, 1.0, 1.0], x),
+                ],
+                vec![vec![(y, h)], vec![(-z, -h)]],
+            ),
+            ShapeKind::Slope => (
+                vec![
+                    bottom,
+                    rect([0.0, 0.0, 1.0], [1.0, 1.0, 1.0], z),
+                    face(&[[0.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 1.0]], -x),
+                    face(&[[1.0, 0.0, 0.0], [1.0, 0.0, 1.0], [1.0, 1.0, 1.0]], x),
+                    face(
+                        &[
+                            [0.0, 0.0, 0.0],
+                            [1.0, 0.0, 0.0],
+                            [0.0, 1.0, 1.0],
+                            [1.0, 1.0, 1.0],
+                        ],
+                        y - z,
+                    ),
+                ],
+                vec![vec![(y - z, 0.0)]],
+            ),
+            ShapeKind::Corner => (
+                vec![
+                    bottom,
+                    face(&[[1.0, 0.0, 0.0], [1.0, 0.0, 1.0], [1.0, 1.0, 1.0]], x),
+                    face(&[[0.0, 0.0, 1.0], [1.0, 0.0, 1.0], [1.0, 1.0, 1.0]], z),
+                    face(&[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 1.0]], y - z),
+                    face(&[[0.0, 0.0, 0.0], [0.0, 0.0, 1.0], [1.0, 1.0, 1.0]], y - x),
+                ],
+                vec![vec![(y - z, 0.0), (y - x, 0.0)]],
+            ),
+            ShapeKind::Wedge => (
+                vec![
+                    bottom,
+                    rect([1.0, 0.0, 0.0], [1.0, 1.0, 1.0], x),
+                    rect([0.0, 0.0, 1.0], [1.0, 1.0, 1.0], z),
+                    face(&[[1.0, 1.0, 0.0], [1.0, 1.0, 1.0], [0.0, 1.0, 1.0]], y),
+                    face(&[[0.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 1.0]], -x),
+                    face(&[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]], -z),
+                    face(
+                        &[[0.0, 0.0, 0.0], [0.0, 1.0, 1.0], [1.0, 1.0, 0.0]],
+                        y - x - z,
+                    ),
+                ],
+                vec![vec![(y - x - z, 0.0)]],
+            ),
+        };
+
+        let rotation = rotation(orientation);
+        let center = vec3(0.5, 0.5, 0.5);
+        let rotate = |point: &Vec3| rotation * (point - center) + center;
+
+        Geometry {
+            polygons: faces
+                .into_iter()
+                .map(|(points, normal): (Vec<Vec3>, Vec3)| {
+                    let points = points.iter().map(rotate).collect();
+                    polygon(points, rotation * normal.normalize())
+                })
+                .collect(),
+            pieces: pieces
+                .into_iter()
+                .map(|planes| {
+                    planes
+                        .into_iter()
+                        .map(|(normal, d)| {
+                            let rotated = rotation * normal;
+                            (rotated, d - dot(&normal, &center) + dot(&rotated, &center))
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The rotation matrix of an orientation, see `Shape::new`.
+fn rotation(orientation: u8) -> Mat3 {
+    // rotations that turn +y into +y, -y, +x, -x, +z and -z
+    let up = match orientation / 4 {
+        0 => Mat3::identity(),
+        1 => Mat3::new(1.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0, -1.0),
+        2 => Mat3::new(0.0, 1.0, 0.0, -1.0, 0.0, 0.0, 0.0, 0.0, 1.0),
+        3 => Mat3::new(0.0, -1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0),
+        4 => Mat3::new(1.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 1.0, 0.0),
+        _ => Mat3::new(1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, -1.0, 0.0),
+    };
+    let turn = Mat3::new(0.0, 0.0, 1.0, 0.0, 1.0, 0.0, -1.0, 0.0, 0.0);
+    let mut rotation = up;
+    for _ in 0..orientation % 4 {
+        rotation *= turn;
+    }
+    rotation
+}
+
+/// Create a face from the corners of a convex polygon, in any order.
+fn polygon(mut points: Vec<Vec3>, normal: Vec3) -> Polygon {
+    let center = points.iter().fold(vec3(0.0, 0.0, 0.0), |a, b| a + b) / points.len() as f32;
+    let u = (points[0] - center).normalize();
+    let v = normal.cross(&u);
+    let angle = |p: &Vec3| dot(&(p - center), &v).atan2(dot(&(p - center), &u));
+    points.sort_by(|a, b| {
+        angle(a)
+            .partial_cmp(&angle(b))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let axis = (0..3)
+        .max_by(|&a, &b| {
+            normal[a]
+                .abs()
+                .partial_cmp(&normal[b].abs())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .unwrap();
+    let positive = normal[axis] > 0.0;
+    let plane = if positive { 1.0 } else { 0.0 };
+    let boundary =
+        normal[axis].abs() > 0.99 && points.iter().all(|p| (p[axis] - plane).abs() < 1e-4);
+    let area = (1..points.len() - 1)
+        .map(|i| {
+            (points[i] - points[0])
+                .cross(&(points[i + 1] - points[0]))
+                .magnitude()
+                * 0.5
+        })
+        .sum::<f32>();
+
+    Polygon {
+        points,
+        normal,
+        side: axis * 2 + if positive { 1 } else { 0 },
+        boundary,
+        full: boundary && area > 0.999,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NORMALS: [[f32; 3]; 6] = [
+        [-1.0, 0.0, 0.0],
+        [1.0, 0.0, 0.0],
+        [0.0, -1.0, 0.0],
+        [0.0, 1.0, 0.0],
+        [0.0, 0.0, -1.0],
+        [0.0, 0.0, 1.0],
+    ];
+
+    fn side_of(normal: Vec3) -> usize {
+        (0..6)
+            .find(|&side| distance(&Vec3::from(NORMALS[side]), &normal) < 1e-4)
+            .unwrap()
+    }
+
+    fn full_sides(shape: Shape) -> Vec<usize> {
+        (0..6).filter(|&side| shape.is_full(side)).collect()
+    }
+
+    #[test]
+    fn rotations() {
+        let rotations = (0..24).map(rotation).collect::<Vec<_>>();
+        for (orientation, rotation) in rotations.iter().enumerate() {
+            assert!((rotation * rotation.transpose() - Mat3::identity()).norm() < 1e-4);
+            assert!((determinant(rotation) - 1.0).abs() < 1e-4);
+            // the top of the shape faces +y, -y, +x, -x, +z and -z in turn
+            let up = side_of(rotation * vec3(0.0, 1.0, 0.0));
+            assert_eq!(up, [3, 2, 1, 0, 5, 4][orientation / 4]);
+            assert!(rotations[..orientation]
+                .iter()
+                .all(|other| (other - rotation).norm() > 1e-4));
+        }
+    }
+
+    #[test]
+    fn is_full() {
+        let full = |kind| full_sides(Shape::new(kind, 0));
+        assert_eq!(full(ShapeKind::Cube), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(full(ShapeKind::Slab), vec![2]);
+        assert_eq!(full(ShapeKind::Stairs), vec![2, 5]);
+        assert_eq!(full(ShapeKind::Slope), vec![2, 5]);
+        assert_eq!(full(ShapeKind::Corner), vec![2]);
+        assert_eq!(full(ShapeKind::Wedge), vec![1, 2, 5]);
+        assert_eq!(full_sides(Shape::new(ShapeKind::Slab, 4)), vec![3]);
+        assert_eq!(
+            full_sides(Shape::new(ShapeKind::Cube, 7)),
+            vec![0, 1, 2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn occlusion_across_orientations() {
+        for &kind in KINDS.iter() {
+            let upright = full_sides(Shape::new(kind, 0));
+            for orientation in 0..24 {
+                let shape = Shape::new(kind, orientation);
+                let rotation = rotation(shape.orientation());
+                let mut expected = upright
+                    .iter()
+                    .map(|&side| side_of(rotation * Vec3::from(NORMALS[side])))
+                    .collect::<Vec<_>>();
+                expected.sort();
+                assert_eq!(full_sides(shape), expected, "{:?} {}", kind, orientation);
+
+                for polygon in shape.polygons().iter().filter(|p| p.boundary) {
+                    assert!(shape.covers(polygon.side, &polygon.points));
+                    assert_eq!(polygon.full, shape.is_full(polygon.side));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn covers() {
+        let slab = Shape::new(ShapeKind::Slab, 0);
+        let half = [
+            vec3(0.0, 0.0, 0.0),
+            vec3(0.0, 0.5, 0.0),
+            vec3(0.0, 0.5, 1.0),
+            vec3(0.0, 0.0, 1.0),
+        ];
+        let whole = [
+            vec3(0.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(0.0, 1.0, 1.0),
+            vec3(0.0, 0.0, 1.0),
+        ];
+        assert!(slab.covers(0, &half));
+        assert!(!slab.covers(0, &whole));
+        assert!(!slab.covers(3, &whole));
+        assert!(slab.covers(2, &[vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 1.0)]));
+        assert!(Shape::default().covers(0, &whole));
+
+        // the half side of a slab does not fit the half side of a slab standing on it's side
+        let standing = Shape::new(ShapeKind::Slab, 12);
+        assert!(!standing.covers(0, &half));
+    }
+
+    #[test]
+    fn raycast() {
+        let down = vec3(0.0, -1.0, 0.0);
+        let above = vec3(0.5, 2.0, 0.5);
+
+        let (t, normal) = Shape::default().raycast(above, down).unwrap();
+        assert!((t - 1.0).abs() < 1e-4);
+        assert!(distance(&normal, &vec3(0.0, 1.0, 0.0)) < 1e-4);
+
+        let (t, normal) = Shape::new(ShapeKind::Slab, 0).raycast(above, down).unwrap();
+        assert!((t - 1.5).abs() < 1e-4);
+        assert!(distance(&normal, &vec3(0.0, 1.0, 0.0)) < 1e-4);
+
+        let (t, _) = Shape::new(ShapeKind::Slab, 4).raycast(above, down).unwrap();
+        assert!((t - 1.0).abs() < 1e-4);
+
+        let (t, normal) = Shape::new(ShapeKind::Slope, 0)
+            .raycast(above, down)
+            .unwrap();
+        assert!((t - 1.5).abs() < 1e-4);
+        assert!(distance(&normal, &vec3(0.0, 1.0, -1.0).normalize()) < 1e-4);
+
+        // rays pass over the slab, and hit it's side below the top
+        let left = vec3(-1.0, 0.0, 0.0);
+        let slab = Shape::new(ShapeKind::Slab, 0);
+        assert!(slab.raycast(vec3(2.0, 0.75, 0.5), left).is_none());
+        let (t, normal) = slab.raycast(vec3(2.0, 0.25, 0.5), left).unwrap();
+        assert!((t - 1.0).abs() < 1e-4);
+        assert!(distance(&normal, &vec3(1.0, 0.0, 0.0)) < 1e-4);
+
+        // the upper step of the stairs is at the back
+        let stairs = Shape::new(ShapeKind::Stairs, 0);
+        let (t, _) = stairs.raycast(vec3(0.5, 2.0, 0.75), down).unwrap();
+        assert!((t - 1.0).abs() < 1e-4);
+        let (t, _) = stairs.raycast(vec3(0.5, 2.0, 0.25), down).unwrap();
+        assert!((t - 1.5).abs() < 1e-4);
+    }
+}
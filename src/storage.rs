@@ -0,0 +1,618 @@
+use serde::de::{Deserializer, Error};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+use std::iter::FromIterator;
+use std::mem::size_of;
+use std::ops::Index;
+
+/// Storage for the subvoxels of a detail voxel.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "C: Deserialize<'de> + PartialEq"))]
+pub enum DetailStorage<C> {
+    /// Every subvoxel is stored separately.
+    Plain(Vec<C>),
+
+    /// Subvoxels are stored as bit packed indices into a palette of unique subvoxels.
+    Palette(PaletteStorage<C>),
+}
+
+/// Palette compressed storage for subvoxels.
+/// Every unique subvoxel is stored once in the palette, the subvoxels themselves are bit packed
+/// indices into the palette. The palette grows and shrinks as subvoxels are changed.
+/// The storage is serialized as it's palette and the palette index of every subvoxel.
+#[derive(Clone)]
+pub struct PaletteStorage<C> {
+    /// The unique subvoxels. Entries with a zero reference count are free to be reused.
+    palette: Vec<C>,
+    /// The amount of subvoxels referencing each palette entry.
+    counts: Vec<u32>,
+    /// The amount of bits used for a single index. Always zero or a power of two.
+    bits: usize,
+    /// The bit packed indices.
+    words: Vec<u64>,
+    /// The amount of subvoxels.
+    len: usize,
+    /// The subvoxel that was last modified through `get_mut`. It's palette entry is merged with an
+    /// equal entry by the next modification.
+    pending: Option<usize>,
+    /// Compares palette entries.
+    eq: fn(&C, &C) -> bool,
+}
+
+/// The serialized form of a `PaletteStorage`.
+#[derive(Deserialize)]
+#[serde(rename = "PaletteStorage")]
+struct SerializedPalette<C> {
+    palette: Vec<C>,
+    indices: Vec<usize>,
+}
+
+impl<C> DetailStorage<C> {
+    /// Returns the amount of subvoxels in the storage.
+    pub fn len(&self) -> usize {
+        match *self {
+            DetailStorage::Plain(ref plain) => plain.len(),
+            DetailStorage::Palette(ref palette) => palette.len(),
+        }
+    }
+
+    /// Returns `true` if the storage contains no subvoxels.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether the subvoxels are stored in palette compressed form.
+    pub fn is_packed(&self) -> bool {
+        if let DetailStorage::Palette(_) = self {
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Retrieve a reference to the subvoxel at index.
+    pub fn get(&self, index: usize) -> Option<&C> {
+        match *self {
+            DetailStorage::Plain(ref plain) => plain.get(index),
+            DetailStorage::Palette(ref palette) => palette.get(index),
+        }
+    }
+
+    /// Iterate over all subvoxels in index order.
+    pub fn iter(&self) -> impl Iterator<Item = &C> {
+        (0..self.len()).map(move |i| &self[i])
+    }
+}
+
+impl<C: Clone> DetailStorage<C> {
+    /// Mutably retrieve the subvoxel at index, without changing the kind of storage.
+    /// See `PaletteStorage::get_mut` for the cost of modifying palette compressed storage.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut C> {
+        match *self {
+            DetailStorage::Plain(ref mut plain) => plain.get_mut(index),
+            DetailStorage::Palette(ref mut palette) => palette.get_mut(index),
+        }
+    }
+
+    /// Replace the subvoxel at index, without changing the kind of storage.
+    /// Returns `false` if the index is out of bounds.
+    pub fn set(&mut self, index: usize, value: C) -> bool {
+        match *self {
+            DetailStorage::Plain(ref mut plain) => {
+                plain.get_mut(index).map(|target| *target = value).is_some()
+            }
+            DetailStorage::Palette(ref mut palette) => palette.set(index, value),
+        }
+    }
+
+    /// Mutably visit every distinct subvoxel.
+    /// For palette compressed storage every palette entry is visited once.
+    pub fn for_each_mut<F: FnMut(&mut C)>(&mut self, mut f: F) {
+        match *self {
+            DetailStorage::Plain(ref mut plain) => plain.iter_mut().for_each(f),
+            DetailStorage::Palette(ref mut palette) => palette.palette_mut().for_each(|c| f(c)),
+        }
+    }
+
+    /// Convert the storage to plain storage.
+    pub fn unpack(&mut self) {
+        if let DetailStorage::Palette(ref palette) = *self {
+            *self = DetailStorage::Plain(palette.iter().cloned().collect());
+        }
+    }
+}
+
+impl<C: Clone + PartialEq> DetailStorage<C> {
+    /// Convert the storage to palette compressed storage. Palette compressed storage is rebuilt
+    /// if it contains duplicate palette entries, which are left behind by `for_each_mut` and the
+    /// last call to `get_mut`.
+    pub fn pack(&mut self) {
+        let packed = match *self {
+            DetailStorage::Plain(ref plain) => plain.iter().cloned().collect(),
+            DetailStorage::Palette(ref palette) if palette.has_duplicates() => {
+                palette.iter().cloned().collect()
+            }
+            DetailStorage::Palette(_) => return,
+        };
+        *self = DetailStorage::Palette(packed);
+    }
+
+    /// Returns whether `pack` would change the storage.
+    pub fn can_pack(&self) -> bool {
+        match *self {
+            DetailStorage::Plain(_) => true,
+            DetailStorage::Palette(ref palette) => palette.has_duplicates(),
+        }
+    }
+}
+
+impl<C> Index<usize> for DetailStorage<C> {
+    type Output = C;
+
+    fn index(&self, index: usize) -> &C {
+        match *self {
+            DetailStorage::Plain(ref plain) => &plain[index],
+            DetailStorage::Palette(ref palette) => &palette[index],
+        }
+    }
+}
+
+impl<C> FromIterator<C> for DetailStorage<C> {
+    fn from_iter<I: IntoIterator<Item = C>>(iter: I) -> Self {
+        DetailStorage::Plain(Vec::from_iter(iter))
+    }
+}
+
+impl<C: PartialEq> PartialEq for DetailStorage<C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl<C> PaletteStorage<C> {
+    /// Returns the amount of subvoxels in the storage.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the storage contains no subvoxels.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the amount of bits used per subvoxel.
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    /// Retrieve a reference to the subvoxel at index.
+    pub fn get(&self, index: usize) -> Option<&C> {
+        if index < self.len {
+            Some(&self.palette[self.entry(index)])
+        } else {
+            None
+        }
+    }
+
+    /// Iterate over all subvoxels in index order.
+    pub fn iter(&self) -> impl Iterator<Item = &C> {
+        (0..self.len).map(move |i| &self.palette[self.entry(i)])
+    }
+
+    /// Iterate over the unique subvoxels that are in use.
+    pub fn palette(&self) -> impl Iterator<Item = &C> {
+        self.palette
+            .iter()
+            .zip(self.counts.iter())
+            .filter(|(_, &count)| count > 0)
+            .map(|(entry, _)| entry)
+    }
+
+    /// Mutably iterate over the unique subvoxels that are in use.
+    /// Modifying an entry modifies every subvoxel that refers to it.
+    /// Entries that become equal are not merged until the palette is rebuilt.
+    pub fn palette_mut(&mut self) -> impl Iterator<Item = &mut C> {
+        self.palette
+            .iter_mut()
+            .zip(self.counts.iter())
+            .filter(|(_, &count)| count > 0)
+            .map(|(entry, _)| entry)
+    }
+
+    /// All palette entries, including the free ones.
+    pub(crate) fn entries(&self) -> &[C] {
+        &self.palette
+    }
+
+    /// The palette indices of all subvoxels in index order.
+    pub(crate) fn indices(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).map(move |i| self.entry(i))
+    }
+
+    /// The memory used by the palette indices and the usage counts of the entries, in bytes.
+    pub(crate) fn index_bytes(&self) -> usize {
+        self.words.len() * size_of::<u64>() + self.counts.len() * size_of::<usize>()
+    }
+
+    fn entry(&self, index: usize) -> usize {
+        read(&self.words, self.bits, index)
+    }
+
+    /// Insert a new palette entry, reusing a free entry if possible.
+    fn insert(&mut self, value: C) -> usize {
+        if let Some(free) = self.counts.iter().position(|&count| count == 0) {
+            self.palette[free] = value;
+            free
+        } else {
+            if self.palette.len() >= 1 << self.bits {
+                let bits = bits_for(self.palette.len() + 1);
+                self.resize(bits);
+            }
+            self.palette.push(value);
+            self.counts.push(0);
+            self.palette.len() - 1
+        }
+    }
+
+    fn resize(&mut self, bits: usize) {
+        let mut words = vec![0; words_for(bits, self.len)];
+        for i in 0..self.len {
+            write(&mut words, bits, i, self.entry(i));
+        }
+        self.bits = bits;
+        self.words = words;
+    }
+}
+
+impl<C: Clone> PaletteStorage<C> {
+    /// Mutably retrieve the subvoxel at index.
+    /// If other subvoxels share the palette entry of the subvoxel, the entry is copied first so
+    /// that only this subvoxel is modified. The modified entry is merged with an equal entry by the
+    /// next call to `get_mut` or `set`, so that the palette shrinks again.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut C> {
+        if index >= self.len {
+            return None;
+        }
+
+        self.merge_pending();
+        let mut entry = self.entry(index);
+        if self.counts[entry] > 1 {
+            let copy = self.insert(self.palette[entry].clone());
+            write(&mut self.words, self.bits, index, copy);
+            self.counts[entry] -= 1;
+            self.counts[copy] += 1;
+            entry = copy;
+        }
+        self.pending = Some(index);
+        Some(&mut self.palette[entry])
+    }
+
+    /// Replace the subvoxel at index. Returns `false` if the index is out of bounds.
+    pub fn set(&mut self, index: usize, value: C) -> bool {
+        if index >= self.len {
+            return false;
+        }
+
+        self.merge_pending();
+
+        let old = self.entry(index);
+        if (self.eq)(&self.palette[old], &value) {
+            return true;
+        }
+
+        let new = match self.find(&value) {
+            Some(new) => new,
+            None => self.insert(value),
+        };
+
+        write(&mut self.words, self.bits, index, new);
+        self.counts[new] += 1;
+        self.counts[old] -= 1;
+        if self.counts[old] == 0 {
+            self.shrink();
+        }
+
+        true
+    }
+
+    fn find(&self, value: &C) -> Option<usize> {
+        self.palette
+            .iter()
+            .zip(self.counts.iter())
+            .position(|(entry, &count)| count > 0 && (self.eq)(entry, value))
+    }
+
+    /// Merge the palette entry of the subvoxel last modified through `get_mut` with an equal entry.
+    fn merge_pending(&mut self) {
+        let index = match self.pending.take() {
+            Some(index) => index,
+            None => return,
+        };
+        let entry = self.entry(index);
+        if self.counts[entry] != 1 {
+            return;
+        }
+
+        let eq = self.eq;
+        let equal = (0..self.palette.len()).find(|&i| {
+            i != entry && self.counts[i] > 0 && eq(&self.palette[i], &self.palette[entry])
+        });
+        if let Some(equal) = equal {
+            write(&mut self.words, self.bits, index, equal);
+            self.counts[equal] += 1;
+            self.counts[entry] = 0;
+            self.shrink();
+        }
+    }
+
+    /// Shrink the palette when only a quarter of the available indices is in use.
+    fn shrink(&mut self) {
+        if self.bits > 0 {
+            let used = self.counts.iter().filter(|&&count| count > 0).count();
+            if used <= (1 << self.bits) / 4 {
+                self.repack();
+            }
+        }
+    }
+
+    /// Remove unused palette entries and use the smallest possible index size.
+    fn repack(&mut self) {
+        let mut remap = vec![0; self.palette.len()];
+        let mut palette = Vec::new();
+        let mut counts = Vec::new();
+        for (i, (entry, &count)) in self.palette.iter().zip(self.counts.iter()).enumerate() {
+            if count > 0 {
+                remap[i] = palette.len();
+                palette.push(entry.clone());
+                counts.push(count);
+            }
+        }
+
+        let bits = bits_for(palette.len());
+        let mut words = vec![0; words_for(bits, self.len)];
+        for i in 0..self.len {
+            write(&mut words, bits, i, remap[self.entry(i)]);
+        }
+
+        self.palette = palette;
+        self.counts = counts;
+        self.bits = bits;
+        self.words = words;
+    }
+}
+
+impl<C: PartialEq> PaletteStorage<C> {
+    /// Create palette storage from palette entries and the palette indices of all subvoxels.
+    /// Returns `None` if an index is out of bounds.
+    pub(crate) fn from_parts(palette: Vec<C>, indices: &[usize]) -> Option<Self> {
+        let mut counts = vec![0; palette.len()];
+        for &index in indices {
+            *counts.get_mut(index)? += 1;
+        }
+
+        let bits = bits_for(palette.len());
+        let mut words = vec![0; words_for(bits, indices.len())];
+        for (i, &index) in indices.iter().enumerate() {
+            write(&mut words, bits, i, index);
+        }
+
+        Some(PaletteStorage {
+            palette,
+            counts,
+            bits,
+            words,
+            len: indices.len(),
+            pending: None,
+            eq: C::eq,
+        })
+    }
+}
+
+impl<C: Clone + PartialEq> PaletteStorage<C> {
+    /// Returns whether multiple palette entries in use are equal.
+    fn has_duplicates(&self) -> bool {
+        self.palette()
+            .enumerate()
+            .any(|(i, a)| self.palette().skip(i + 1).any(|b| a == b))
+    }
+}
+
+impl<C> Index<usize> for PaletteStorage<C> {
+    type Output = C;
+
+    fn index(&self, index: usize) -> &C {
+        assert!(index < self.len, "subvoxel index out of bounds");
+        &self.palette[self.entry(index)]
+    }
+}
+
+impl<C: Clone + PartialEq> FromIterator<C> for PaletteStorage<C> {
+    fn from_iter<I: IntoIterator<Item = C>>(iter: I) -> Self {
+        let mut palette: Vec<C> = Vec::new();
+        let mut counts = Vec::new();
+        let indices = iter
+            .into_iter()
+            .map(
+                |value| match palette.iter().position(|entry| *entry == value) {
+                    Some(i) => {
+                        counts[i] += 1;
+                        i
+                    }
+                    None => {
+                        palette.push(value);
+                        counts.push(1);
+                        palette.len() - 1
+                    }
+                },
+            )
+            .collect::<Vec<usize>>();
+
+        let bits = bits_for(palette.len());
+        let mut words = vec![0; words_for(bits, indices.len())];
+        for (i, &index) in indices.iter().enumerate() {
+            write(&mut words, bits, i, index);
+        }
+
+        PaletteStorage {
+            palette,
+            counts,
+            bits,
+            words,
+            len: indices.len(),
+            pending: None,
+            eq: C::eq,
+        }
+    }
+}
+
+impl<C: Serialize> Serialize for PaletteStorage<C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("PaletteStorage", 2)?;
+        state.serialize_field("palette", &self.palette)?;
+        state.serialize_field("indices", &self.indices().collect::<Vec<_>>())?;
+        state.end()
+    }
+}
+
+impl<'de, C: Deserialize<'de> + PartialEq> Deserialize<'de> for PaletteStorage<C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let serialized = SerializedPalette::deserialize(deserializer)?;
+        PaletteStorage::from_parts(serialized.palette, &serialized.indices)
+            .ok_or_else(|| D::Error::custom("palette index out of range"))
+    }
+}
+
+/// The smallest supported index size that can address `entries` palette entries.
+fn bits_for(entries: usize) -> usize {
+    let mut bits = 0;
+    while (1 << bits) < entries {
+        bits = if bits == 0 { 1 } else { bits * 2 };
+    }
+    bits
+}
+
+fn words_for(bits: usize, len: usize) -> usize {
+    (bits * len + 63) / 64
+}
+
+fn read(words: &[u64], bits: usize, index: usize) -> usize {
+    if bits == 0 {
+        0
+    } else {
+        let bit = index * bits;
+        let mask = if bits == 64 { !0 } else { (1 << bits) - 1 };
+        ((words[bit / 64] >> (bit % 64)) & mask) as usize
+    }
+}
+
+fn write(words: &mut [u64], bits: usize, index: usize, value: usize) {
+    if bits > 0 {
+        let bit = index * bits;
+        let mask = if bits == 64 { !0 } else { (1 << bits) - 1 };
+        let word = &mut words[bit / 64];
+        *word = (*word & !(mask << (bit % 64))) | ((value as u64 & mask) << (bit % 64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Apply the same pseudo random writes to plain and palette storage.
+    fn compare(values: u32, writes: usize, mutable: bool) {
+        let mut plain = DetailStorage::Plain(vec![0u32; 4096]);
+        let mut packed = plain.clone();
+        packed.pack();
+
+        let mut state = 0x2545_f491u32;
+        for _ in 0..writes {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let index = (state >> 8) as usize % 4096;
+            let value = (state >> 20) % values;
+            if mutable {
+                *plain.get_mut(index).unwrap() = value;
+                *packed.get_mut(index).unwrap() = value;
+            } else {
+                assert!(plain.set(index, value));
+                assert!(packed.set(index, value));
+            }
+            assert_eq!(plain.get(index), packed.get(index));
+        }
+
+        // the subvoxel modified last may still hold a copy of an entry
+        match packed {
+            DetailStorage::Palette(ref palette) => {
+                assert!(palette.palette.len() <= values as usize + 1);
+                assert!(palette.bits() <= bits_for(values as usize + 1));
+            }
+            _ => panic!("storage is no longer packed"),
+        }
+        assert!(plain == packed);
+        assert_eq!(plain.get(4096), None);
+        assert_eq!(packed.get(4096), None);
+
+        packed.pack();
+        assert!(!packed.can_pack());
+        assert!(plain == packed);
+    }
+
+    #[test]
+    fn set_matches_plain() {
+        compare(3, 10_000, false);
+        compare(300, 10_000, false);
+    }
+
+    #[test]
+    fn get_mut_matches_plain() {
+        compare(3, 10_000, true);
+        compare(300, 10_000, true);
+    }
+
+    #[test]
+    fn palette_shrinks() {
+        let mut storage: PaletteStorage<u32> = (0..4096).map(|i| i % 16).collect();
+        assert_eq!(storage.bits(), 4);
+        for i in 0..4096 {
+            storage.set(i, i as u32 % 2);
+        }
+        assert!(storage.bits() < 4);
+        assert_eq!(storage.palette().count(), 2);
+        assert!((0..4096).all(|i| storage[i] == i as u32 % 2));
+    }
+
+    #[test]
+    fn get_mut_copies_shared_entries() {
+        let mut storage = DetailStorage::Palette((0..8).map(|_| 1u32).collect());
+        *storage.get_mut(3).unwrap() = 2;
+        *storage.get_mut(4).unwrap() = 1;
+        assert!(storage.is_packed());
+        assert_eq!(
+            storage.iter().cloned().collect::<Vec<_>>(),
+            [1, 1, 1, 2, 1, 1, 1, 1]
+        );
+        assert_eq!(storage.get_mut(8), None);
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let storage =
+            DetailStorage::Palette((0..8).map(|i| i % 3).collect::<PaletteStorage<u32>>());
+        let serialized = ron::ser::to_string(&storage).unwrap();
+        let deserialized: DetailStorage<u32> = ron::de::from_str(&serialized).unwrap();
+        assert!(deserialized.is_packed());
+        assert!(deserialized == storage);
+    }
+
+    #[test]
+    fn malformed_palette_is_rejected() {
+        for malformed in &[
+            "Palette((palette: [], indices: [0, 0]))",
+            "Palette((palette: [1, 2], indices: [0, 2]))",
+        ] {
+            assert!(ron::de::from_str::<DetailStorage<u32>>(malformed).is_err());
+        }
+        let storage: DetailStorage<u32> =
+            ron::de::from_str("Palette((palette: [1, 2], indices: [1, 0, 1]))").unwrap();
+        assert_eq!(storage.iter().cloned().collect::<Vec<_>>(), [2, 1, 2]);
+    }
+}
@@ -0,0 +1,376 @@
+use crate::material::{AtlasData, AtlasMaterialHandle};
+use crate::model::ModelData;
+use crate::terrain::hash;
+use crate::vox::load_vox;
+use crate::voxel::{ChildOf, Data, NestedVoxel, Voxel};
+use crate::world::{Limits, VoxelSource, VoxelSourceResult};
+
+use amethyst::ecs::prelude::*;
+use futures::Future;
+
+use std::collections::HashMap;
+use std::io::Result;
+use std::sync::Arc;
+
+/// A group of voxels that can be placed in a world by a `StructureSource`, like a tree or a ruin.
+/// Structures are defined in subvoxel units of the chunks they are placed in.
+pub struct Structure {
+    size: [usize; 3],
+    anchor: [i64; 3],
+    voxels: Vec<([usize; 3], AtlasMaterialHandle)>,
+}
+
+/// Places structures at deterministic positions on a grid of regions.
+pub struct StructureLayer {
+    structures: Vec<Arc<Structure>>,
+    spacing: usize,
+    attempts: usize,
+    chance: f32,
+    height: Arc<dyn Fn(i64, i64, u64) -> Option<i64> + Send + Sync>,
+}
+
+/// A `VoxelSource` that places structures in the chunks of another `VoxelSource`.
+///
+/// Structure positions are computed per region of every `StructureLayer`, solely from the seed and
+/// the region coordinate. Every chunk stamps the parts of all nearby structures that overlap it,
+/// so that structures can straddle chunk borders.
+///
+/// Chunks that are persisted, for example by a `RegionSource`, should not be stamped again when they
+/// are loaded. Use `StructureSource::stamp` in the generator of such a source instead.
+pub struct StructureSource<S> {
+    inner: S,
+    seed: u64,
+    layers: Arc<Vec<StructureLayer>>,
+}
+
+/// A structure positioned in the world.
+struct Placement {
+    structure: Arc<Structure>,
+    origin: [i64; 3],
+}
+
+impl Structure {
+    /// Create a new `Structure` from a list of filled voxels.
+    /// The anchor of the structure is set to the center of it's bottom.
+    pub fn new(size: [usize; 3], voxels: Vec<([usize; 3], AtlasMaterialHandle)>) -> Self {
+        Self {
+            size,
+            anchor: [(size[0] / 2) as i64, 0, (size[2] / 2) as i64],
+            voxels: voxels
+                .into_iter()
+                .filter(|(coord, _)| (0..3).all(|i| coord[i] < size[i]))
+                .collect(),
+        }
+    }
+
+    /// Create a new `Structure` from the first submodel of a model.
+    /// The materials of the model are added to the atlas with the id `"<name>/<palette index>"`,
+    /// so that chunks containing the structure can be saved and loaded by material id.
+    /// Structures created with the same name share their materials.
+    pub fn from_model(name: &str, model: &ModelData, atlas: &mut AtlasData) -> Self {
+        let submodel = &model.submodels[0];
+        let dimensions = submodel.dimensions;
+        let mut materials = HashMap::new();
+
+        let voxels = submodel
+            .voxels
+            .iter()
+            .map(|instance| {
+                // models are z up, structures are y up
+                let index = instance.index;
+                let x = index % dimensions[0];
+                let y = (index / (dimensions[0] * dimensions[1])) % dimensions[2];
+                let z = (index / dimensions[0]) % dimensions[1];
+                let material = *materials.entry(instance.material).or_insert_with(|| {
+                    atlas.create(
+                        format!("{}/{}", name, instance.material),
+                        model.materials[instance.material].clone(),
+                    )
+                });
+                ([x, y, z], material)
+            })
+            .collect();
+
+        Self::new([dimensions[0], dimensions[2], dimensions[1]], voxels)
+    }
+
+    /// Create a new `Structure` from the contents of a MagicaVoxel .vox file.
+    /// The materials of the model are added to the atlas, see `Structure::from_model`.
+    pub fn from_vox(name: &str, bytes: &[u8], atlas: &mut AtlasData) -> Result<Self> {
+        load_vox(bytes).map(|model| Self::from_model(name, &model, atlas))
+    }
+
+    /// Set the point of the structure that is placed at the position chosen by a `StructureLayer`.
+    pub fn with_anchor(mut self, anchor: [i64; 3]) -> Self {
+        self.anchor = anchor;
+        self
+    }
+
+    /// The size of this structure in voxels.
+    pub fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    /// The maximum distance between the placement position and any voxel of this structure.
+    fn reach(&self) -> i64 {
+        (0..3)
+            .map(|i| {
+                self.anchor[i]
+                    .abs()
+                    .max((self.size[i] as i64 - self.anchor[i]).abs())
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl StructureLayer {
+    /// Create a new `StructureLayer` that places one of `structures` in square regions of `spacing`
+    /// by `spacing` voxels along the x and z axes.
+    ///
+    /// `height` returns the y coordinate to place a structure at for the x and z coordinate of a
+    /// placement, or `None` to skip the placement. It also receives a deterministic random number,
+    /// which can be used to place structures like ore veins at varying depths.
+    pub fn new<F>(structures: Vec<Structure>, spacing: usize, height: F) -> Self
+    where
+        F: 'static + Fn(i64, i64, u64) -> Option<i64> + Send + Sync,
+    {
+        assert!(spacing > 0, "structure spacing must be at least 1");
+        Self {
+            structures: structures.into_iter().map(Arc::new).collect(),
+            spacing,
+            attempts: 1,
+            chance: 1.0,
+            height: Arc::new(height),
+        }
+    }
+
+    /// Set the amount of placements that are attempted per region, and the chance that an attempt
+    /// succeeds.
+    pub fn with_density(mut self, attempts: usize, chance: f32) -> Self {
+        self.attempts = attempts;
+        self.chance = chance;
+        self
+    }
+
+    /// Compute the structures of a single region.
+    fn place(&self, seed: u64, region: [i64; 2], placements: &mut Vec<Placement>) {
+        if self.structures.is_empty() {
+            return;
+        }
+
+        let spacing = self.spacing as i64;
+        for attempt in 0..self.attempts {
+            let random = hash(seed, region[0], attempt as i64, region[1]);
+            let roll = (random >> 40) as f32 / (1u64 << 24) as f32;
+            if roll >= self.chance {
+                continue;
+            }
+
+            let x = region[0] * spacing + (hash(random, 1, 0, 0) % self.spacing as u64) as i64;
+            let z = region[1] * spacing + (hash(random, 2, 0, 0) % self.spacing as u64) as i64;
+            let choice = hash(random, 3, 0, 0) % self.structures.len() as u64;
+            let structure = &self.structures[choice as usize];
+            if let Some(y) = (self.height)(x, z, hash(random, 4, 0, 0)) {
+                placements.push(Placement {
+                    structure: structure.clone(),
+                    origin: [
+                        x - structure.anchor[0],
+                        y - structure.anchor[1],
+                        z - structure.anchor[2],
+                    ],
+                });
+            }
+        }
+    }
+}
+
+impl<S> StructureSource<S> {
+    /// Create a new `StructureSource` that places structures in the chunks loaded from `inner`.
+    pub fn new(inner: S, seed: u64) -> Self {
+        Self {
+            inner,
+            seed,
+            layers: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Add a layer of structures. Layers are stamped in the order they are added.
+    pub fn with_layer(mut self, layer: StructureLayer) -> Self {
+        Arc::get_mut(&mut self.layers)
+            .expect("StructureSource layers changed after loading")
+            .push(layer);
+        self
+    }
+
+    /// Retrieve the wrapped `VoxelSource`.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Stamp the parts of all structures that overlap the chunk at `coord` into `chunk`.
+    pub fn stamp<T: Data>(&self, coord: [i64; 3], chunk: NestedVoxel<T>) -> NestedVoxel<T> {
+        stamp(self.seed, &self.layers, coord, chunk)
+    }
+}
+
+/// Stamp all structures of all layers that overlap the chunk at `coord`.
+fn stamp<T: Data>(
+    seed: u64,
+    layers: &[StructureLayer],
+    coord: [i64; 3],
+    mut chunk: NestedVoxel<T>,
+) -> NestedVoxel<T> {
+    let width = NestedVoxel::<T>::WIDTH as i64;
+    let min = [coord[0] * width, coord[1] * width, coord[2] * width];
+    let max = [min[0] + width - 1, min[1] + width - 1, min[2] + width - 1];
+
+    for (index, layer) in layers.iter().enumerate() {
+        let seed = hash(seed, index as i64, 0, 0);
+        let reach = layer
+            .structures
+            .iter()
+            .map(|s| s.reach())
+            .max()
+            .unwrap_or(0);
+        let spacing = layer.spacing as i64;
+        let from = [
+            (min[0] - reach).div_euclid(spacing),
+            (min[2] - reach).div_euclid(spacing),
+        ];
+        let to = [
+            (max[0] + reach).div_euclid(spacing),
+            (max[2] + reach).div_euclid(spacing),
+        ];
+
+        let mut placements = Vec::new();
+        for rz in from[1]..=to[1] {
+            for rx in from[0]..=to[0] {
+                layer.place(seed, [rx, rz], &mut placements);
+            }
+        }
+
+        for placement in placements {
+            for &(offset, material) in placement.structure.voxels.iter() {
+                let position = [
+                    placement.origin[0] + offset[0] as i64,
+                    placement.origin[1] + offset[1] as i64,
+                    placement.origin[2] + offset[2] as i64,
+                ];
+                if (0..3).all(|i| position[i] >= min[i] && position[i] <= max[i]) {
+                    let index = NestedVoxel::<T>::coord_to_index(
+                        (position[0] - min[0]) as usize,
+                        (position[1] - min[1]) as usize,
+                        (position[2] - min[2]) as usize,
+                    );
+                    if let Some(child) = chunk.get_mut_or_expand(index) {
+                        *child =
+                            ChildOf::<NestedVoxel<T>>::new_filled(Default::default(), material);
+                    }
+                }
+            }
+        }
+    }
+
+    chunk
+}
+
+impl<'s, T: Data, S: VoxelSource<'s, T>> VoxelSource<'s, T> for StructureSource<S> {
+    type SystemData = S::SystemData;
+
+    fn load_voxel(
+        &mut self,
+        system_data: &mut Self::SystemData,
+        coord: [i64; 3],
+    ) -> VoxelSourceResult<T> {
+        let seed = self.seed;
+        let layers = self.layers.clone();
+        match self.inner.load_voxel(system_data, coord) {
+            VoxelSourceResult::Ok(chunk) => VoxelSourceResult::Loading(Box::new(move |_| {
+                Ok(stamp(seed, &layers, coord, chunk))
+            })),
+            VoxelSourceResult::Loading(job) => VoxelSourceResult::Loading(Box::new(move |token| {
+                let chunk = job(token)?;
+                if token.is_cancelled() {
+                    Ok(chunk)
+                } else {
+                    Ok(stamp(seed, &layers, coord, chunk))
+                }
+            })),
+            VoxelSourceResult::Future(future) => VoxelSourceResult::Future(Box::new(
+                future.map(move |chunk| stamp(seed, &layers, coord, chunk)),
+            )),
+            VoxelSourceResult::Err(error) => VoxelSourceResult::Err(error),
+            VoxelSourceResult::Retry => VoxelSourceResult::Retry,
+        }
+    }
+
+    fn drop_voxel(
+        &mut self,
+        system_data: &mut Self::SystemData,
+        coord: [i64; 3],
+        voxel: NestedVoxel<T>,
+        modified: bool,
+    ) -> Box<dyn FnOnce() + Send> {
+        self.inner.drop_voxel(system_data, coord, voxel, modified)
+    }
+
+    fn abandon_voxel(
+        &mut self,
+        system_data: &mut Self::SystemData,
+        coord: [i64; 3],
+        voxel: Option<NestedVoxel<T>>,
+    ) -> Box<dyn FnOnce() + Send> {
+        self.inner.abandon_voxel(system_data, coord, voxel)
+    }
+
+    fn limits(&self) -> Limits {
+        self.inner.limits()
+    }
+}
+
+impl<S: Component> Component for StructureSource<S> {
+    type Storage = DenseVecStorage<Self>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{AtlasAccess, ColoredMaterial, VoxelMaterial};
+    use crate::model::{Instance, SubModelData};
+    use std::sync::Arc;
+
+    fn model() -> ModelData {
+        let materials: Vec<Arc<dyn VoxelMaterial>> = vec![
+            Arc::new(ColoredMaterial::default()),
+            Arc::new(ColoredMaterial::default()),
+        ];
+        let voxels = (0..4)
+            .map(|index| Instance {
+                index,
+                material: index % 2,
+                bone: 0,
+            })
+            .collect();
+        let submodel = SubModelData::new(voxels, [2, 2, 1]);
+        ModelData::new(materials.into(), vec![submodel], Vec::new())
+    }
+
+    #[test]
+    fn materials_have_stable_ids() {
+        let mut atlas = AtlasData::default();
+        let tree = Structure::from_model("tree", &model(), &mut atlas);
+        let first = atlas.get("tree/0").unwrap();
+        let second = atlas.get("tree/1").unwrap();
+        assert!(tree.voxels.iter().all(|&(_, m)| m == first || m == second));
+
+        // structures with the same name reuse the materials
+        let again = Structure::from_model("tree", &model(), &mut atlas);
+        assert!(again.voxels == tree.voxels);
+        assert_eq!(atlas.ids().iter().count(), 2);
+
+        Structure::from_model("rock", &model(), &mut atlas);
+        assert!(atlas.get("rock/0").is_some());
+        assert!(atlas.get("rock/0") != Some(first));
+    }
+}
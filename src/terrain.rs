@@ -0,0 +1,391 @@
+use crate::material::{AtlasAccess, AtlasMaterialHandle};
+use crate::storage::DetailStorage;
+use crate::voxel::{ChildOf, Data, NestedVoxel, Voxel};
+use crate::world::{Limits, VoxelSource, VoxelSourceResult};
+
+use amethyst::ecs::prelude::*;
+
+use std::iter::repeat;
+use std::sync::Arc;
+
+/// A `VoxelSource` that generates terrain from noise.
+///
+/// The terrain is a layered noise heightmap with caves carved out by 3d noise. The surface is
+/// covered by a top material, followed by a layer of filler material with stone below it.
+/// Terrain is generated on the subvoxels of chunks, all coordinates are in subvoxel units.
+/// Generation is fully determined by the seed, so that chunks can be regenerated instead of stored.
+pub struct TerrainSource<T: Data> {
+    terrain: Arc<Terrain<T>>,
+}
+
+/// The settings and cached voxels of a `TerrainSource`.
+struct Terrain<T: Data> {
+    seed: u64,
+    limits: Limits,
+    top: AtlasMaterialHandle,
+    filler: AtlasMaterialHandle,
+    stone: AtlasMaterialHandle,
+    filler_depth: i64,
+    base_height: f32,
+    amplitude: f32,
+    scale: f32,
+    octaves: usize,
+    cave_scale: f32,
+    cave_threshold: f32,
+    empty: NestedVoxel<T>,
+    solid: NestedVoxel<T>,
+}
+
+impl<T: Data> TerrainSource<T>
+where
+    T::Child: PartialEq,
+{
+    /// Create a new `TerrainSource` with the given seed.
+    /// The top, filler and stone materials are looked up by their ids in `atlas`.
+    /// Returns `None` if any of the materials doesn't exist.
+    pub fn new<A: AtlasAccess + ?Sized>(
+        seed: u64,
+        atlas: &A,
+        top: &str,
+        filler: &str,
+        stone: &str,
+    ) -> Option<Self> {
+        let top = atlas.get(top)?;
+        let filler = atlas.get(filler)?;
+        let stone = atlas.get(stone)?;
+        Some(TerrainSource {
+            terrain: Arc::new(Terrain {
+                seed,
+                limits: Limits {
+                    from: [None; 3],
+                    to: [None; 3],
+                },
+                top,
+                filler,
+                stone,
+                filler_depth: 3,
+                base_height: 0.0,
+                amplitude: 32.0,
+                scale: 128.0,
+                octaves: 4,
+                cave_scale: 24.0,
+                cave_threshold: 0.75,
+                empty: uniform(ChildOf::<NestedVoxel<T>>::new_empty(Default::default())),
+                solid: uniform(ChildOf::<NestedVoxel<T>>::new_filled(
+                    Default::default(),
+                    stone,
+                )),
+            }),
+        })
+    }
+
+    /// Set the average height of the surface and the maximum deviation from that height.
+    pub fn with_height(mut self, base_height: f32, amplitude: f32) -> Self {
+        let terrain = self.settings();
+        terrain.base_height = base_height;
+        terrain.amplitude = amplitude;
+        self
+    }
+
+    /// Set the horizontal size of the largest hills and the amount of noise octaves layered on top
+    /// of each other. Every octave has half the size and half the amplitude of the previous one.
+    pub fn with_scale(mut self, scale: f32, octaves: usize) -> Self {
+        let terrain = self.settings();
+        terrain.scale = scale;
+        terrain.octaves = octaves.max(1);
+        self
+    }
+
+    /// Set the size of caves and the noise threshold above which caves are carved out.
+    /// A threshold of 1 or higher disables caves.
+    pub fn with_caves(mut self, scale: f32, threshold: f32) -> Self {
+        let terrain = self.settings();
+        terrain.cave_scale = scale;
+        terrain.cave_threshold = threshold;
+        self
+    }
+
+    /// Set the depth of the filler layer below the top layer.
+    pub fn with_filler_depth(mut self, depth: usize) -> Self {
+        self.settings().filler_depth = depth as i64;
+        self
+    }
+
+    /// Set the limits in chunks of this source. Chunks outside of the limits are empty.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.settings().limits = limits;
+        self
+    }
+
+    /// Retrieve the height of the surface at subvoxel coordinate `x`, `z`.
+    pub fn height(&self, x: i64, z: i64) -> i64 {
+        self.terrain.height(x, z)
+    }
+
+    /// Generate the chunk at `coord`. Returns identical chunks for identical seeds and coordinates.
+    pub fn generate(&self, coord: [i64; 3]) -> NestedVoxel<T> {
+        self.terrain.generate(coord)
+    }
+
+    fn settings(&mut self) -> &mut Terrain<T> {
+        Arc::get_mut(&mut self.terrain).expect("TerrainSource settings changed after cloning")
+    }
+}
+
+impl<T: Data> Clone for TerrainSource<T> {
+    fn clone(&self) -> Self {
+        TerrainSource {
+            terrain: self.terrain.clone(),
+        }
+    }
+}
+
+impl<T: Data> Terrain<T>
+where
+    T::Child: PartialEq,
+{
+    fn height(&self, x: i64, z: i64) -> i64 {
+        let (x, z) = (x as f32, z as f32);
+        let mut noise = 0.0;
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut scale = self.scale;
+        for octave in 0..self.octaves {
+            let seed = self.seed.wrapping_add(octave as u64);
+            noise += value_noise_2d(seed, x / scale, z / scale) * amplitude;
+            total += amplitude;
+            amplitude *= 0.5;
+            scale *= 0.5;
+        }
+        (self.base_height + (noise / total * 2.0 - 1.0) * self.amplitude).floor() as i64
+    }
+
+    fn cave(&self, x: i64, y: i64, z: i64) -> bool {
+        let seed = self.seed ^ 0x6361_7665;
+        let s = self.cave_scale;
+        self.cave_threshold < 1.0
+            && value_noise_3d(seed, x as f32 / s, y as f32 / s, z as f32 / s) > self.cave_threshold
+    }
+
+    fn within_limits(&self, coord: [i64; 3]) -> bool {
+        (0..3).all(|i| {
+            self.limits.from[i]
+                .map(|from| coord[i] >= from)
+                .unwrap_or(true)
+                && self.limits.to[i].map(|to| coord[i] <= to).unwrap_or(true)
+        })
+    }
+
+    fn generate(&self, coord: [i64; 3]) -> NestedVoxel<T> {
+        if !self.within_limits(coord) {
+            return self.empty.clone();
+        }
+
+        let width = NestedVoxel::<T>::WIDTH as i64;
+        let origin = [coord[0] * width, coord[1] * width, coord[2] * width];
+        let heights = (0..width * width)
+            .map(|i| self.height(origin[0] + i % width, origin[2] + i / width))
+            .collect::<Vec<_>>();
+
+        let lowest = heights.iter().cloned().min().unwrap_or(0);
+        let highest = heights.iter().cloned().max().unwrap_or(0);
+        let caves = self.cave_threshold < 1.0;
+        if origin[1] > highest {
+            return self.empty.clone();
+        }
+        if origin[1] + width - 1 < lowest - self.filler_depth && !caves {
+            return self.solid.clone();
+        }
+
+        let voxels = (0..NestedVoxel::<T>::COUNT).map(|i| {
+            let (x, y, z) = NestedVoxel::<T>::index_to_coord(i);
+            let height = heights[x + z * width as usize];
+            let (x, y, z) = (
+                origin[0] + x as i64,
+                origin[1] + y as i64,
+                origin[2] + z as i64,
+            );
+            let material = if y > height || self.cave(x, y, z) {
+                None
+            } else if y == height {
+                Some(self.top)
+            } else if y >= height - self.filler_depth {
+                Some(self.filler)
+            } else {
+                Some(self.stone)
+            };
+            match material {
+                Some(material) => {
+                    ChildOf::<NestedVoxel<T>>::new_filled(Default::default(), material)
+                }
+                None => ChildOf::<NestedVoxel<T>>::new_empty(Default::default()),
+            }
+        });
+
+        NestedVoxel::from_iter_packed(Default::default(), voxels)
+    }
+}
+
+impl<'s, T: Data> VoxelSource<'s, T> for TerrainSource<T>
+where
+    T::Child: PartialEq,
+{
+    type SystemData = ();
+
+    fn load_voxel(&mut self, _: &mut (), coord: [i64; 3]) -> VoxelSourceResult<T> {
+        let terrain = self.terrain.clone();
+        VoxelSourceResult::Loading(Box::new(move |_| Ok(terrain.generate(coord))))
+    }
+
+    fn limits(&self) -> Limits {
+        self.terrain.limits.clone()
+    }
+}
+
+impl<T: Data> Component for TerrainSource<T> {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// A chunk of which all subvoxels are the same, sharing a single subvoxel array.
+fn uniform<T: Data>(child: T::Child) -> NestedVoxel<T>
+where
+    T::Child: PartialEq,
+{
+    NestedVoxel::Detail {
+        detail: Arc::new(DetailStorage::Palette(
+            repeat(child).take(NestedVoxel::<T>::COUNT).collect(),
+        )),
+        data: Default::default(),
+    }
+}
+
+/// Hash a lattice point to a value in the range [0, 1).
+fn lattice(seed: u64, x: i64, y: i64, z: i64) -> f32 {
+    (hash(seed, x, y, z) >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Hash a seed and a lattice point to a pseudo random number.
+pub(crate) fn hash(seed: u64, x: i64, y: i64, z: i64) -> u64 {
+    let mut h = seed
+        ^ (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        ^ (y as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f)
+        ^ (z as u64).wrapping_mul(0x1656_67b1_9e37_79f9);
+    // splitmix64 finalizer
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+fn smooth(t: f32) -> f32 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Smoothly interpolated value noise in the range [0, 1).
+fn value_noise_2d(seed: u64, x: f32, z: f32) -> f32 {
+    let (x0, z0) = (x.floor(), z.floor());
+    let (tx, tz) = (smooth(x - x0), smooth(z - z0));
+    let (x0, z0) = (x0 as i64, z0 as i64);
+    let v = |x, z| lattice(seed, x, 0, z);
+    lerp(
+        lerp(v(x0, z0), v(x0 + 1, z0), tx),
+        lerp(v(x0, z0 + 1), v(x0 + 1, z0 + 1), tx),
+        tz,
+    )
+}
+
+/// Smoothly interpolated value noise in the range [0, 1).
+fn value_noise_3d(seed: u64, x: f32, y: f32, z: f32) -> f32 {
+    let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+    let (tx, ty, tz) = (smooth(x - x0), smooth(y - y0), smooth(z - z0));
+    let (x0, y0, z0) = (x0 as i64, y0 as i64, z0 as i64);
+    let v = |x, y, z| lattice(seed, x, y, z);
+    let plane = |y| {
+        lerp(
+            lerp(v(x0, y, z0), v(x0 + 1, y, z0), tx),
+            lerp(v(x0, y, z0 + 1), v(x0 + 1, y, z0 + 1), tx),
+            tz,
+        )
+    };
+    lerp(plane(y0), plane(y0 + 1), ty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{AtlasData, ColoredMaterial, VoxelMaterial};
+    use crate::voxel::SimpleVoxel;
+
+    #[derive(Clone, Default, PartialEq)]
+    struct Chunk;
+
+    impl Data for Chunk {
+        const SUBDIV: usize = 3;
+        type Child = SimpleVoxel;
+    }
+
+    fn source(seed: u64) -> TerrainSource<Chunk> {
+        let mut atlas = AtlasData::default();
+        for id in &["grass", "dirt", "stone"] {
+            let material: Box<dyn VoxelMaterial> = Box::new(ColoredMaterial::default());
+            atlas.create(*id, material);
+        }
+        TerrainSource::new(seed, &atlas, "grass", "dirt", "stone")
+            .unwrap()
+            .with_height(4.0, 12.0)
+            .with_scale(32.0, 3)
+    }
+
+    fn coords() -> impl Iterator<Item = [i64; 3]> {
+        (-2..2).flat_map(|y| (-2..2).flat_map(move |z| (-2..2).map(move |x| [x, y, z])))
+    }
+
+    fn is_empty(chunk: &NestedVoxel<Chunk>) -> bool {
+        (0..NestedVoxel::<Chunk>::COUNT).all(|i| chunk.get(i).unwrap().is_empty())
+    }
+
+    #[test]
+    fn same_seed_same_terrain() {
+        let (a, b) = (source(7), source(7));
+        for coord in coords() {
+            assert!(a.generate(coord) == b.generate(coord));
+            assert!(a.generate(coord) == a.generate(coord));
+        }
+        assert!(coords().any(|coord| !is_empty(&a.generate(coord))));
+        assert!(coords().any(|coord| is_empty(&a.generate(coord))));
+    }
+
+    #[test]
+    fn different_seed_different_terrain() {
+        let (a, b) = (source(7), source(8));
+        assert!((-16..16).any(|x| a.height(x * 5, x * 3) != b.height(x * 5, x * 3)));
+        assert!(coords().any(|coord| a.generate(coord) != b.generate(coord)));
+    }
+
+    #[test]
+    fn empty_outside_limits() {
+        let limits = Limits {
+            from: [Some(-1), None, Some(-1)],
+            to: [Some(0), Some(0), Some(0)],
+        };
+        let limited = source(7).with_limits(limits);
+        let unlimited = source(7);
+        for coord in coords() {
+            let chunk = limited.generate(coord);
+            let x = coord[0] >= -1 && coord[0] <= 0;
+            let z = coord[2] >= -1 && coord[2] <= 0;
+            if x && coord[1] <= 0 && z {
+                assert!(chunk == unlimited.generate(coord));
+            } else {
+                assert!(is_empty(&chunk));
+            }
+        }
+        for &coord in &[[1, -1, 0], [0, -1, 1], [-2, -2, 0]] {
+            assert!(!is_empty(&unlimited.generate(coord)));
+            assert!(is_empty(&limited.generate(coord)));
+        }
+    }
+}
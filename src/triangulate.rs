@@ -1,7 +1,9 @@
 use crate::ambient_occlusion::*;
 use crate::context::Context;
 use crate::material::{AtlasAccess, AtlasMaterialHandle};
+use crate::mesh::MeshingMode;
 use crate::pass::Surface;
+use crate::shape::Shape;
 use crate::side::*;
 use crate::voxel::*;
 use amethyst::renderer::{
@@ -16,10 +18,14 @@ use amethyst::renderer::{
 };
 use nalgebra_glm::*;
 use std::iter::repeat;
+use std::mem::size_of_val;
+use std::ops::Index;
 
 /// Triangulated mesh data created from a single voxel definition.
 pub struct Triangulation {
     skinned: bool,
+    /// Whether coplanar faces are merged into larger quads.
+    greedy: bool,
     pos: Vec<Position>,
     nml: Vec<Normal>,
     tan: Vec<Tangent>,
@@ -28,17 +34,49 @@ pub struct Triangulation {
     ind: Vec<u32>,
 }
 
+/// Triangulated voxel geometry that doesn't depend on a renderer.
+/// Every vertex has an entry in each of the vertex attributes, except for the joints,
+/// which are empty for meshes that are not skinned.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VoxelMeshData {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub tangents: Vec<[f32; 4]>,
+    /// The texture coordinates in the atlas and the ambient occlusion factor.
+    /// On faces merged by `build_greedy` the coordinates run past the tile of the material.
+    pub tex_ao: Vec<[f32; 3]>,
+    /// The tile of the material in the atlas as x, y, width and height for every vertex.
+    /// Textures repeat once per voxel, wrap the coordinates to sample them:
+    /// `tile.xy + mod(tex_ao.xy - tile.xy, tile.zw)`. The tiles are not uploaded by `to_mesh`.
+    pub tiles: Vec<[f32; 4]>,
+    pub joint_ids: Vec<[u16; 4]>,
+    pub joint_weights: Vec<[f32; 4]>,
+    /// Three indices per triangle.
+    pub indices: Vec<u32>,
+}
+
 struct Texturing {
     material_id: u32,
     side: u8,
-    coord: u8,
+    /// The position in the texture of the material, from (0, 0) to (1, 1) for every voxel that the
+    /// face covers.
+    uv: [f32; 2],
     ao: f32,
 }
 
+/// A face of a filled voxel that can be merged with it's neighbours.
+#[derive(Clone, Copy, PartialEq)]
+struct Face {
+    material: AtlasMaterialHandle,
+    occlusion: [f32; 4],
+    skins: [[(u8, u8); 4]; 4],
+}
+
 impl Triangulation {
     pub fn new(skinned: bool) -> Self {
         Triangulation {
             skinned,
+            greedy: false,
             pos: Vec::new(),
             nml: Vec::new(),
             tan: Vec::new(),
@@ -48,8 +86,15 @@ impl Triangulation {
         }
     }
 
+    /// Merge coplanar neighbouring faces with the same material and ambient occlusion into larger
+    /// quads. The texture of the material repeats across the merged quads, see `VoxelMeshData::tiles`.
+    pub fn with_greedy(mut self) -> Self {
+        self.greedy = true;
+        self
+    }
+
     /// Create a new mesh
-    pub fn append<'a, T: Voxel, C: Context<T>>(
+    pub fn append<T: Voxel, C: Context<T>>(
         &mut self,
         root: &T,
         ao: &SharedVertexData,
@@ -65,6 +110,48 @@ impl Triangulation {
         root.triangulate::<Above, C>(self, ao, context, origin, scale);
         root.triangulate::<Back, C>(self, ao, context, origin, scale);
         root.triangulate::<Front, C>(self, ao, context, origin, scale);
+        self.transform(start, transform);
+    }
+
+    /// Append a smooth surface through the subvoxels of `root`, see `triangulate_smooth`.
+    pub fn append_smooth<T: Voxel, C: Context<T>>(
+        &mut self,
+        root: &T,
+        context: &C,
+        stride: usize,
+        transform: &Mat4x4,
+    ) {
+        let start = self.pos.len();
+        triangulate_smooth(self, root, context, stride);
+        self.transform(start, transform);
+    }
+
+    /// Append a detail voxel in cubes of `stride` subvoxels, see `triangulate_blocks`.
+    pub fn append_blocks<T: Data, C: Context<NestedVoxel<T>>>(
+        &mut self,
+        root: &NestedVoxel<T>,
+        ao: &SharedVertexData,
+        context: &C,
+        stride: usize,
+        transform: &Mat4x4,
+    ) {
+        let detail = match root.detail() {
+            Some(detail) => detail,
+            None => return self.append(root, ao, context, vec3(0.0, 0.0, 0.0), 1.0, transform),
+        };
+        let start = self.pos.len();
+        let origin = vec3(0.0, 0.0, 0.0);
+        triangulate_blocks::<Left, _, _, _>(self, ao, context, origin, 1.0, stride, detail);
+        triangulate_blocks::<Right, _, _, _>(self, ao, context, origin, 1.0, stride, detail);
+        triangulate_blocks::<Below, _, _, _>(self, ao, context, origin, 1.0, stride, detail);
+        triangulate_blocks::<Above, _, _, _>(self, ao, context, origin, 1.0, stride, detail);
+        triangulate_blocks::<Back, _, _, _>(self, ao, context, origin, 1.0, stride, detail);
+        triangulate_blocks::<Front, _, _, _>(self, ao, context, origin, 1.0, stride, detail);
+        self.transform(start, transform);
+    }
+
+    /// Transform the vertices appended since `start`.
+    fn transform(&mut self, start: usize, transform: &Mat4x4) {
         for i in start..self.pos.len() {
             let pos: [f32; 3] = self.pos[i].0.into();
             let nml: [f32; 3] = self.nml[i].0.into();
@@ -76,55 +163,167 @@ impl Triangulation {
         }
     }
 
-    /// Transform into a rendy Mesh
-    pub fn to_mesh<A, B>(self, atlas: &A, queue: QueueId, factory: &Factory<B>) -> Option<Mesh>
+    /// Resolve the texture coordinates of the triangulated faces in `atlas`.
+    pub fn to_data<A: AtlasAccess>(self, atlas: &A) -> VoxelMeshData {
+        let (tex_ao, tiles) = self
+            .tex
+            .iter()
+            .map(|texturing| {
+                let [u0, v0] = atlas.coord(texturing.material_id, texturing.side, 0);
+                let [u1, v1] = atlas.coord(texturing.material_id, texturing.side, 2);
+                let [u, v] = texturing.uv;
+                let tex_ao = [u0 + (u1 - u0) * u, v0 + (v1 - v0) * v, texturing.ao];
+                (tex_ao, [u0, v0, u1 - u0, v1 - v0])
+            })
+            .unzip();
+
+        VoxelMeshData {
+            positions: self.pos.into_iter().map(|p| p.0).collect(),
+            normals: self.nml.into_iter().map(|n| n.0).collect(),
+            tangents: self.tan.into_iter().map(|t| t.0).collect(),
+            tex_ao,
+            tiles,
+            joint_ids: self.jnt.iter().map(|j| j.joint_ids.0).collect(),
+            joint_weights: self.jnt.iter().map(|j| j.joint_weights.0).collect(),
+            indices: self.ind,
+        }
+    }
+}
+
+impl VoxelMeshData {
+    /// Triangulate a voxel, using `context` to look up its neighbours.
+    /// The voxel is triangulated in a unit cube, materials are looked up in `atlas`.
+    pub fn build<V, C, A>(voxel: &V, context: &C, atlas: &A, meshing: MeshingMode) -> Self
     where
+        V: Voxel,
+        C: Context<V>,
         A: AtlasAccess,
-        B: Backend,
     {
-        if !self.pos.is_empty() {
-            let tex = self
-                .tex
-                .into_iter()
-                .map(|texturing| {
-                    let [u, v] =
-                        atlas.coord(texturing.material_id, texturing.side, texturing.coord);
-                    Surface {
-                        tex_ao: [u, v, texturing.ao],
-                    }
-                })
-                .collect::<Vec<_>>();
+        let mut triangulation = Triangulation::new(false);
+        if meshing == MeshingMode::Smooth {
+            triangulation.append_smooth(voxel, context, 1, &identity());
+        } else {
+            let shared = SharedVertexData::build(voxel, context);
+            triangulation.append(
+                voxel,
+                &shared,
+                context,
+                vec3(0.0, 0.0, 0.0),
+                1.0,
+                &identity(),
+            );
+        }
+        triangulation.to_data(atlas)
+    }
 
-            let mut builder = MeshBuilder::new()
-                .with_indices(self.ind)
-                .with_vertices(self.pos)
-                .with_vertices(self.nml)
-                .with_vertices(self.tan)
-                .with_vertices(tex);
+    /// Triangulate a voxel in cubes, merging coplanar neighbouring faces with the same material and
+    /// ambient occlusion into larger quads. Textures repeat once per voxel across the merged quads,
+    /// so the texture coordinates have to be wrapped within `tiles` when sampling. The meshes of
+    /// `to_mesh` don't wrap them, greedy meshes are meant for custom renderers.
+    pub fn build_greedy<V, C, A>(voxel: &V, context: &C, atlas: &A) -> Self
+    where
+        V: Voxel,
+        C: Context<V>,
+        A: AtlasAccess,
+    {
+        let mut triangulation = Triangulation::new(false).with_greedy();
+        let shared = SharedVertexData::build(voxel, context);
+        triangulation.append(
+            voxel,
+            &shared,
+            context,
+            vec3(0.0, 0.0, 0.0),
+            1.0,
+            &identity(),
+        );
+        triangulation.to_data(atlas)
+    }
 
-            if self.skinned {
-                builder = builder.with_vertices(self.jnt);
-            }
+    /// Returns whether there are no triangles in this mesh.
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
 
-            Some(B::wrap_mesh(builder.build(queue, factory).unwrap()))
-        } else {
-            None
+    /// The size of the vertex and index data in bytes, as uploaded by `to_mesh`.
+    pub(crate) fn bytes(&self) -> usize {
+        size_of_val(&self.positions[..])
+            + size_of_val(&self.normals[..])
+            + size_of_val(&self.tangents[..])
+            + size_of_val(&self.tex_ao[..])
+            + size_of_val(&self.joint_ids[..])
+            + size_of_val(&self.joint_weights[..])
+            + size_of_val(&self.indices[..])
+    }
+
+    /// Upload the mesh to the gpu. Returns `None` if the mesh is empty.
+    pub fn to_mesh<B: Backend>(&self, queue: QueueId, factory: &Factory<B>) -> Option<Mesh> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut builder = MeshBuilder::new()
+            .with_indices(self.indices.clone())
+            .with_vertices(
+                self.positions
+                    .iter()
+                    .map(|&p| Position(p))
+                    .collect::<Vec<_>>(),
+            )
+            .with_vertices(self.normals.iter().map(|&n| Normal(n)).collect::<Vec<_>>())
+            .with_vertices(
+                self.tangents
+                    .iter()
+                    .map(|&t| Tangent(t))
+                    .collect::<Vec<_>>(),
+            )
+            .with_vertices(
+                self.tex_ao
+                    .iter()
+                    .map(|&tex_ao| Surface { tex_ao })
+                    .collect::<Vec<_>>(),
+            );
+
+        if !self.joint_ids.is_empty() {
+            let joints = self
+                .joint_ids
+                .iter()
+                .zip(self.joint_weights.iter())
+                .map(|(&ids, &weights)| JointCombined {
+                    joint_ids: JointIds(ids),
+                    joint_weights: JointWeights(weights),
+                })
+                .collect::<Vec<_>>();
+            builder = builder.with_vertices(joints);
         }
+
+        Some(B::wrap_mesh(builder.build(queue, factory).unwrap()))
     }
 }
 
-pub fn triangulate_detail<S, T, C>(
+pub fn triangulate_detail<S, T, C, D>(
     triangulation: &mut Triangulation,
     shared: &SharedVertexData,
     context: &C,
     origin: Vec3,
     scale: f32,
-    sub: &[ChildOf<T>],
+    sub: &D,
 ) where
     S: Side,
     T: Voxel,
     C: Context<T>,
+    D: Index<usize, Output = ChildOf<T>> + ?Sized,
 {
+    if triangulation.greedy {
+        return triangulate_greedy::<S, T, C, D>(
+            triangulation,
+            shared,
+            context,
+            origin,
+            scale,
+            sub,
+        );
+    }
+
     // the scale of a single sub-voxel
     let scale = scale * T::SCALE;
     // loop over all sub-voxels and check for visible faces
@@ -133,12 +332,22 @@ pub fn triangulate_detail<S, T, C>(
             let x = (i) & T::LAST;
             let y = (i >> <T::Data as Data>::SUBDIV) & T::LAST;
             let z = (i >> (<T::Data as Data>::SUBDIV * 2)) & T::LAST;
-            let j = (i as isize + S::offset::<T>()) as usize;
 
-            if sub[i].render()
-                || (S::accept::<T>(x, y, z) && sub[j].render())
-                || context.render(x as isize + S::DX, y as isize + S::DY, z as isize + S::DZ)
-            {
+            if triangulate_shaped::<S, T, C, D>(
+                triangulation,
+                shared,
+                context,
+                origin,
+                scale,
+                sub,
+                x,
+                y,
+                z,
+            ) {
+                continue;
+            }
+
+            if face_visible::<S, T, C, D>(context, sub, x, y, z) {
                 let shared = shared.sub(x, y, z);
                 let ctx = context.child(x as isize, y as isize, z as isize);
                 let src = vec3(
@@ -154,6 +363,497 @@ pub fn triangulate_detail<S, T, C>(
     }
 }
 
+/// Returns whether the face of the subvoxel at x, y, z on side `S` is not hidden by it's neighbour.
+fn face_visible<S, T, C, D>(context: &C, sub: &D, x: usize, y: usize, z: usize) -> bool
+where
+    S: Side,
+    T: Voxel,
+    C: Context<T>,
+    D: Index<usize, Output = ChildOf<T>> + ?Sized,
+{
+    occluder::<S, T, C, D>(context, sub, x, y, z)
+        .map(|shape| !shape.is_full(S::SIDE ^ 1))
+        .unwrap_or(true)
+}
+
+/// Returns the shape of the neighbour on side `S` of the subvoxel at x, y, z, if that neighbour
+/// can hide faces of the subvoxel.
+fn occluder<S, T, C, D>(context: &C, sub: &D, x: usize, y: usize, z: usize) -> Option<Shape>
+where
+    S: Side,
+    T: Voxel,
+    C: Context<T>,
+    D: Index<usize, Output = ChildOf<T>> + ?Sized,
+{
+    let i = T::coord_to_index(x, y, z);
+    let j = (i as isize + S::offset::<T>()) as usize;
+    let (nx, ny, nz) = (x as isize + S::DX, y as isize + S::DY, z as isize + S::DZ);
+    if sub[i].render() || (S::accept::<T>(x, y, z) && sub[j].render()) || context.render(nx, ny, nz)
+    {
+        None
+    } else if S::accept::<T>(x, y, z) {
+        Some(sub[j].shape())
+    } else {
+        Some(context.shape(nx, ny, nz))
+    }
+}
+
+/// Triangulate the faces on side `S` of the subvoxel at x, y, z if it has a shape other than a
+/// cube. Returns whether the subvoxel was triangulated.
+#[allow(clippy::too_many_arguments)]
+fn triangulate_shaped<S, T, C, D>(
+    triangulation: &mut Triangulation,
+    shared: &SharedVertexData,
+    context: &C,
+    origin: Vec3,
+    scale: f32,
+    sub: &D,
+    x: usize,
+    y: usize,
+    z: usize,
+) -> bool
+where
+    S: Side,
+    T: Voxel,
+    C: Context<T>,
+    D: Index<usize, Output = ChildOf<T>> + ?Sized,
+{
+    let voxel = &sub[T::coord_to_index(x, y, z)];
+    let shape = voxel.shape();
+    match voxel.material() {
+        Some(material) if !shape.is_cube() => {
+            let src = vec3(
+                origin.x + x as f32 * scale,
+                origin.y + y as f32 * scale,
+                origin.z + z as f32 * scale,
+            );
+            triangulate_shape::<S>(
+                triangulation,
+                &shared.sub(x, y, z),
+                src,
+                scale,
+                material,
+                shape,
+                occluder::<S, T, C, D>(context, sub, x, y, z),
+            );
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Triangulate the subvoxels of a detail voxel like `triangulate_detail`, but merge the faces of
+/// filled subvoxels into quads that are as large as possible.
+/// The faces are merged per slice along the axis of `S`.
+fn triangulate_greedy<S, T, C, D>(
+    triangulation: &mut Triangulation,
+    shared: &SharedVertexData,
+    context: &C,
+    origin: Vec3,
+    scale: f32,
+    sub: &D,
+) where
+    S: Side,
+    T: Voxel,
+    C: Context<T>,
+    D: Index<usize, Output = ChildOf<T>> + ?Sized,
+{
+    // the scale of a single sub-voxel
+    let scale = scale * T::SCALE;
+    let width = T::WIDTH;
+    let axis = if S::DX != 0 {
+        0
+    } else if S::DY != 0 {
+        1
+    } else {
+        2
+    };
+    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+    let mut mask = vec![None; width * width];
+
+    for k in 0..width {
+        // collect the mergeable faces of this slice, other faces are triangulated right away
+        for j in 0..width {
+            for i in 0..width {
+                let mut coord = [0; 3];
+                coord[axis] = k;
+                coord[u] = i;
+                coord[v] = j;
+                let [x, y, z] = coord;
+                let index = T::coord_to_index(x, y, z);
+                if !sub[index].visible()
+                    || triangulate_shaped::<S, T, C, D>(
+                        triangulation,
+                        shared,
+                        context,
+                        origin,
+                        scale,
+                        sub,
+                        x,
+                        y,
+                        z,
+                    )
+                    || !face_visible::<S, T, C, D>(context, sub, x, y, z)
+                {
+                    continue;
+                }
+
+                let shared = shared.sub(x, y, z);
+                let material = sub[index].material().filter(|_| !sub[index].is_detail());
+                match material {
+                    Some(material) => {
+                        let quad = shared.quad::<S>();
+                        mask[i + j * width] = Some(Face {
+                            material,
+                            occlusion: [
+                                quad[0].occlusion,
+                                quad[1].occlusion,
+                                quad[2].occlusion,
+                                quad[3].occlusion,
+                            ],
+                            skins: [quad[0].skins, quad[1].skins, quad[2].skins, quad[3].skins],
+                        });
+                    }
+                    None => {
+                        let ctx = context.child(x as isize, y as isize, z as isize);
+                        let src = vec3(
+                            origin.x + x as f32 * scale,
+                            origin.y + y as f32 * scale,
+                            origin.z + z as f32 * scale,
+                        );
+                        sub[index].triangulate::<S, _>(triangulation, &shared, &ctx, src, scale);
+                    }
+                }
+            }
+        }
+
+        // grow every face along u first and v second, clearing the faces that were merged
+        for j in 0..width {
+            let mut i = 0;
+            while i < width {
+                let face = match mask[i + j * width] {
+                    Some(face) => face,
+                    None => {
+                        i += 1;
+                        continue;
+                    }
+                };
+
+                let mut w = 1;
+                while i + w < width && mask[i + w + j * width] == Some(face) {
+                    w += 1;
+                }
+                let mut h = 1;
+                while j + h < width && (i..i + w).all(|a| mask[a + (j + h) * width] == Some(face)) {
+                    h += 1;
+                }
+                for b in j..j + h {
+                    for a in i..i + w {
+                        mask[a + b * width] = None;
+                    }
+                }
+
+                let mut start = [0.0; 3];
+                start[axis] = k as f32;
+                start[u] = i as f32;
+                start[v] = j as f32;
+                let mut size = [1.0; 3];
+                size[u] = w as f32;
+                size[v] = h as f32;
+                let vertex = |c: usize| SharedVertex {
+                    occlusion: face.occlusion[c],
+                    skins: face.skins[c],
+                };
+                let shared = [vertex(0), vertex(1), vertex(2), vertex(3)];
+                triangulate_quad::<S>(
+                    triangulation,
+                    &shared,
+                    origin + vec3(start[0], start[1], start[2]) * scale,
+                    vec3(size[0], size[1], size[2]) * scale,
+                    scale,
+                    face.material,
+                );
+
+                i += w;
+            }
+        }
+    }
+}
+
+/// Triangulate a smooth surface through the subvoxels of `root` using surface nets.
+/// The density of the subvoxels is sampled at the lowest corner of cubes of `stride` subvoxels,
+/// the surface passes through a density of 0.5. Samples outside of `root` are taken from `context`,
+/// so that the surfaces of neighbouring voxels connect seamlessly.
+/// Every quad is textured with the material of the solid sample it covers, like a face of a cube.
+pub fn triangulate_smooth<T: Voxel, C: Context<T>>(
+    triangulation: &mut Triangulation,
+    root: &T,
+    context: &C,
+    stride: usize,
+) {
+    const ISO: f32 = 0.5;
+    let stride = stride.max(1).min(T::WIDTH) as isize;
+    let n = T::WIDTH as isize / stride;
+    let w = (n + 2) as usize;
+    let index =
+        |p: [isize; 3]| (p[0] + 1) as usize + (p[1] + 1) as usize * w + (p[2] + 1) as usize * w * w;
+
+    // density, material and skin of the samples, including a border of samples from the context
+    let mut samples = Vec::with_capacity(w * w * w);
+    for z in -1..=n {
+        for y in -1..=n {
+            for x in -1..=n {
+                let (sx, sy, sz) = (x * stride, y * stride, z * stride);
+                let inside = [x, y, z].iter().all(|&c| c >= 0 && c < n);
+                samples.push(if inside {
+                    match root.get(T::coord_to_index(sx as usize, sy as usize, sz as usize)) {
+                        Some(child) => (child.density(), surface_material(child), child.skin()),
+                        None => (root.density(), root.material(), root.skin()),
+                    }
+                } else {
+                    (
+                        context.density(sx, sy, sz),
+                        context.material(sx, sy, sz),
+                        context.skin(sx, sy, sz),
+                    )
+                });
+            }
+        }
+    }
+    let solid = |i: usize| samples[i].0 > ISO;
+
+    // place a vertex in every cell that is crossed by the surface, at the average of the crossings
+    // of it's edges. The normal is the negated gradient of the density.
+    let mut vertices: Vec<Option<(Vec3, Vec3)>> = vec![None; w * w * w];
+    for z in -1..n {
+        for y in -1..n {
+            for x in -1..n {
+                let corner =
+                    |c: usize| vec3((c & 1) as f32, ((c >> 1) & 1) as f32, (c >> 2) as f32);
+                let mut d = [0.0; 8];
+                for (c, d) in d.iter_mut().enumerate() {
+                    let c = corner(c);
+                    *d = samples[index([x + c.x as isize, y + c.y as isize, z + c.z as isize])].0;
+                }
+
+                let mut sum = vec3(0.0, 0.0, 0.0);
+                let mut crossings = 0;
+                for a in 0..8 {
+                    for &bit in [1, 2, 4].iter() {
+                        let b = a | bit;
+                        if b != a && (d[a] > ISO) != (d[b] > ISO) {
+                            let t = (ISO - d[a]) / (d[b] - d[a]);
+                            sum += corner(a) + (corner(b) - corner(a)) * t;
+                            crossings += 1;
+                        }
+                    }
+                }
+                if crossings == 0 {
+                    continue;
+                }
+
+                let gradient = vec3(
+                    (d[1] + d[3] + d[5] + d[7]) - (d[0] + d[2] + d[4] + d[6]),
+                    (d[2] + d[3] + d[6] + d[7]) - (d[0] + d[1] + d[4] + d[5]),
+                    (d[4] + d[5] + d[6] + d[7]) - (d[0] + d[1] + d[2] + d[3]),
+                );
+                let position = vec3(x as f32, y as f32, z as f32) + sum / crossings as f32;
+                vertices[index([x, y, z])] = Some((position, -gradient));
+            }
+        }
+    }
+
+    // connect the vertices of the four cells around every edge that is crossed by the surface.
+    // edges are owned by the voxel that contains their lowest sample.
+    for a in 0..3 {
+        let (b, c) = ((a + 1) % 3, (a + 2) % 3);
+        let mut axis = vec3(0.0, 0.0, 0.0);
+        axis[a] = 1.0;
+        for z in 0..n {
+            for y in 0..n {
+                for x in 0..n {
+                    let p = [x, y, z];
+                    let mut q = p;
+                    q[a] += 1;
+                    let (from, to) = (index(p), index(q));
+                    if solid(from) == solid(to) {
+                        continue;
+                    }
+
+                    let (filled, positive) = if solid(from) {
+                        (from, true)
+                    } else {
+                        (to, false)
+                    };
+                    let (_, material, skin) = samples[filled];
+                    let material = match material {
+                        Some(material) => material,
+                        None => continue,
+                    };
+
+                    let cell = |db: isize, dc: isize| {
+                        let mut cell = p;
+                        cell[b] -= db;
+                        cell[c] -= dc;
+                        vertices[index(cell)]
+                    };
+                    // counter clockwise around `axis`, reversed if the surface faces the other way
+                    let mut quad = [cell(1, 1), cell(0, 1), cell(0, 0), cell(1, 0)];
+                    if !positive {
+                        quad.reverse();
+                    }
+                    if quad.iter().any(|v| v.is_none()) {
+                        continue;
+                    }
+
+                    let side = 2 * a as u8 + if positive { 1 } else { 0 };
+                    let normal = if positive { axis } else { -axis };
+                    triangulate_smooth_quad(triangulation, &quad, n, normal, side, material, skin);
+                }
+            }
+        }
+    }
+}
+
+/// Add a quad of the surface created by `triangulate_smooth`, with vertices in sample space.
+fn triangulate_smooth_quad(
+    triangulation: &mut Triangulation,
+    quad: &[Option<(Vec3, Vec3)>; 4],
+    samples: isize,
+    normal: Vec3,
+    side: u8,
+    material: AtlasMaterialHandle,
+    skin: Option<u8>,
+) {
+    let begin = triangulation.pos.len() as u32;
+    let vertex = |i: usize| quad[i].unwrap();
+    let edge = vertex(1).0 - vertex(0).0;
+
+    for i in 0..4 {
+        let (position, gradient) = vertex(i);
+        let vertex_normal = if gradient.magnitude() > 0.0 {
+            gradient.normalize()
+        } else {
+            normal
+        };
+        let tangent = edge - vertex_normal * dot(&vertex_normal, &edge);
+        let tangent = if tangent.magnitude() > 0.0 {
+            tangent.normalize()
+        } else {
+            vec3(normal.y, normal.z, normal.x)
+        };
+
+        // samples are taken at the lowest corner of a cube, but represent the whole cube
+        let position = (position + vec3(0.5, 0.5, 0.5)) / samples as f32;
+        triangulation.pos.push(Position(convert3(position)));
+        triangulation.nml.push(Normal(convert3(vertex_normal)));
+        triangulation.tan.push(Tangent(convert4(tangent)));
+        triangulation.tex.push(Texturing {
+            material_id: material.0,
+            side,
+            uv: [MAP_X[i], MAP_Y[i]],
+            ao: 1.0,
+        });
+        if triangulation.skinned {
+            triangulation.jnt.push(JointCombined {
+                joint_ids: JointIds([skin.unwrap_or(0) as u16, 0, 0, 0]),
+                joint_weights: JointWeights([1.0, 0.0, 0.0, 0.0]),
+            });
+        }
+    }
+
+    // split the quad along it's shortest diagonal
+    if distance(&vertex(0).0, &vertex(2).0) <= distance(&vertex(1).0, &vertex(3).0) {
+        triangulation.ind.extend_from_slice(&[
+            begin,
+            begin + 1,
+            begin + 2,
+            begin,
+            begin + 2,
+            begin + 3,
+        ]);
+    } else {
+        triangulation.ind.extend_from_slice(&[
+            begin,
+            begin + 1,
+            begin + 3,
+            begin + 1,
+            begin + 2,
+            begin + 3,
+        ]);
+    }
+}
+
+/// Triangulate the subvoxels of a detail voxel in cubes of `stride` subvoxels along every axis,
+/// like the output of `NestedVoxel::downsample`. Every cube is triangulated as a single voxel,
+/// using the subvoxel at it's lowest corner.
+/// Faces on the border are only culled if no subvoxel of the neighbouring cube renders.
+pub fn triangulate_blocks<S, T, C, D>(
+    triangulation: &mut Triangulation,
+    shared: &SharedVertexData,
+    context: &C,
+    origin: Vec3,
+    scale: f32,
+    stride: usize,
+    sub: &D,
+) where
+    S: Side,
+    T: Voxel,
+    C: Context<T>,
+    D: Index<usize, Output = ChildOf<T>> + ?Sized,
+{
+    // the scale of a single sub-voxel
+    let scale = scale * T::SCALE;
+    let width = T::WIDTH as isize;
+    let step = stride as isize;
+    for z in (0..T::WIDTH).step_by(stride) {
+        for y in (0..T::WIDTH).step_by(stride) {
+            for x in (0..T::WIDTH).step_by(stride) {
+                let i = T::coord_to_index(x, y, z);
+                if !sub[i].visible() {
+                    continue;
+                }
+
+                let n = [
+                    x as isize + S::DX * step,
+                    y as isize + S::DY * step,
+                    z as isize + S::DZ * step,
+                ];
+                let open = if sub[i].render() {
+                    true
+                } else if n.iter().all(|&n| n >= 0 && n < width) {
+                    sub[T::coord_to_index(n[0] as usize, n[1] as usize, n[2] as usize)].render()
+                } else {
+                    (0..step).any(|dz| {
+                        (0..step).any(|dy| {
+                            (0..step).any(|dx| context.render(n[0] + dx, n[1] + dy, n[2] + dz))
+                        })
+                    })
+                };
+
+                if open {
+                    let shared = shared.block(x, y, z, stride);
+                    let ctx = context.child(x as isize, y as isize, z as isize);
+                    let src = vec3(
+                        origin.x + x as f32 * scale,
+                        origin.y + y as f32 * scale,
+                        origin.z + z as f32 * scale,
+                    );
+
+                    // add the visible face of the whole cube
+                    sub[i].triangulate::<S, _>(
+                        triangulation,
+                        &shared,
+                        &ctx,
+                        src,
+                        scale * stride as f32,
+                    );
+                }
+            }
+        }
+    }
+}
+
 pub fn triangulate_face<S: Side>(
     triangulation: &mut Triangulation,
     shared: &SharedVertexData,
@@ -161,19 +861,42 @@ pub fn triangulate_face<S: Side>(
     scale: f32,
     material: AtlasMaterialHandle,
 ) {
-    let sc = scale * 0.5;
+    triangulate_quad::<S>(
+        triangulation,
+        &shared.quad::<S>(),
+        origin,
+        vec3(scale, scale, scale),
+        scale,
+        material,
+    );
+}
+
+/// Triangulate side `S` of a box starting at `origin` with the given size.
+/// The texture of `material` repeats every `scale` units along the face.
+fn triangulate_quad<S: Side>(
+    triangulation: &mut Triangulation,
+    shared: &[SharedVertex; 4],
+    origin: Vec3,
+    size: Vec3,
+    scale: f32,
+    material: AtlasMaterialHandle,
+) {
+    let transform = S::orientation();
+    let half = size * 0.5;
+    // the half size of the box in the space of the face
+    let extent = transform.transpose() * half;
+    let (sx, sy, sz) = (extent.x.abs(), extent.y.abs(), extent.z.abs());
+    let (repeat_x, repeat_y) = (2.0 * sx / scale, 2.0 * sy / scale);
     let quad = [
-        vec3(-sc, sc, sc),
-        vec3(sc, sc, sc),
-        vec3(sc, -sc, sc),
-        vec3(-sc, -sc, sc),
+        vec3(-sx, sy, sz),
+        vec3(sx, sy, sz),
+        vec3(sx, -sy, sz),
+        vec3(-sx, -sy, sz),
     ];
     let begin = triangulation.pos.len() as u32;
-    let transform = S::orientation();
-    let center = vec3(origin.x + sc, origin.y + sc, origin.z + sc);
+    let center = origin + half;
     let normal = transform * vec3(0.0, 0.0, 1.0);
     let tangent = transform * vec3(1.0, 0.0, 0.0);
-    let shared = shared.quad::<S>();
 
     triangulation.pos.extend(
         quad.iter()
@@ -190,7 +913,7 @@ pub fn triangulate_face<S: Side>(
         .extend(shared.iter().enumerate().map(|(i, shared)| Texturing {
             material_id: material.0,
             side: S::SIDE as u8,
-            coord: i as u8,
+            uv: [MAP_X[i] * repeat_x, MAP_Y[i] * repeat_y],
             ao: shared.occlusion,
         }));
 
@@ -234,6 +957,95 @@ pub fn triangulate_face<S: Side>(
     }
 }
 
+/// Triangulate the faces of a shaped voxel on side `S`, see `Shape`. Faces on the sides of the
+/// voxel are hidden if they are covered by `occluder`, the shape of the neighbour on side `S`.
+/// The texture and ambient occlusion of a face are projected from side `S` of the cube.
+pub fn triangulate_shape<S: Side>(
+    triangulation: &mut Triangulation,
+    shared: &SharedVertexData,
+    origin: Vec3,
+    scale: f32,
+    material: AtlasMaterialHandle,
+    shape: Shape,
+    occluder: Option<Shape>,
+) {
+    let transform = S::orientation();
+    let quad = shared.quad::<S>();
+    let direction = vec3(S::DX as f32, S::DY as f32, S::DZ as f32);
+
+    for polygon in shape.polygons().iter().filter(|p| p.side == S::SIDE) {
+        if polygon.boundary {
+            let covered = occluder.map(|occluder| {
+                let points: Vec<Vec3> = polygon.points.iter().map(|p| p - direction).collect();
+                occluder.covers(S::SIDE ^ 1, &points)
+            });
+            if covered.unwrap_or(false) {
+                continue;
+            }
+        }
+
+        let begin = triangulation.pos.len() as u32;
+        let tangent = transform * vec3(1.0, 0.0, 0.0);
+        let tangent = (tangent - polygon.normal * dot(&polygon.normal, &tangent)).normalize();
+
+        for point in polygon.points.iter() {
+            // project the point on the face of the cube
+            let face = transform.transpose() * (point - vec3(0.5, 0.5, 0.5));
+            let (u, v) = (face.x + 0.5, 0.5 - face.y);
+            let ao = quad[0].occlusion * (1.0 - u) * (1.0 - v)
+                + quad[1].occlusion * u * (1.0 - v)
+                + quad[2].occlusion * u * v
+                + quad[3].occlusion * (1.0 - u) * v;
+
+            triangulation
+                .pos
+                .push(Position(convert3(origin + point * scale)));
+            triangulation.nml.push(Normal(convert3(polygon.normal)));
+            triangulation.tan.push(Tangent(convert4(tangent)));
+            triangulation.tex.push(Texturing {
+                material_id: material.0,
+                side: S::SIDE as u8,
+                uv: [u, v],
+                ao,
+            });
+
+            if triangulation.skinned {
+                let corner = match (u.round() as u8, v.round() as u8) {
+                    (0, 0) => 0,
+                    (_, 0) => 1,
+                    (0, _) => 3,
+                    _ => 2,
+                };
+                let skins = quad[corner].skins;
+                triangulation.jnt.push(JointCombined {
+                    joint_ids: JointIds([
+                        skins[0].0 as u16,
+                        skins[1].0 as u16,
+                        skins[2].0 as u16,
+                        skins[3].0 as u16,
+                    ]),
+                    joint_weights: JointWeights([
+                        skins[0].1 as f32 / 255.0,
+                        skins[1].1 as f32 / 255.0,
+                        skins[2].1 as f32 / 255.0,
+                        skins[3].1 as f32 / 255.0,
+                    ]),
+                });
+            }
+        }
+
+        for i in 1..polygon.points.len() as u32 - 1 {
+            triangulation
+                .ind
+                .extend_from_slice(&[begin, begin + i, begin + i + 1]);
+        }
+    }
+}
+
+/// The texture coordinates of the corners of a quad.
+const MAP_X: [f32; 4] = [0.0, 1.0, 1.0, 0.0];
+const MAP_Y: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+
 #[inline]
 fn convert3(v: Vec3) -> [f32; 3] {
     [v[0], v[1], v[2]]
@@ -243,3 +1055,99 @@ fn convert3(v: Vec3) -> [f32; 3] {
 fn convert4(v: Vec3) -> [f32; 4] {
     [v[0], v[1], v[2], 1.0]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::VoxelContext;
+    use crate::material::{AtlasData, ColoredMaterial, VoxelMaterial};
+
+    #[derive(Clone, Default)]
+    struct Cube;
+
+    impl Data for Cube {
+        const SUBDIV: usize = 1;
+        type Child = SimpleVoxel;
+    }
+
+    #[test]
+    fn greedy_quads_repeat_textures() {
+        let mut atlas = AtlasData::default();
+        let stone = atlas.create(
+            "stone",
+            Box::new(ColoredMaterial::default()) as Box<dyn VoxelMaterial>,
+        );
+        let voxel = NestedVoxel::from_iter(Cube, repeat(SimpleVoxel::new_filled((), stone)));
+        let context = VoxelContext::new(&voxel);
+
+        let cubes = VoxelMeshData::build(&voxel, &context, &atlas, MeshingMode::Cubes);
+        let greedy = VoxelMeshData::build_greedy(&voxel, &context, &atlas);
+        assert_eq!(cubes.indices.len(), 24 * 6);
+        assert_eq!(greedy.indices.len(), 6 * 6);
+        assert_eq!(greedy.tiles.len(), greedy.positions.len());
+
+        let [u0, v0] = atlas.coord(stone.0, 0, 0);
+        let [u1, v1] = atlas.coord(stone.0, 0, 2);
+        // every face covers two voxels along both axes, so the texture repeats twice
+        for (tex, tile) in greedy.tex_ao.iter().zip(greedy.tiles.iter()) {
+            assert_eq!(*tile, [u0, v0, u1 - u0, v1 - v0]);
+            let repeats = [(tex[0] - tile[0]) / tile[2], (tex[1] - tile[1]) / tile[3]];
+            assert!(repeats
+                .iter()
+                .all(|&r| r.abs() < 1e-4 || (r - 2.0).abs() < 1e-4));
+        }
+        for (tex, tile) in cubes.tex_ao.iter().zip(cubes.tiles.iter()) {
+            assert!(tex[0] >= tile[0] && tex[0] <= tile[0] + tile[2]);
+            assert!(tex[1] >= tile[1] && tex[1] <= tile[1] + tile[3]);
+        }
+    }
+
+    #[test]
+    fn shaped_neighbours_occlude_by_full_sides() {
+        use crate::shape::ShapeKind;
+
+        let mut atlas = AtlasData::default();
+        let stone = atlas.create(
+            "stone",
+            Box::new(ColoredMaterial::default()) as Box<dyn VoxelMaterial>,
+        );
+        // the darkest corner of the top face of a cube, with `above` diagonally above it
+        let darkest = |above: SimpleVoxel| {
+            let voxel = NestedVoxel::from_iter(
+                Cube,
+                (0..8).map(|i| match i {
+                    0 => SimpleVoxel::new_filled((), stone),
+                    3 => above.clone(),
+                    _ => SimpleVoxel::new_empty(()),
+                }),
+            );
+            let mesh = VoxelMeshData::build(
+                &voxel,
+                &VoxelContext::new(&voxel),
+                &atlas,
+                MeshingMode::Cubes,
+            );
+            mesh.positions
+                .iter()
+                .zip(mesh.normals.iter().zip(mesh.tex_ao.iter()))
+                .filter(|(p, (n, _))| (p[1] - 0.5).abs() < 1e-4 && **n == [0.0, 1.0, 0.0])
+                .map(|(_, (_, tex_ao))| tex_ao[2])
+                .fold(1.0f32, f32::min)
+        };
+
+        let cube = darkest(SimpleVoxel::new_filled((), stone));
+        let slab = darkest(SimpleVoxel::new_shaped(
+            (),
+            stone,
+            Shape::new(ShapeKind::Slab, 0),
+        ));
+        let upside_down = darkest(SimpleVoxel::new_shaped(
+            (),
+            stone,
+            Shape::new(ShapeKind::Slab, 4),
+        ));
+        assert!(cube < 1.0);
+        assert_eq!(slab, cube);
+        assert_eq!(upside_down, 1.0);
+    }
+}
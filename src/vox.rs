@@ -24,7 +24,7 @@ impl Format<ModelData> for VoxFormat {
     }
 }
 
-fn load_vox<R>(mut reader: R) -> Result<ModelData>
+pub(crate) fn load_vox<R>(mut reader: R) -> Result<ModelData>
 where
     R: ReadBytesExt,
 {
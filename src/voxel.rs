@@ -1,13 +1,20 @@
-use std::iter::FromIterator;
+use std::cmp::Ordering;
+use std::io::{self, Read, Write};
+use std::iter::{repeat, FromIterator};
+use std::mem::replace;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 
 use nalgebra_glm::Vec3;
+use serde::de::{Deserializer, Error};
+use serde::{Deserialize, Serialize};
 
 use crate::ambient_occlusion::SharedVertexData;
 use crate::context::Context;
 use crate::material::AtlasMaterialHandle;
+use crate::shape::Shape;
 use crate::side::Side;
+use crate::storage::DetailStorage;
 use crate::triangulate::Triangulation;
 
 pub trait Voxel: 'static + Clone + Send + Sync {
@@ -41,12 +48,25 @@ pub trait Voxel: 'static + Clone + Send + Sync {
     /// Construct a new, filled voxel. The voxel will be filled with one single material.
     fn new_filled(data: Self::Data, material: AtlasMaterialHandle) -> Self;
 
+    /// Construct a new voxel filled with one single material in the given shape.
+    fn new_shaped(data: Self::Data, material: AtlasMaterialHandle, shape: Shape) -> Self {
+        let mut voxel = Self::new_filled(data, material);
+        voxel.set_shape(shape);
+        voxel
+    }
+
     /// Retrieve a reference to subvoxel at index.
     fn get(&self, index: usize) -> Option<&<Self::Data as Data>::Child>;
 
     /// Mutably retrieve subvoxel at index
     fn get_mut(&mut self, index: usize) -> Option<&mut <Self::Data as Data>::Child>;
 
+    /// Replace the subvoxel at index. Detail voxels keep their kind of storage, so that palette
+    /// compressed subvoxels stay compressed. Returns whether the subvoxel was replaced.
+    fn set(&mut self, index: usize, child: <Self::Data as Data>::Child) -> bool {
+        self.get_mut(index).map(|target| *target = child).is_some()
+    }
+
     /// Returns whether this voxel is visible, i.e. if it has geometry.
     fn visible(&self) -> bool;
 
@@ -59,6 +79,60 @@ pub trait Voxel: 'static + Clone + Send + Sync {
     /// Whether this voxel has subvoxels.
     fn is_detail(&self) -> bool;
 
+    /// Returns the material of this voxel if it is completely filled with a single material.
+    fn material(&self) -> Option<AtlasMaterialHandle> {
+        None
+    }
+
+    /// The density of this voxel for `MeshingMode::Smooth`, see `Data::density`.
+    /// By default a voxel is solid if it's neighbours are not rendered from inside of it.
+    fn density(&self) -> f32 {
+        if self.render() {
+            0.0
+        } else {
+            1.0
+        }
+    }
+
+    /// The shape of this voxel if it is filled with a single material. Faces of neighbouring
+    /// voxels are only hidden by the sides that the shape covers completely.
+    fn shape(&self) -> Shape {
+        Shape::default()
+    }
+
+    /// Set the shape of this voxel. Only voxels filled with a single material have a shape.
+    /// Returns whether the shape was set.
+    fn set_shape(&mut self, _shape: Shape) -> bool {
+        false
+    }
+
+    /// Returns whether this voxel is completely empty.
+    fn is_empty(&self) -> bool {
+        !self.visible() && !self.is_detail()
+    }
+
+    /// Collapse detail voxels of which all subvoxels are empty or filled with the same material.
+    /// Subvoxels are collapsed first, so that uniform detail is collapsed recursively.
+    /// Subvoxels with a shape other than a cube are never collapsed, see `Voxel::shape`.
+    /// The data of subvoxels is discarded, the data of this voxel is kept.
+    /// Returns whether this voxel was collapsed.
+    fn collapse(&mut self) -> bool {
+        false
+    }
+
+    /// Expand an empty or filled voxel into a detail voxel with subvoxels that look the same.
+    /// Voxels with a shape other than a cube can not be expanded.
+    /// The data of this voxel is kept. Returns whether this voxel is a detail voxel afterwards.
+    fn expand(&mut self) -> bool {
+        self.is_detail()
+    }
+
+    /// Replace the material `from` by `to` in this voxel and all of it's subvoxels.
+    /// Returns whether any voxel was changed.
+    fn replace_material(&mut self, _from: AtlasMaterialHandle, _to: AtlasMaterialHandle) -> bool {
+        false
+    }
+
     /// Triangulate the voxel on a specific side
     fn triangulate<'a, S: Side, C: Context<Self>>(
         &self,
@@ -92,13 +166,46 @@ pub trait Data: 'static + Default + Clone + Send + Sync {
     fn skin(&self) -> Option<u8> {
         None
     }
+
+    /// The density of this voxel for `MeshingMode::Smooth`, between 0 and 1. The smooth surface
+    /// passes through a density of 0.5. Returns `None` to use the occupancy of the voxel instead.
+    fn density(&self) -> Option<f32> {
+        None
+    }
+
+    /// Write the user data to the binary chunk format, see `binary::encode`.
+    fn write_payload(&self, _writer: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Read user data that was written by `write_payload` from the binary chunk format.
+    fn read_payload(_reader: &mut dyn Read) -> io::Result<Self> {
+        Ok(Self::default())
+    }
 }
 
 #[allow(type_alias_bounds)]
 pub type ChildOf<T: Voxel> = <T::Data as Data>::Child;
 
+/// The material of a voxel, or the first material found in it's subvoxels if it has detail.
+pub(crate) fn surface_material<V: Voxel>(voxel: &V) -> Option<AtlasMaterialHandle> {
+    voxel.material().or_else(|| {
+        if voxel.is_detail() {
+            (0..V::COUNT)
+                .filter_map(|i| voxel.get(i))
+                .find_map(surface_material)
+        } else {
+            None
+        }
+    })
+}
+
 /// A single voxel with nesting capability.
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T: Serialize, T::Child: Serialize",
+    deserialize = "T: Deserialize<'de>, T::Child: Deserialize<'de> + PartialEq"
+))]
 pub enum NestedVoxel<T: Data> {
     /// An empty voxel, air for example.
     Empty {
@@ -109,8 +216,9 @@ pub enum NestedVoxel<T: Data> {
     /// A detail voxel. This voxel contains a number of subvoxels determined by `T::subdivisions()`.
     Detail {
         /// A shared array of subvoxels. The array is shared so that templated detail voxels can be
-        /// represented cheaply.
-        detail: Arc<Vec<T::Child>>,
+        /// represented cheaply. The subvoxels can optionally be stored palette compressed.
+        #[serde(deserialize_with = "deserialize_detail::<_, T>")]
+        detail: Arc<DetailStorage<T::Child>>,
 
         /// User data for the voxel.
         data: T,
@@ -121,6 +229,10 @@ pub enum NestedVoxel<T: Data> {
         /// The material id
         material: AtlasMaterialHandle,
 
+        /// The shape of the voxel, a full cube by default.
+        #[serde(default, skip_serializing_if = "Shape::is_cube")]
+        shape: Shape,
+
         /// User data for the voxel.
         data: T,
     },
@@ -129,9 +241,29 @@ pub enum NestedVoxel<T: Data> {
     Placeholder,
 }
 
-#[derive(Clone)]
+/// Deserialize the subvoxels of a detail voxel, rejecting storage with the wrong amount of subvoxels.
+fn deserialize_detail<'de, D, T>(deserializer: D) -> Result<Arc<DetailStorage<T::Child>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Data,
+    T::Child: Deserialize<'de> + PartialEq,
+{
+    let detail = DetailStorage::deserialize(deserializer)?;
+    if detail.len() == NestedVoxel::<T>::COUNT {
+        Ok(Arc::new(detail))
+    } else {
+        Err(D::Error::invalid_length(
+            detail.len(),
+            &"the amount of subvoxels of the voxel",
+        ))
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct SimpleVoxel {
     material: Option<AtlasMaterialHandle>,
+    #[serde(default, skip_serializing_if = "Shape::is_cube")]
+    shape: Shape,
 }
 
 impl Data for () {
@@ -143,11 +275,17 @@ impl Voxel for SimpleVoxel {
     type Data = ();
 
     fn new_empty(_: ()) -> Self {
-        Self { material: None }
+        Self {
+            material: None,
+            shape: Shape::default(),
+        }
     }
 
     fn new_filled(_: (), material: AtlasMaterialHandle) -> Self {
-        Self { material: Some(material) }
+        Self {
+            material: Some(material),
+            shape: Shape::default(),
+        }
     }
 
     fn get(&self, _: usize) -> Option<&ChildOf<Self>> {
@@ -174,6 +312,36 @@ impl Voxel for SimpleVoxel {
         false
     }
 
+    fn material(&self) -> Option<AtlasMaterialHandle> {
+        self.material
+    }
+
+    fn shape(&self) -> Shape {
+        self.shape
+    }
+
+    fn set_shape(&mut self, shape: Shape) -> bool {
+        if self.material.is_some() {
+            self.shape = shape;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.material.is_none()
+    }
+
+    fn replace_material(&mut self, from: AtlasMaterialHandle, to: AtlasMaterialHandle) -> bool {
+        if self.material == Some(from) {
+            self.material = Some(to);
+            true
+        } else {
+            false
+        }
+    }
+
     fn triangulate<'a, S: Side, C: Context<Self>>(
         &self,
         mesh: &mut Triangulation,
@@ -184,7 +352,11 @@ impl Voxel for SimpleVoxel {
     ) {
         use crate::triangulate::*;
         if let Some(material) = self.material {
-            triangulate_face::<S>(mesh, ao, origin, scale, material);
+            if self.shape.is_cube() {
+                triangulate_face::<S>(mesh, ao, origin, scale, material);
+            } else {
+                triangulate_shape::<S>(mesh, ao, origin, scale, material, self.shape, None);
+            }
         }
     }
 }
@@ -197,7 +369,142 @@ impl<T: Data> NestedVoxel<T> {
     {
         Self::Detail {
             data,
-            detail: Arc::new(Vec::from_iter(iter.into_iter().take(Self::COUNT))),
+            detail: Arc::new(DetailStorage::from_iter(iter.into_iter().take(Self::COUNT))),
+        }
+    }
+
+    /// Retrieve the subvoxel storage of a detail voxel.
+    pub fn detail(&self) -> Option<&DetailStorage<T::Child>> {
+        match *self {
+            Self::Detail { ref detail, .. } => Some(detail),
+            _ => None,
+        }
+    }
+
+    /// Convert the subvoxels of a detail voxel to plain storage.
+    pub fn unpack(&mut self) {
+        if let Self::Detail { ref mut detail, .. } = *self {
+            if detail.is_packed() {
+                Arc::make_mut(detail).unpack();
+            }
+        }
+    }
+
+    /// Mutably retrieve subvoxel at index. If this voxel is empty or filled it is expanded into a
+    /// detail voxel first, so that a single subvoxel can be modified.
+    pub fn get_mut_or_expand(&mut self, index: usize) -> Option<&mut T::Child> {
+        self.expand();
+        self.get_mut(index)
+    }
+
+    /// Collapse the subvoxels of a detail voxel recursively, without collapsing this voxel itself.
+    /// Returns whether any subvoxel was collapsed.
+    pub fn collapse_detail(&mut self) -> bool {
+        match *self {
+            Self::Detail { ref mut detail, .. } => {
+                if detail.iter().any(|child| child.is_detail()) {
+                    let mut collapsed = false;
+                    Arc::make_mut(detail).for_each_mut(|child| collapsed |= child.collapse());
+                    collapsed
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Create a lower detail copy of a detail voxel, used to render distant chunks.
+    /// The subvoxels are grouped in cubes of `2^level` subvoxels along every axis, and every cube
+    /// is filled with the most common material in it, or emptied if it is mostly empty.
+    /// Nested detail counts towards the cube by the share of every material in it.
+    /// A level of 0 only collapses nested detail to it's dominant material.
+    /// The level is limited to `T::SUBDIV`. The data of the subvoxels is discarded.
+    pub fn downsample(&self, level: usize) -> Self {
+        let (detail, data) = match *self {
+            Self::Detail {
+                ref detail,
+                ref data,
+            } => (detail, data),
+            _ => return self.clone(),
+        };
+
+        let stride = 1 << level.min(T::SUBDIV);
+        let blocks = Self::WIDTH / stride;
+        let block = |x: usize| x / stride;
+        let mut weights = vec![Vec::new(); blocks * blocks * blocks];
+        for (index, child) in detail.iter().enumerate() {
+            let (x, y, z) = Self::index_to_coord(index);
+            let block = block(x) + block(y) * blocks + block(z) * blocks * blocks;
+            weigh(child, 1.0, &mut weights[block]);
+        }
+
+        let children = weights
+            .iter()
+            .map(|weights| {
+                let dominant = weights
+                    .iter()
+                    .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+                    .and_then(|&(material, _)| material);
+                match dominant {
+                    Some(material) => T::Child::new_filled(Default::default(), material),
+                    None => T::Child::new_empty(Default::default()),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Self::from_iter(
+            data.clone(),
+            (0..Self::COUNT).map(|index| {
+                let (x, y, z) = Self::index_to_coord(index);
+                children[block(x) + block(y) * blocks + block(z) * blocks * blocks].clone()
+            }),
+        )
+    }
+}
+
+/// Add the share of every material in `voxel` to `weights`, with `None` for empty space.
+fn weigh<V: Voxel>(voxel: &V, weight: f32, weights: &mut Vec<(Option<AtlasMaterialHandle>, f32)>) {
+    if voxel.is_detail() {
+        let weight = weight / V::COUNT as f32;
+        for child in (0..V::COUNT).filter_map(|index| voxel.get(index)) {
+            weigh(child, weight, weights);
+        }
+    } else {
+        let material = if voxel.is_empty() {
+            None
+        } else {
+            voxel.material()
+        };
+        match weights.iter_mut().find(|(m, _)| *m == material) {
+            Some((_, total)) => *total += weight,
+            None => weights.push((material, weight)),
+        }
+    }
+}
+
+impl<T: Data> NestedVoxel<T>
+where
+    T::Child: PartialEq,
+{
+    /// Construct a Voxel::Detail with palette compressed subvoxels from an iterator.
+    pub fn from_iter_packed<I>(data: T, iter: I) -> Self
+    where
+        I: IntoIterator<Item = T::Child>,
+    {
+        let mut voxel = Self::from_iter(data, iter);
+        voxel.pack();
+        voxel
+    }
+
+    /// Convert the subvoxels of a detail voxel to palette compressed storage.
+    /// Modifying the subvoxels through `get_mut` or `Voxel::set` keeps them compressed. Packing
+    /// the voxel again merges duplicate palette entries, see `DetailStorage::pack`.
+    pub fn pack(&mut self) {
+        if let Self::Detail { ref mut detail, .. } = *self {
+            if detail.can_pack() {
+                Arc::make_mut(detail).pack();
+            }
         }
     }
 }
@@ -210,7 +517,11 @@ impl<T: Data> Voxel for NestedVoxel<T> {
     }
 
     fn new_filled(data: Self::Data, material: AtlasMaterialHandle) -> Self {
-        Self::Material { data, material }
+        Self::Material {
+            data,
+            material,
+            shape: Shape::default(),
+        }
     }
 
     fn get(&self, index: usize) -> Option<&<T as Data>::Child> {
@@ -231,6 +542,13 @@ impl<T: Data> Voxel for NestedVoxel<T> {
         }
     }
 
+    fn set(&mut self, index: usize, child: T::Child) -> bool {
+        match *self {
+            Self::Detail { ref mut detail, .. } => Arc::make_mut(detail).set(index, child),
+            _ => false,
+        }
+    }
+
     fn visible(&self) -> bool {
         match *self {
             Self::Empty { .. } => false,
@@ -266,6 +584,121 @@ impl<T: Data> Voxel for NestedVoxel<T> {
         }
     }
 
+    fn material(&self) -> Option<AtlasMaterialHandle> {
+        if let Self::Material { material, .. } = *self {
+            Some(material)
+        } else {
+            None
+        }
+    }
+
+    fn density(&self) -> f32 {
+        let density = match *self {
+            Self::Empty { ref data }
+            | Self::Detail { ref data, .. }
+            | Self::Material { ref data, .. } => data.density(),
+            Self::Placeholder => None,
+        };
+        density.unwrap_or(if self.render() { 0.0 } else { 1.0 })
+    }
+
+    fn shape(&self) -> Shape {
+        match *self {
+            Self::Material { shape, .. } => shape,
+            _ => Shape::default(),
+        }
+    }
+
+    fn set_shape(&mut self, new: Shape) -> bool {
+        match *self {
+            Self::Material { ref mut shape, .. } => {
+                *shape = new;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match *self {
+            Self::Empty { .. } | Self::Placeholder => true,
+            _ => false,
+        }
+    }
+
+    fn collapse(&mut self) -> bool {
+        self.collapse_detail();
+
+        let collapsed = match *self {
+            Self::Detail {
+                ref detail,
+                ref mut data,
+            } => {
+                let mut children = detail.iter();
+                let first = children.next();
+                let filled =
+                    |child: &T::Child| child.material().filter(|_| child.shape().is_cube());
+                if let Some(material) = first.and_then(filled) {
+                    if children.all(|child| filled(child) == Some(material)) {
+                        Some(Self::Material {
+                            material,
+                            shape: Shape::default(),
+                            data: replace(data, T::default()),
+                        })
+                    } else {
+                        None
+                    }
+                } else if first.map(|first| first.is_empty()).unwrap_or(false)
+                    && children.all(|child| child.is_empty())
+                {
+                    Some(Self::Empty {
+                        data: replace(data, T::default()),
+                    })
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        collapsed.map(|collapsed| *self = collapsed).is_some()
+    }
+
+    fn expand(&mut self) -> bool {
+        let child = match *self {
+            Self::Empty { .. } => T::Child::new_empty(Default::default()),
+            Self::Material {
+                material, shape, ..
+            } if shape.is_cube() => T::Child::new_filled(Default::default(), material),
+            Self::Material { .. } => return false,
+            Self::Detail { .. } => return true,
+            Self::Placeholder => return false,
+        };
+
+        let data = replace(self.deref_mut(), T::default());
+        *self = Self::from_iter(data, repeat(child));
+        true
+    }
+
+    fn replace_material(&mut self, from: AtlasMaterialHandle, to: AtlasMaterialHandle) -> bool {
+        match *self {
+            Self::Material {
+                ref mut material, ..
+            } if *material == from => {
+                *material = to;
+                true
+            }
+            Self::Detail { ref mut detail, .. } => {
+                let mut replaced = false;
+                Arc::make_mut(detail).for_each_mut(|child| {
+                    replaced |= child.replace_material(from, to);
+                });
+                replaced
+            }
+            _ => false,
+        }
+    }
+
     fn triangulate<'a, S: Side, C: Context<Self>>(
         &self,
         mesh: &mut Triangulation,
@@ -278,17 +711,23 @@ impl<T: Data> Voxel for NestedVoxel<T> {
         match *self {
             Self::Empty { .. } => (),
 
-            Self::Detail { ref detail, .. } => triangulate_detail::<S, _, _>(
+            Self::Detail { ref detail, .. } => triangulate_detail::<S, _, _, _>(
                 mesh,
                 shared,
                 context,
                 origin,
                 scale,
-                detail.as_slice(),
+                detail.as_ref(),
             ),
 
-            Self::Material { material, .. } => {
-                triangulate_face::<S>(mesh, shared, origin, scale, material)
+            Self::Material {
+                material, shape, ..
+            } => {
+                if shape.is_cube() {
+                    triangulate_face::<S>(mesh, shared, origin, scale, material)
+                } else {
+                    triangulate_shape::<S>(mesh, shared, origin, scale, material, shape, None)
+                }
             }
 
             Self::Placeholder => (),
@@ -296,11 +735,47 @@ impl<T: Data> Voxel for NestedVoxel<T> {
     }
 }
 
+impl<T: Data + PartialEq> PartialEq for NestedVoxel<T>
+where
+    T::Child: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Empty { data: a }, Self::Empty { data: b }) => a == b,
+            (
+                Self::Detail {
+                    detail: a,
+                    data: data_a,
+                },
+                Self::Detail {
+                    detail: b,
+                    data: data_b,
+                },
+            ) => data_a == data_b && (Arc::ptr_eq(a, b) || a == b),
+            (
+                Self::Material {
+                    material: a,
+                    shape: shape_a,
+                    data: data_a,
+                },
+                Self::Material {
+                    material: b,
+                    shape: shape_b,
+                    data: data_b,
+                },
+            ) => a == b && shape_a == shape_b && data_a == data_b,
+            (Self::Placeholder, Self::Placeholder) => true,
+            _ => false,
+        }
+    }
+}
+
 impl<T: Data> From<AtlasMaterialHandle> for NestedVoxel<T> {
     fn from(material: AtlasMaterialHandle) -> Self {
         Self::Material {
             data: Default::default(),
             material,
+            shape: Shape::default(),
         }
     }
 }
@@ -336,3 +811,101 @@ impl<T: Data> DerefMut for NestedVoxel<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::ShapeKind;
+
+    #[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+    struct Inner;
+
+    impl Data for Inner {
+        const SUBDIV: usize = 1;
+        type Child = SimpleVoxel;
+    }
+
+    #[derive(Clone, Default, PartialEq)]
+    struct Parent;
+
+    impl Data for Parent {
+        const SUBDIV: usize = 1;
+        type Child = NestedVoxel<Inner>;
+    }
+
+    #[test]
+    fn collapse_uniform_detail() {
+        let material = AtlasMaterialHandle(1);
+        let child = NestedVoxel::<Inner>::new_filled(Inner, material);
+        let mut voxel = NestedVoxel::<Parent>::from_iter(Parent, repeat(child));
+        assert!(voxel.collapse());
+        assert!(voxel.material() == Some(material));
+
+        let empty = NestedVoxel::<Inner>::new_empty(Inner);
+        let mut voxel = NestedVoxel::<Parent>::from_iter(Parent, repeat(empty));
+        assert!(voxel.collapse());
+        assert!(voxel.is_empty());
+    }
+
+    #[test]
+    fn collapse_keeps_shapes() {
+        let slab = Shape::new(ShapeKind::Slab, 0);
+        let child = NestedVoxel::<Inner>::new_shaped(Inner, AtlasMaterialHandle(1), slab);
+        let mut voxel = NestedVoxel::<Parent>::from_iter(Parent, repeat(child));
+        assert!(!voxel.collapse());
+        assert!(voxel.is_detail());
+        assert!((0..8).all(|i| voxel.get(i).unwrap().shape().kind() == ShapeKind::Slab));
+
+        let child = SimpleVoxel::new_shaped((), AtlasMaterialHandle(1), slab);
+        let mut voxel = NestedVoxel::<Inner>::from_iter(Inner, repeat(child));
+        assert!(!voxel.collapse());
+        assert!((0..8).all(|i| voxel.get(i).unwrap().shape() == slab));
+    }
+
+    #[test]
+    fn shapes_need_material() {
+        let slab = Shape::new(ShapeKind::Slab, 0);
+        let mut voxel = NestedVoxel::<Inner>::new_empty(Inner);
+        assert!(!voxel.set_shape(slab));
+        assert!(voxel.shape().is_cube());
+
+        let mut voxel = NestedVoxel::<Inner>::new_filled(Inner, AtlasMaterialHandle(1));
+        assert!(voxel.set_shape(slab));
+        assert!(voxel != NestedVoxel::new_filled(Inner, AtlasMaterialHandle(1)));
+        assert!(!voxel.expand());
+        assert!(voxel.replace_material(AtlasMaterialHandle(1), AtlasMaterialHandle(2)));
+        assert!(voxel == NestedVoxel::new_shaped(Inner, AtlasMaterialHandle(2), slab));
+
+        assert!(!SimpleVoxel::new_empty(()).set_shape(slab));
+    }
+
+    #[test]
+    fn collapse_nested_detail() {
+        let material = AtlasMaterialHandle(2);
+        let filled = SimpleVoxel::new_filled((), material);
+        let child = NestedVoxel::<Inner>::from_iter(Inner, repeat(filled));
+        let mut voxel = NestedVoxel::<Parent>::from_iter(Parent, repeat(child));
+        assert!(voxel.collapse());
+        assert!(voxel.material() == Some(material));
+    }
+
+    #[test]
+    fn deserialize_checks_subvoxel_count() {
+        let voxel = NestedVoxel::<Inner>::from_iter(Inner, repeat(SimpleVoxel::new_empty(())));
+        let serialized = ron::ser::to_string(&voxel).unwrap();
+        assert!(ron::de::from_str::<NestedVoxel<Inner>>(&serialized).unwrap() == voxel);
+
+        let empty = "(material: None)";
+        for &count in &[0, 7, 8, 9] {
+            let detail = vec![empty; count].join(", ");
+            let plain = format!("Detail(detail: Plain([{}]), data: ())", detail);
+            let deserialized = ron::de::from_str::<NestedVoxel<Inner>>(&plain);
+            assert_eq!(deserialized.is_ok(), count == 8);
+        }
+        let palette = format!(
+            "Detail(detail: Palette((palette: [{}], indices: [0])), data: ())",
+            empty
+        );
+        assert!(ron::de::from_str::<NestedVoxel<Inner>>(&palette).is_err());
+    }
+}
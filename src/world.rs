@@ -1,39 +1,162 @@
+use crate::cache::{CacheStats, CachedChunk, ChunkCache};
 use crate::material::Atlas;
 use crate::mesh::*;
+use crate::origin::FloatingOrigin;
 use crate::voxel::*;
 
 use amethyst::{
-    assets::Handle,
+    assets::{AssetStorage, Handle},
     core::{
         ecs::storage::{GenericReadStorage, GenericWriteStorage},
-        math::Vector3,
-        transform::Transform,
+        transform::{Parent, Transform},
+        Time,
     },
     ecs::prelude::*,
+    error::Error,
     renderer::{ActiveCamera, Camera},
+    shrev::EventChannel,
 };
 use crossbeam::atomic::AtomicCell;
+use futures::executor::{spawn, Notify, NotifyHandle, Spawn};
+use futures::{Async, Future};
 use nalgebra_glm::*;
 use rayon::ThreadPool;
 
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
 use std::mem::replace;
 use std::ops::{Deref, DerefMut};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 /// A dynamically loaded infinite world component.
 /// Voxel data is pulled from a VoxelSource component on the same entity.
-/// The voxel world has a rendering range around the viewpoint of the current camera, which is automatically updated.
+/// Chunks are loaded around every entity with a `VoxelViewer`. When there are no viewers,
+/// the chunks around the viewpoint of the current camera are loaded instead.
+///
+/// If the entity has a `Transform`, the world moves along with it. Chunk entities are children of
+/// the world entity, and loading and raycasting take the transform of the world into account.
 pub struct VoxelWorld<T: Data> {
-    loaded: bool,
     limits: Limits,
-    visibility: [f32; 6],
-    view_range: f32,
     atlas: Handle<Atlas>,
+    /// Slots of the chunks that are loaded or loading. Unused slots are `Chunk::NotNeeded`.
     pub(crate) data: Vec<Chunk<T>>,
+    /// Maps absolute chunk coordinates to slots in `data`.
+    lookup: HashMap<[i64; 3], usize>,
+    free: Vec<usize>,
     pub(crate) dims: [usize; 3],
-    pub(crate) origin: [isize; 3],
     pub(crate) scale: f32,
+    /// The global matrix of the world entity, as of the last frame.
+    pub(crate) transform: Mat4,
+    /// The absolute position of the origin of the render space when using a `FloatingOrigin`.
+    pub(crate) offset: [f64; 3],
+    max_loads: usize,
+    max_inserts: usize,
+    view_bias: f32,
+    hysteresis: usize,
+    /// Distances in chunks at which the level of detail of chunks is lowered.
+    lod: Vec<usize>,
+    meshing: MeshingMode,
+    /// The shape loaded around the camera, instead of `dims`.
+    shape: Option<ViewShape>,
+    /// The regions in view as of the last frame.
+    regions: Vec<Region>,
+    stats: QueueStats,
+    cache: ChunkCache<T>,
+    /// Requests of chunks that are no longer needed, waiting for their job to finish.
+    abandoned: Vec<([i64; 3], Arc<Request<T>>)>,
+    retry: RetryPolicy,
+    /// Failed attempts of the chunks that are being retried.
+    attempts: HashMap<[i64; 3], usize>,
+}
+
+/// Statistics on the chunks that a `VoxelWorld` is loading, as of the last frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Chunks in range of a viewer that were not requested yet because of the load budget.
+    pub missing: usize,
+    /// Chunks that are being loaded on the background pool or by a future.
+    pub loading: usize,
+    /// Chunks that finished loading, but are waiting for an entity because of the insert budget.
+    pub loaded: usize,
+    /// Chunks that have an entity.
+    pub ready: usize,
+    /// Chunks that failed to load, including chunks that are waiting to be retried.
+    pub failed: usize,
+}
+
+/// How a `VoxelWorld` retries chunks that failed to load.
+/// The first retry happens after `delay`, every next retry waits `backoff` times as long as the
+/// previous one, up to `max_delay`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The amount of failed attempts after which a chunk is given up on, `None` to retry forever.
+    /// Chunks for which the `VoxelSource` returned `VoxelSourceResult::Retry` are never given up on.
+    pub max_attempts: Option<usize>,
+    /// The time to wait before the first retry of a chunk.
+    pub delay: Duration,
+    /// The factor by which the wait grows with every failed attempt. The retry after `n` failed
+    /// attempts waits `delay * backoff^(n - 1)`, capped at `max_delay`. A factor of 1 retries at
+    /// a fixed interval.
+    pub backoff: f32,
+    /// The longest time to wait before a retry, however many attempts failed.
+    pub max_delay: Duration,
+}
+
+/// A chunk that failed to load. Failed chunks render nothing.
+pub struct ChunkFailure {
+    attempts: usize,
+    error: Option<Error>,
+    /// The time at which the chunk is retried, `None` if it was given up on.
+    retry_at: Option<Duration>,
+}
+
+/// A component that makes all `VoxelWorld`s load the chunks around it's entity.
+/// The entity also needs a `Transform`.
+pub struct VoxelViewer {
+    /// The shape of the chunks to load around the chunk the viewer is in.
+    pub shape: ViewShape,
+}
+
+/// The shape of the volume of chunks that is loaded around a viewer. All sizes are in chunks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ViewShape {
+    /// A box with a radius per axis.
+    Box([usize; 3]),
+    /// A sphere with a radius.
+    Sphere(usize),
+    /// A vertical cylinder with a horizontal radius,
+    /// extending `height` chunks above and below the viewer.
+    Cylinder { radius: usize, height: usize },
+}
+
+/// An event in the lifecycle of a chunk of a `VoxelWorld`.
+/// Chunk events are sent through the `EventChannel<ChunkEvent>` resource.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkEvent {
+    pub event_type: ChunkEventType,
+    /// The entity of the `VoxelWorld` that the chunk belongs to.
+    pub world: Entity,
+    /// The chunk coordinate of the chunk.
+    pub coord: [i64; 3],
+    /// The entity of the chunk, if it has one.
+    pub chunk: Option<Entity>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkEventType {
+    /// The chunk was requested from the `VoxelSource` or taken from the cache.
+    /// The chunk doesn't have an entity yet.
+    Requested,
+    /// The chunk finished loading and it's entity was created.
+    Loaded,
+    /// The mesh of the chunk was rebuilt.
+    Meshed,
+    /// The voxels of the chunk were changed. Sent right before the chunk is meshed again.
+    Modified,
+    /// The chunk is no longer needed. It's entity was deleted, if it had one.
+    Unloaded,
 }
 
 /// Utility struct for accessing `Voxel`s in a `VoxelWorld`.
@@ -44,29 +167,58 @@ pub struct VoxelWorldAccess<'a, 'b, T: Data> {
 
 pub enum VoxelSourceResult<T: Data> {
     Ok(NestedVoxel<T>),
-    Loading(Box<dyn FnOnce() -> NestedVoxel<T> + Send>),
+    /// The chunk could not be loaded. It is retried according to the `RetryPolicy` of the world.
+    Err(Error),
+    /// A job that is run on the background pool. Chunks of which the job fails are retried like
+    /// `VoxelSourceResult::Err`.
+    Loading(Box<dyn FnOnce(&CancellationToken) -> Result<NestedVoxel<T>, Error> + Send>),
+    /// A future that is polled by the `WorldSystem` every frame until it resolves, so that waiting
+    /// for IO doesn't block a thread of the pool. The future is dropped when the chunk is no longer
+    /// needed. Chunks of which the future fails are retried like `VoxelSourceResult::Err`.
+    Future(Box<dyn Future<Item = NestedVoxel<T>, Error = Error> + Send>),
+    /// The chunk is not available yet. It is requested again after the delay of the `RetryPolicy`.
     Retry,
 }
 
+/// Passed to `VoxelSourceResult::Loading` jobs, cancelled when the chunk that is being loaded
+/// is no longer needed. Long running jobs can check the token to stop early.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
 /// Voxel data source for `VoxelWorld`
 pub trait VoxelSource<'s, T: Data>: Send + Sync {
     type SystemData: SystemData<'s>;
 
     /// Load chunk at the specified chunk coordinate.
     /// After this the returned FnOnce will be run on a background thread to get the final result.
+    /// If the chunk is no longer needed before the job finished, the result is passed to `abandon_voxel`.
     fn load_voxel(
         &mut self,
         system_data: &mut Self::SystemData,
-        coord: [isize; 3],
+        coord: [i64; 3],
     ) -> VoxelSourceResult<T>;
 
     /// When a chunk is removed from the `VoxelWorld`, some sources might want to persist the changes made
     /// to the voxel. When a chunk is removed, this function will be called dispose of the chunk properly.
+    /// `modified` is `false` if the voxel was not accessed mutably since it was loaded.
     fn drop_voxel(
         &mut self,
         _system_data: &mut Self::SystemData,
-        _coord: [isize; 3],
+        _coord: [i64; 3],
         _voxel: NestedVoxel<T>,
+        _modified: bool,
+    ) -> Box<dyn FnOnce() + Send> {
+        Box::new(|| ())
+    }
+
+    /// When a chunk is no longer needed while it was still being loaded, the load is cancelled and
+    /// this function will be called to release any resources reserved for the load.
+    /// `voxel` contains the result of the load, or `None` if the job was cancelled before it started.
+    fn abandon_voxel(
+        &mut self,
+        _system_data: &mut Self::SystemData,
+        _coord: [i64; 3],
+        _voxel: Option<NestedVoxel<T>>,
     ) -> Box<dyn FnOnce() + Send> {
         Box::new(|| ())
     }
@@ -79,88 +231,565 @@ pub trait VoxelSource<'s, T: Data>: Send + Sync {
 
 pub struct WorldSystem<T: Data, S: for<'s> VoxelSource<'s, T>> {
     pool: Arc<ThreadPool>,
+    notify: NotifyHandle,
     marker: PhantomData<(T, S)>,
 }
 
+/// Chunk futures are polled every frame, so they don't need to be woken up.
+struct PollEveryFrame;
+
 /// Chunk coordinates to denote the rendering limits of a `VoxelWorld`.
 /// `None` specifies a non-existing limit, the world will be infinite in that direction.
 /// `Some` specifies an existing inclusive limit, chunk past this limit will not be requested.
 #[derive(Clone)]
 pub struct Limits {
-    pub from: [Option<isize>; 3],
-    pub to: [Option<isize>; 3],
+    pub from: [Option<i64>; 3],
+    pub to: [Option<i64>; 3],
 }
 
 pub(crate) enum Chunk<T: Data> {
     NotNeeded,
-    NotReady(Arc<AtomicCell<Option<NestedVoxel<T>>>>),
+    NotReady(Arc<Request<T>>),
+    /// A chunk that is being loaded by a `VoxelSourceResult::Future`.
+    Polling(Mutex<Spawn<Box<dyn Future<Item = NestedVoxel<T>, Error = Error> + Send>>>),
+    Loaded(NestedVoxel<T>),
+    Cached(CachedChunk<T>),
+    Failed(ChunkFailure),
     Ready(Entity),
 }
 
+/// A chunk that is being loaded on the background pool.
+pub(crate) struct Request<T: Data> {
+    token: CancellationToken,
+    /// `Some` when the job is done, containing `None` if the job was skipped because it was cancelled.
+    result: AtomicCell<Option<Option<Result<NestedVoxel<T>, Error>>>>,
+}
+
+/// The location and direction that chunks are loaded around.
+struct View {
+    position: Vec3,
+    forward: Vec3,
+    shape: Option<ViewShape>,
+}
+
+/// A box of chunk coordinates, from inclusive to exclusive,
+/// optionally limited to a shape around the center of the box.
+#[derive(Clone, Copy)]
+struct Region {
+    center: [i64; 3],
+    from: [i64; 3],
+    to: [i64; 3],
+    shape: Option<ViewShape>,
+}
+
 impl<T: Data> VoxelWorld<T> {
-    /// Create a new `VoxelWorld` component with specified render distance `dims` and a specified chunk `scale`.
+    /// Create a new `VoxelWorld` component with a specified chunk `scale`.
+    /// `dims` is the box of chunks that is loaded around the current camera when there are no
+    /// `VoxelViewer`s, see `with_shape` for other shapes.
     /// The `VoxelWorld` will still require a `VoxelSource`, that should be added to the entity separately.
     pub fn new(atlas: Handle<Atlas>, dims: [usize; 3], scale: f32) -> Self {
         Self {
-            loaded: false,
             limits: Limits {
                 from: [None; 3],
                 to: [None; 3],
             },
-            visibility: [0.0; 6],
-            view_range: 0.0,
             atlas,
-            data: (0..dims[0] * dims[1] * dims[2])
-                .map(|_| Chunk::NotNeeded)
-                .collect(),
+            data: Vec::new(),
+            lookup: HashMap::new(),
+            free: Vec::new(),
             dims,
-            origin: [0, 0, 0],
             scale,
+            transform: Mat4::identity(),
+            offset: [0.0; 3],
+            max_loads: std::usize::MAX,
+            max_inserts: std::usize::MAX,
+            view_bias: 0.5,
+            hysteresis: 0,
+            lod: Vec::new(),
+            meshing: MeshingMode::Cubes,
+            shape: None,
+            regions: Vec::new(),
+            stats: QueueStats::default(),
+            cache: ChunkCache::new(0, 0),
+            abandoned: Vec::new(),
+            retry: RetryPolicy::default(),
+            attempts: HashMap::new(),
         }
     }
 
+    /// Limit the amount of chunks that are requested from the `VoxelSource` and the amount of chunk
+    /// entities that are created per frame. Chunks nearest to the viewers are handled first.
+    pub fn with_budget(mut self, max_loads: usize, max_inserts: usize) -> Self {
+        self.max_loads = max_loads;
+        self.max_inserts = max_inserts;
+        self
+    }
+
+    /// Set how much chunks in front of the viewers are preferred over chunks behind them,
+    /// between 0 (distance only) and 1. The default is 0.5.
+    pub fn with_view_bias(mut self, view_bias: f32) -> Self {
+        self.view_bias = view_bias;
+        self
+    }
+
+    /// Load the chunks in `shape` around the current camera when there are no `VoxelViewer`s,
+    /// instead of the box specified by `dims`.
+    pub fn with_shape(mut self, shape: ViewShape) -> Self {
+        self.shape = Some(shape);
+        self
+    }
+
+    /// Keep chunks loaded until they are `margin` chunks outside of the range of all viewers,
+    /// so that moving back and forth across a chunk border doesn't reload chunks.
+    pub fn with_hysteresis(mut self, margin: usize) -> Self {
+        self.hysteresis = margin;
+        self
+    }
+
+    /// Keep up to `max_chunks` recently unloaded chunks and their meshes in memory, using at most
+    /// approximately `max_bytes` of memory for the voxels and the vertex data of the meshes.
+    /// Cached chunks are reused instead of being loaded from the `VoxelSource` when they come back
+    /// into range. Their old mesh is kept, it is only triangulated again if a neighbouring chunk
+    /// changed while they were unloaded.
+    /// The cache is disabled by default.
+    pub fn with_cache(mut self, max_chunks: usize, max_bytes: usize) -> Self {
+        self.cache = ChunkCache::new(max_chunks, max_bytes);
+        self
+    }
+
+    /// Render distant chunks with less detail. Chunks that are at least `rings[n - 1]` chunks away
+    /// from the nearest viewer are meshed at level `n` of `NestedVoxel::downsample`.
+    /// The distances should be increasing. Levels are limited to `T::SUBDIV`.
+    /// Worlds that use `MeshingMode::Smooth` always mesh chunks with full detail.
+    pub fn with_lod(mut self, rings: Vec<usize>) -> Self {
+        self.lod = rings;
+        self
+    }
+
+    /// Set how the faces of chunks are turned into triangles, see `DynamicVoxelMesh::with_meshing_mode`.
+    pub fn with_meshing_mode(mut self, meshing: MeshingMode) -> Self {
+        self.meshing = meshing;
+        self
+    }
+
+    /// Set how chunks that failed to load are retried, see `RetryPolicy`.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Iterate over the chunks that failed to load, including chunks waiting to be retried.
+    pub fn failing_chunks<'a>(&'a self) -> impl 'a + Iterator<Item = ([i64; 3], &'a ChunkFailure)> {
+        self.lookup
+            .iter()
+            .filter_map(move |(&coord, &index)| match self.data[index] {
+                Chunk::Failed(ref failure) => Some((coord, failure)),
+                _ => None,
+            })
+    }
+
+    /// Returns whether the chunk at `coord` is in the view volume of any viewer, as of the last frame.
+    /// Chunks that are kept loaded because of hysteresis are not in view.
+    pub fn in_view(&self, coord: [i64; 3]) -> bool {
+        self.regions.iter().any(|region| region.contains(coord))
+    }
+
+    /// Retrieve statistics on the chunks that are being loaded.
+    pub fn stats(&self) -> QueueStats {
+        self.stats
+    }
+
+    /// Retrieve statistics on the cache of recently unloaded chunks.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
     pub fn get<'a, R: 'a + GenericReadStorage<Component = DynamicVoxelMesh<T>>>(
         &self,
-        mut coord: [isize; 3],
+        coord: [i64; 3],
         chunks: &'a R,
     ) -> Option<&'a NestedVoxel<T>> {
-        for i in 0..3 {
-            coord[i] -= self.origin[i];
-            if coord[i] < 0 || coord[i] >= self.dims[i] as isize {
-                return None;
-            }
-        }
-
-        let index = coord[0] as usize
-            + coord[1] as usize * self.dims[0]
-            + coord[2] as usize * self.dims[0] * self.dims[1];
-
-        self.data[index]
-            .get()
+        self.entity(coord)
             .and_then(move |e| chunks.get(e))
             .map(|m| m.deref())
     }
 
     pub fn get_mut<'a, W: 'a + GenericWriteStorage<Component = DynamicVoxelMesh<T>>>(
         &self,
-        mut coord: [isize; 3],
+        coord: [i64; 3],
         chunks: &'a mut W,
     ) -> Option<&'a mut NestedVoxel<T>> {
-        for i in 0..3 {
-            coord[i] -= self.origin[i];
-            if coord[i] < 0 || coord[i] >= self.dims[i] as isize {
-                return None;
+        self.entity(coord)
+            .and_then(move |e| chunks.get_mut(e))
+            .map(|r| r.deref_mut())
+    }
+
+    /// Mark the loaded neighbours of the chunk at `coord` dirty, so that their meshes are rebuilt.
+    /// `touched` specifies per axis which sides of the chunk were changed, as `[negative, positive]`.
+    /// Only the neighbours on the face, edges and corners that were touched are marked.
+    pub fn dirty_neighbours<W: GenericWriteStorage<Component = DynamicVoxelMesh<T>>>(
+        &self,
+        coord: [i64; 3],
+        touched: [[bool; 2]; 3],
+        chunks: &mut W,
+    ) {
+        let offsets = |i: usize| {
+            let mut offsets = vec![0];
+            if touched[i][0] {
+                offsets.push(-1);
+            }
+            if touched[i][1] {
+                offsets.push(1);
+            }
+            offsets
+        };
+
+        for &z in offsets(2).iter() {
+            for &y in offsets(1).iter() {
+                for &x in offsets(0).iter() {
+                    if x != 0 || y != 0 || z != 0 {
+                        let neighbour = [coord[0] + x, coord[1] + y, coord[2] + z];
+                        if let Some(mesh) = self.entity(neighbour).and_then(|e| chunks.get_mut(e))
+                        {
+                            mesh.dirty = true;
+                        }
+                    }
+                }
             }
         }
+    }
+
+    /// The generations of the meshes of the 26 neighbours of the chunk at `coord`, `None` for
+    /// neighbours that are not loaded. See `CachedChunk`.
+    fn generations<R: GenericReadStorage<Component = DynamicVoxelMesh<T>>>(
+        &self,
+        coord: [i64; 3],
+        chunks: &R,
+    ) -> Vec<Option<u64>> {
+        neighbours(coord)
+            .map(|neighbour| {
+                self.entity(neighbour)
+                    .and_then(|e| chunks.get(e))
+                    .map(|mesh| mesh.generation)
+            })
+            .collect()
+    }
 
-        let index = coord[0] as usize
-            + coord[1] as usize * self.dims[0]
-            + coord[2] as usize * self.dims[0] * self.dims[1];
+    /// Returns whether the chunk at `coord` is loaded or being loaded.
+    pub fn contains(&self, coord: [i64; 3]) -> bool {
+        self.lookup.contains_key(&coord)
+    }
 
-        self.data[index]
-            .get()
-            .and_then(move |e| chunks.get_mut(e))
-            .map(|r| r.deref_mut())
+    /// Iterate over the coordinates of all chunks that are loaded or being loaded.
+    pub fn chunks<'a>(&'a self) -> impl 'a + Iterator<Item = [i64; 3]> {
+        self.lookup.keys().cloned()
+    }
+
+    /// Record a failed attempt to load the chunk at `coord`.
+    fn fail(&mut self, coord: [i64; 3], error: Option<Error>, now: Duration) {
+        let attempts = self.attempts.remove(&coord).unwrap_or(0) + 1;
+        let give_up = error.is_some()
+            && self
+                .retry
+                .max_attempts
+                .map(|max| attempts >= max)
+                .unwrap_or(false);
+        let retry_at = if give_up {
+            None
+        } else {
+            Some(now + self.retry.delay(attempts))
+        };
+        self.remove(coord);
+        self.insert(
+            coord,
+            Chunk::Failed(ChunkFailure {
+                attempts,
+                error,
+                retry_at,
+            }),
+        );
+    }
+
+    /// Request the chunk at `coord` from `source`.
+    /// Returns `false` if the source failed to provide the chunk right away.
+    fn request<'s, S: VoxelSource<'s, T>>(
+        &mut self,
+        coord: [i64; 3],
+        source: &mut S,
+        source_data: &mut S::SystemData,
+        pool: &ThreadPool,
+        now: Duration,
+    ) -> bool {
+        match source.load_voxel(source_data, coord) {
+            VoxelSourceResult::Ok(chunk) => self.insert(coord, Chunk::Loaded(chunk)),
+            VoxelSourceResult::Loading(job) => {
+                let request = Arc::new(Request {
+                    token: CancellationToken::default(),
+                    result: AtomicCell::default(),
+                });
+                let pending = request.clone();
+                pool.spawn(move || {
+                    let result = if pending.token.is_cancelled() {
+                        None
+                    } else {
+                        Some(job(&pending.token))
+                    };
+                    pending.result.store(Some(result));
+                });
+                self.insert(coord, Chunk::NotReady(request));
+            }
+            VoxelSourceResult::Future(future) => {
+                self.insert(coord, Chunk::Polling(Mutex::new(spawn(future))))
+            }
+            VoxelSourceResult::Err(error) => {
+                self.fail(coord, Some(error), now);
+                return false;
+            }
+            VoxelSourceResult::Retry => {
+                self.fail(coord, None, now);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Hand a chunk that was removed before it became ready back to `source`.
+    /// Chunks that are still loading are cancelled.
+    fn release<'s, S: VoxelSource<'s, T>>(
+        &mut self,
+        coord: [i64; 3],
+        chunk: Chunk<T>,
+        source: &mut S,
+        source_data: &mut S::SystemData,
+        pool: &ThreadPool,
+    ) {
+        match chunk {
+            Chunk::NotNeeded | Chunk::Failed(_) => (),
+            Chunk::NotReady(request) => {
+                request.token.cancel();
+                self.abandoned.push((coord, request));
+            }
+            Chunk::Polling(_) => {
+                // the future is cancelled by dropping it
+                let job = source.abandon_voxel(source_data, coord, None);
+                pool.spawn(move || job());
+            }
+            Chunk::Loaded(voxel) => {
+                let job = source.abandon_voxel(source_data, coord, Some(voxel));
+                pool.spawn(move || job());
+            }
+            Chunk::Cached(cached) => self.cache.insert(coord, cached),
+            Chunk::Ready(_) => unreachable!("Ready chunks are unloaded by the WorldSystem"),
+        }
+    }
+
+    /// Collect the chunks that finished loading, clean up cancelled loads and
+    /// schedule the failed chunks that are due for another attempt.
+    fn poll<'s, S: VoxelSource<'s, T>>(
+        &mut self,
+        source: &mut S,
+        source_data: &mut S::SystemData,
+        pool: &ThreadPool,
+        notify: &NotifyHandle,
+        now: Duration,
+    ) {
+        // clean up the cancelled loads that are done
+        for (coord, request) in replace(&mut self.abandoned, Vec::new()) {
+            match request.result.take() {
+                Some(voxel) => {
+                    let voxel = voxel.and_then(|loaded| loaded.ok());
+                    let job = source.abandon_voxel(source_data, coord, voxel);
+                    pool.spawn(move || job());
+                }
+                None => self.abandoned.push((coord, request)),
+            }
+        }
+
+        // collect the chunks that finished loading on the background pool or in a future
+        let mut failed = Vec::new();
+        for (&coord, &index) in self.lookup.iter() {
+            let chunk = &mut self.data[index];
+            let loaded = match chunk {
+                Chunk::NotReady(request) => match request.result.take() {
+                    // jobs are only skipped when cancelled, which doesn't happen to needed chunks
+                    Some(loaded) => match loaded.expect("Chunk load skipped") {
+                        Ok(loaded) => Some(loaded),
+                        Err(error) => {
+                            failed.push((coord, error));
+                            None
+                        }
+                    },
+                    None => None,
+                },
+                Chunk::Polling(future) => {
+                    let future = future.get_mut().unwrap();
+                    match future.poll_future_notify(notify, 0) {
+                        Ok(Async::Ready(loaded)) => Some(loaded),
+                        Ok(Async::NotReady) => None,
+                        Err(error) => {
+                            failed.push((coord, error));
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
+            if let Some(loaded) = loaded {
+                *chunk = Chunk::Loaded(loaded);
+            }
+        }
+
+        for (coord, error) in failed {
+            self.fail(coord, Some(error), now);
+            let job = source.abandon_voxel(source_data, coord, None);
+            pool.spawn(move || job());
+        }
+
+        // chunks that failed to load are requested again after their delay
+        let retries = self
+            .lookup
+            .iter()
+            .filter(|&(_, &index)| match self.data[index] {
+                Chunk::Failed(ref failure) => failure.retry_at.map_or(false, |at| at <= now),
+                _ => false,
+            })
+            .map(|(&coord, _)| coord)
+            .collect::<Vec<_>>();
+        for coord in retries {
+            if let Chunk::Failed(failure) = self.remove(coord) {
+                self.attempts.insert(coord, failure.attempts);
+            }
+        }
+    }
+
+    /// Convert an absolute chunk coordinate to an index in `data`.
+    pub(crate) fn index(&self, coord: [i64; 3]) -> Option<usize> {
+        self.lookup.get(&coord).cloned()
+    }
+
+    /// Store a chunk in a free slot.
+    fn insert(&mut self, coord: [i64; 3], chunk: Chunk<T>) {
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.data[index] = chunk;
+                index
+            }
+            None => {
+                self.data.push(chunk);
+                self.data.len() - 1
+            }
+        };
+        self.lookup.insert(coord, index);
+    }
+
+    /// Remove a chunk, freeing it's slot.
+    fn remove(&mut self, coord: [i64; 3]) -> Chunk<T> {
+        match self.lookup.remove(&coord) {
+            Some(index) => {
+                self.free.push(index);
+                replace(&mut self.data[index], Chunk::NotNeeded)
+            }
+            None => Chunk::NotNeeded,
+        }
+    }
+
+    /// The translation of the chunk at `coord` relative to the world entity.
+    pub(crate) fn chunk_translation(&self, coord: [i64; 3]) -> Vec3 {
+        let f = |i: usize| (coord[i] as f64 * self.scale as f64 - self.offset[i]) as f32;
+        vec3(f(0), f(1), f(2))
+    }
+
+    /// The chunk that contains the origin of the render space, and the position of that origin
+    /// relative to the chunk. Keeps raycasts precise when using a `FloatingOrigin`.
+    pub(crate) fn origin_chunk(&self) -> ([i64; 3], Vec3) {
+        let scale = self.scale as f64;
+        let chunk = |i: usize| (self.offset[i] / scale).floor() as i64;
+        let chunk = [chunk(0), chunk(1), chunk(2)];
+        let local = |i: usize| (self.offset[i] - chunk[i] as f64 * scale) as f32;
+        (chunk, vec3(local(0), local(1), local(2)))
+    }
+
+    /// The region of chunks that should be loaded for a viewer at `center`.
+    /// Without a shape the region is the shape of the world, or the box specified by `dims`.
+    fn region(&self, center: Vec3, shape: Option<ViewShape>) -> Region {
+        let shape = shape.or(self.shape);
+        let mut region = Region {
+            center: [0; 3],
+            from: [0; 3],
+            to: [0; 3],
+            shape,
+        };
+        for i in 0..3 {
+            let chunk = ((center[i] as f64 + self.offset[i]) / self.scale as f64).floor() as i64;
+            let (from, to) = match shape {
+                Some(shape) => {
+                    let extent = shape.extent()[i] as i64;
+                    (chunk - extent, chunk + extent + 1)
+                }
+                None => {
+                    let from = chunk - (self.dims[i] / 2) as i64;
+                    (from, from + self.dims[i] as i64)
+                }
+            };
+            region.center[i] = chunk;
+            region.from[i] = from.max(self.limits.from[i].unwrap_or(from));
+            region.to[i] = to.min(self.limits.to[i].map(|to| to + 1).unwrap_or(to));
+        }
+        region
+    }
+
+    /// Sort chunk coordinates by their distance to the nearest view, weighted by the view direction.
+    fn sort_by_priority(&self, coords: &mut [[i64; 3]], views: &[View]) {
+        let priority = |coord: &[i64; 3]| {
+            let center = self.chunk_translation(*coord) + vec3(0.5, 0.5, 0.5) * self.scale;
+            views
+                .iter()
+                .map(|view| {
+                    let delta = center - view.position;
+                    let distance = delta.magnitude();
+                    if distance > 0.0 {
+                        distance * (1.0 - self.view_bias * dot(&delta, &view.forward) / distance)
+                    } else {
+                        0.0
+                    }
+                })
+                .fold(std::f32::INFINITY, f32::min)
+        };
+        coords.sort_by(|a, b| {
+            priority(a)
+                .partial_cmp(&priority(b))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+    }
+
+    /// The level of detail of the chunk at `coord`, by it's distance to the nearest view.
+    fn level_of_detail(&self, coord: [i64; 3], views: &[View]) -> usize {
+        // smooth surfaces sampled at different strides don't line up between chunks
+        if self.meshing == MeshingMode::Smooth {
+            return 0;
+        }
+        let center = self.chunk_translation(coord) + vec3(0.5, 0.5, 0.5) * self.scale;
+        let distance = views
+            .iter()
+            .map(|view| (center - view.position).magnitude())
+            .fold(std::f32::INFINITY, f32::min)
+            / self.scale;
+        self.lod
+            .iter()
+            .take_while(|&&ring| distance >= ring as f32)
+            .count()
+            .min(T::SUBDIV)
+    }
+
+    /// Retrieve the entity of the loaded chunk at an absolute chunk coordinate.
+    pub(crate) fn entity(&self, coord: [i64; 3]) -> Option<Entity> {
+        self.index(coord).and_then(|index| self.data[index].get())
+    }
+
+    /// Get the global matrix of the world entity, as of the last frame.
+    /// Chunk `coord` is located at `coord * scale` in the space of this matrix.
+    pub fn transform(&self) -> &Mat4 {
+        &self.transform
     }
 
     /// Get a `Handle<Atlas>` to the texture atlas used by this `VoxelWorld`
@@ -181,20 +810,148 @@ impl<'a, 'b, V: Data> VoxelWorldAccess<'a, 'b, V> {
         Self { world, chunks }
     }
 
-    pub fn get(&self, coord: [isize; 3]) -> Option<&NestedVoxel<V>> {
+    pub fn get(&self, coord: [i64; 3]) -> Option<&NestedVoxel<V>> {
         self.world.get(coord, self.chunks)
     }
 
-    pub fn get_mut(&mut self, coord: [isize; 3]) -> Option<&mut NestedVoxel<V>> {
+    pub fn get_mut(&mut self, coord: [i64; 3]) -> Option<&mut NestedVoxel<V>> {
         self.world.get_mut(coord, self.chunks)
     }
 }
 
+impl VoxelViewer {
+    /// Create a new `VoxelViewer` that loads the chunks in `shape`.
+    pub fn new(shape: ViewShape) -> Self {
+        VoxelViewer { shape }
+    }
+}
+
+impl ViewShape {
+    /// The radius of the bounding box of this shape, per axis.
+    fn extent(&self) -> [usize; 3] {
+        match *self {
+            ViewShape::Box(radius) => radius,
+            ViewShape::Sphere(radius) => [radius; 3],
+            ViewShape::Cylinder { radius, height } => [radius, height, radius],
+        }
+    }
+
+    /// Returns whether the chunk at `offset` from the center chunk is part of this shape.
+    fn contains(&self, offset: [i64; 3]) -> bool {
+        let squared = |r: usize| (r * r) as i64;
+        let [x, y, z] = offset;
+        match *self {
+            ViewShape::Box(_) => true,
+            ViewShape::Sphere(radius) => x * x + y * y + z * z <= squared(radius),
+            ViewShape::Cylinder { radius, .. } => x * x + z * z <= squared(radius),
+        }
+    }
+
+    fn grow(self, margin: usize) -> Self {
+        match self {
+            ViewShape::Box(radius) => {
+                ViewShape::Box([radius[0] + margin, radius[1] + margin, radius[2] + margin])
+            }
+            ViewShape::Sphere(radius) => ViewShape::Sphere(radius + margin),
+            ViewShape::Cylinder { radius, height } => ViewShape::Cylinder {
+                radius: radius + margin,
+                height: height + margin,
+            },
+        }
+    }
+}
+
+impl Component for VoxelViewer {
+    type Storage = HashMapStorage<Self>;
+}
+
+impl RetryPolicy {
+    /// The delay before the next attempt after `attempts` failed attempts.
+    fn delay(&self, attempts: usize) -> Duration {
+        let factor = f64::from(self.backoff).powi(attempts.saturating_sub(1) as i32);
+        let delay = self.delay.as_secs_f64() * factor;
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: Some(5),
+            delay: Duration::from_millis(250),
+            backoff: 2.0,
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ChunkFailure {
+    /// The amount of failed attempts to load the chunk.
+    pub fn attempts(&self) -> usize {
+        self.attempts
+    }
+
+    /// The error of the last attempt, `None` if the `VoxelSource` asked to retry the chunk.
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    /// Returns whether the chunk was given up on. It is not retried until it is unloaded.
+    pub fn is_permanent(&self) -> bool {
+        self.retry_at.is_none()
+    }
+}
+
+impl CancellationToken {
+    /// Returns whether the chunk that is being loaded is no longer needed.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Region {
+    fn contains(&self, coord: [i64; 3]) -> bool {
+        let offset = [
+            coord[0] - self.center[0],
+            coord[1] - self.center[1],
+            coord[2] - self.center[2],
+        ];
+        (0..3).all(|i| coord[i] >= self.from[i] && coord[i] < self.to[i])
+            && self.shape.map(|s| s.contains(offset)).unwrap_or(true)
+    }
+
+    fn grow(mut self, margin: usize) -> Region {
+        for i in 0..3 {
+            self.from[i] -= margin as i64;
+            self.to[i] += margin as i64;
+        }
+        self.shape = self.shape.map(|s| s.grow(margin));
+        self
+    }
+
+    fn coords(self) -> impl Iterator<Item = [i64; 3]> {
+        (self.from[2]..self.to[2])
+            .flat_map(move |z| {
+                (self.from[1]..self.to[1])
+                    .flat_map(move |y| (self.from[0]..self.to[0]).map(move |x| [x, y, z]))
+            })
+            .filter(move |&coord| self.contains(coord))
+    }
+}
+
 impl<T: Data> Chunk<T> {
     pub fn get(&self) -> Option<Entity> {
         match *self {
             Chunk::NotNeeded => None,
             Chunk::NotReady(_) => None,
+            Chunk::Polling(_) => None,
+            Chunk::Loaded(_) => None,
+            Chunk::Cached(_) => None,
+            Chunk::Failed(_) => None,
             Chunk::Ready(voxel) => Some(voxel),
         }
     }
@@ -204,21 +961,33 @@ impl<T: Data, S: for<'s> VoxelSource<'s, T>> WorldSystem<T, S> {
     pub fn new(pool: Arc<ThreadPool>) -> Self {
         WorldSystem {
             marker: PhantomData,
+            notify: NotifyHandle::from(Arc::new(PollEveryFrame)),
             pool,
         }
     }
 }
 
+impl Notify for PollEveryFrame {
+    fn notify(&self, _id: usize) {}
+}
+
 impl<'s, T: Data, S: for<'a> VoxelSource<'a, T> + Component> System<'s> for WorldSystem<T, S> {
     #[allow(clippy::type_complexity)]
     type SystemData = (
         WriteStorage<'s, VoxelWorld<T>>,
         WriteStorage<'s, DynamicVoxelMesh<T>>,
+        WriteStorage<'s, Handle<VoxelMesh>>,
+        Read<'s, AssetStorage<VoxelMesh>>,
         WriteStorage<'s, S>,
         Entities<'s>,
         Read<'s, ActiveCamera>,
         ReadStorage<'s, Camera>,
+        ReadStorage<'s, VoxelViewer>,
         WriteStorage<'s, Transform>,
+        WriteStorage<'s, Parent>,
+        Option<Read<'s, FloatingOrigin>>,
+        Read<'s, Time>,
+        Write<'s, EventChannel<ChunkEvent>>,
         <S as VoxelSource<'s, T>>::SystemData,
     );
 
@@ -227,225 +996,585 @@ impl<'s, T: Data, S: for<'a> VoxelSource<'a, T> + Component> System<'s> for Worl
         (
             mut worlds,
             mut meshes,
+            mut handles,
+            mesh_storage,
             mut sources,
             entities,
             active_camera,
             cameras,
+            viewers,
             mut transforms,
+            mut parents,
+            floating_origin,
+            time,
+            mut events,
             mut source_data,
         ): Self::SystemData,
     ) {
-        let identity_transform = Transform::default();
-
-        let transform = active_camera
-            .entity
-            .as_ref()
-            .and_then(|ac| transforms.get(*ac))
-            .or_else(|| (&cameras, &transforms).join().next().map(|(_c, t)| t))
-            .unwrap_or(&identity_transform);
-
-        let center = {
-            let m = transform.global_matrix().column(3).xyz();
-            [m[0], m[1], m[2]]
+        let view = |transform: &Transform, shape| {
+            let m = transform.global_matrix();
+            View {
+                position: m.column(3).xyz(),
+                // cameras look along the negative z axis
+                forward: -m.column(2).xyz().normalize(),
+                shape,
+            }
         };
 
+        let mut views = (&viewers, &transforms)
+            .join()
+            .map(|(viewer, transform)| view(transform, Some(viewer.shape)))
+            .collect::<Vec<_>>();
+
+        // fall back to the camera if there are no viewers
+        if views.is_empty() {
+            let identity_transform = Transform::default();
+            let transform = active_camera
+                .entity
+                .as_ref()
+                .and_then(|ac| transforms.get(*ac))
+                .or_else(|| (&cameras, &transforms).join().next().map(|(_c, t)| t))
+                .unwrap_or(&identity_transform);
+            views.push(view(transform, None));
+        }
+
         for (world_entity, world, source) in (&entities, &mut worlds, &mut sources).join() {
-            let limits = source.limits();
             world.limits = source.limits();
+            world.transform = transforms
+                .get(world_entity)
+                .map(|t| *t.global_matrix())
+                .unwrap_or_else(Mat4::identity);
 
-            let origin = {
-                let f = |i: usize| {
-                    let origin =
-                        (center[i] / world.scale).floor() as isize - (world.dims[i] / 2) as isize;
-                    origin
-                        .max(limits.from[i].unwrap_or(origin))
-                        .min(limits.to[i].unwrap_or(origin))
-                };
-                [f(0), f(1), f(2)]
+            // worlds with a transform are moved by the floating origin like any other entity,
+            // worlds without one move their chunks instead
+            let offset = match floating_origin {
+                Some(ref floating_origin) if !transforms.contains(world_entity) => {
+                    floating_origin.offset()
+                }
+                _ => [0.0; 3],
             };
+            if offset != world.offset {
+                world.offset = offset;
+                for (&coord, &index) in world.lookup.iter() {
+                    let chunk = world.data[index].get();
+                    if let Some(transform) = chunk.and_then(|e| transforms.get_mut(e)) {
+                        transform.set_translation(world.chunk_translation(coord));
+                    }
+                }
+            }
 
-            for (i, &center) in center.iter().enumerate() {
-                world.visibility[i * 2] = center - world.scale * (world.dims[i] / 2) as f32;
-                world.visibility[i * 2 + 1] =
-                    world.visibility[i * 2] + world.scale * world.dims[i] as f32;
+            // load chunks around the viewers as seen from the world
+            let inverse = world.transform.try_inverse().unwrap_or_else(Mat4::identity);
+            let views = views
+                .iter()
+                .map(|view| View {
+                    position: (inverse
+                        * vec4(view.position[0], view.position[1], view.position[2], 1.0))
+                    .xyz(),
+                    forward: inverse.transform_vector(&view.forward).normalize(),
+                    shape: view.shape,
+                })
+                .collect::<Vec<_>>();
+
+            world.regions = views
+                .iter()
+                .map(|view| world.region(view.position, view.shape))
+                .collect();
+            let regions = world.regions.clone();
+            let mut arrived = Vec::new();
+
+            // drop the chunks that are not in range of any viewer
+            let unneeded = world
+                .chunks()
+                .filter(|&coord| {
+                    !regions
+                        .iter()
+                        .any(|r| r.grow(world.hysteresis).contains(coord))
+                })
+                .collect::<Vec<_>>();
+            for coord in unneeded {
+                let chunk = world.remove(coord);
+                events.single_write(ChunkEvent {
+                    event_type: ChunkEventType::Unloaded,
+                    world: world_entity,
+                    coord,
+                    chunk: chunk.get(),
+                });
+
+                match chunk {
+                    Chunk::Ready(entity) => {
+                        let mesh = meshes.get_mut(entity).unwrap();
+                        let lod = mesh.lod;
+                        let outdated = mesh.dirty;
+                        let modified = mesh.unsaved;
+                        let voxel = replace(mesh.deref_mut(), NestedVoxel::Placeholder);
+                        if world.cache.enabled() {
+                            let mesh = handles.remove(entity);
+                            let mesh_bytes = mesh
+                                .as_ref()
+                                .and_then(|mesh| mesh_storage.get(mesh))
+                                .map_or(0, |mesh| mesh.bytes);
+                            let neighbours = if outdated {
+                                None
+                            } else {
+                                Some(world.generations(coord, &meshes))
+                            };
+                            let cached =
+                                CachedChunk::new(voxel.clone(), mesh, mesh_bytes, lod, neighbours);
+                            world.cache.insert(coord, cached);
+                        }
+                        entities.delete(entity).expect("Remove chunk entity failed");
+                        let job = source.drop_voxel(&mut source_data, coord, voxel, modified);
+                        self.pool.spawn(move || job());
+                    }
+                    chunk => world.release(coord, chunk, source, &mut source_data, &self.pool),
+                }
             }
 
-            let offset = {
-                let f = |i| origin[i] - world.origin[i];
-                [f(0), f(1), f(2)]
+            let hysteresis = world.hysteresis;
+            world
+                .attempts
+                .retain(|&coord, _| regions.iter().any(|r| r.grow(hysteresis).contains(coord)));
+
+            let now = time.absolute_time();
+            world.poll(source, &mut source_data, &self.pool, &self.notify, now);
+
+            // request the missing chunks nearest to the viewers first
+            let mut missing = regions
+                .iter()
+                .flat_map(|region| region.coords())
+                .filter(|&coord| !world.contains(coord))
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>();
+            world.sort_by_priority(&mut missing, &views);
+
+            let mut requested = 0;
+            let mut waiting = 0;
+            let request_event = |coord| ChunkEvent {
+                event_type: ChunkEventType::Requested,
+                world: world_entity,
+                coord,
+                chunk: None,
             };
-            let dims = [
-                world.dims[0] as isize,
-                world.dims[1] as isize,
-                world.dims[2] as isize,
-            ];
-
-            fn limit_visibility(v: &mut [f32; 6], center: [f32; 3], limit: [f32; 3], scale: f32) {
-                for i in 0..3 {
-                    if limit[i] + scale < center[i] {
-                        v[i * 2] = v[i * 2].max(limit[i] + scale);
-                    } else {
-                        v[i * 2 + 1] = v[i * 2 + 1].min(limit[i]);
-                    }
+            for &coord in missing.iter() {
+                if let Some(cached) = world.cache.take(coord) {
+                    world.insert(coord, Chunk::Cached(cached));
+                    events.single_write(request_event(coord));
+                    continue;
+                }
+                if requested == world.max_loads {
+                    waiting += 1;
+                    continue;
+                }
+
+                requested += 1;
+                world.cache.miss();
+                if world.request(coord, source, &mut source_data, &self.pool, now) {
+                    events.single_write(request_event(coord));
                 }
             }
 
-            fn for_loop(to: isize, reverse: bool, mut f: impl FnMut(isize)) {
-                let range = 0..to;
-                if reverse {
-                    for i in range.rev() {
-                        f(i);
-                    }
-                } else {
-                    for i in range {
-                        f(i);
+            // create entities for the loaded chunks nearest to the viewers first
+            let mut loaded = world
+                .lookup
+                .iter()
+                .filter(|&(_, &index)| match world.data[index] {
+                    Chunk::Loaded(_) | Chunk::Cached(_) => true,
+                    _ => false,
+                })
+                .map(|(&coord, _)| coord)
+                .collect::<Vec<_>>();
+            world.sort_by_priority(&mut loaded, &views);
+            loaded.truncate(world.max_inserts);
+
+            for coord in loaded {
+                let index = world.lookup[&coord];
+                let (chunk, mesh, lod, changed) =
+                    match replace(&mut world.data[index], Chunk::NotNeeded) {
+                        Chunk::Loaded(chunk) => (chunk, None, 0, vec![true; 26]),
+                        Chunk::Cached(cached) => {
+                            let changed = cached.changed(&world.generations(coord, &meshes));
+                            (cached.voxel, cached.mesh, cached.lod, changed)
+                        }
+                        _ => unreachable!(),
+                    };
+                let entity = spawn_chunk(
+                    &entities,
+                    &mut meshes,
+                    &mut transforms,
+                    world_entity,
+                    world,
+                    coord,
+                    chunk,
+                );
+
+                parents.insert(entity, Parent::new(world_entity)).ok();
+
+                // cached chunks keep their old mesh, it is only triangulated again if any of their
+                // neighbours changed while they were unloaded
+                if let Some(mesh) = mesh {
+                    let dynamic_mesh = meshes.get_mut(entity).unwrap();
+                    dynamic_mesh.lod = lod;
+                    dynamic_mesh.dirty = changed.iter().any(|&changed| changed);
+                    handles.insert(entity, mesh).ok();
+                }
+                world.data[index] = Chunk::Ready(entity);
+                world.attempts.remove(&coord);
+                events.single_write(ChunkEvent {
+                    event_type: ChunkEventType::Loaded,
+                    world: world_entity,
+                    coord,
+                    chunk: Some(entity),
+                });
+                arrived.push((coord, changed));
+            }
+
+            // neighbours of new chunks have to be rebuilt to remove faces and fix ambient occlusion,
+            // unless they did not change since a cached chunk was unloaded
+            for (coord, changed) in arrived {
+                let changed = neighbours(coord)
+                    .zip(changed)
+                    .filter(|&(_, changed)| changed);
+                for (neighbour, _) in changed {
+                    if let Some(mesh) = world.entity(neighbour).and_then(|e| meshes.get_mut(e)) {
+                        mesh.dirty = true;
                     }
                 }
             }
 
-            for_loop(dims[2], offset[2] < 0, |z| {
-                let exists = z + offset[2] >= 0 && z + offset[2] < dims[2];
-                for_loop(dims[1], offset[1] < 0, |y| {
-                    let exists = exists && y + offset[1] >= 0 && y + offset[1] < dims[1];
-                    for_loop(dims[0], offset[0] < 0, |x| {
-                        let exists = exists && x + offset[0] >= 0 && x + offset[0] < dims[0];
-                        let index = (z * dims[0] * dims[1] + y * dims[0] + x) as usize;
-
-                        // retrieve the existing chunk
-                        let moved_chunk = if exists {
-                            let index = ((z + offset[2]) * dims[0] * dims[1]
-                                + (y + offset[1]) * dims[0]
-                                + (x + offset[0])) as usize;
-                            replace(&mut world.data[index], Chunk::NotNeeded)
-                        } else {
-                            Chunk::NotNeeded
-                        };
-
-                        // process the chunk
-                        let moved_chunk = match moved_chunk {
-                            Chunk::NotNeeded => {
-                                // todo: *check* if the chunk needs to be loaded
-                                let coord = [x + origin[0], y + origin[1], z + origin[2]];
-                                limit_visibility(
-                                    &mut world.visibility,
-                                    center,
-                                    [
-                                        coord[0] as f32 * world.scale,
-                                        coord[1] as f32 * world.scale,
-                                        coord[2] as f32 * world.scale,
-                                    ],
-                                    world.scale,
-                                );
-
-                                match source.load_voxel(&mut source_data, coord) {
-                                    VoxelSourceResult::Ok(chunk) => {
-                                        let entity = entities.create();
-                                        let mut mesh =
-                                            DynamicVoxelMesh::new(chunk, world.atlas.clone());
-                                        let mut transform = Transform::default();
-                                        transform.set_scale(Vector3::new(
-                                            world.scale,
-                                            world.scale,
-                                            world.scale,
-                                        ));
-                                        transform.set_translation(vec3(
-                                            coord[0] as f32 * world.scale,
-                                            coord[1] as f32 * world.scale,
-                                            coord[2] as f32 * world.scale,
-                                        ));
-                                        mesh.transform = identity();
-                                        mesh.parent = Some((world_entity, [x, y, z]));
-                                        meshes.insert(entity, mesh).ok();
-                                        transforms.insert(entity, transform).ok();
-                                        Chunk::Ready(entity)
-                                    }
-                                    VoxelSourceResult::Loading(job) => {
-                                        let request = Arc::new(AtomicCell::default());
-                                        let weak = Arc::downgrade(&request);
-                                        self.pool.spawn(move || {
-                                            if let Some(request) = weak.upgrade() {
-                                                let result = job();
-                                                request.store(Some(result));
-                                            }
-                                        });
-                                        Chunk::NotReady(request)
-                                    }
-                                    VoxelSourceResult::Retry => {
-                                        world.loaded = false;
-                                        Chunk::NotNeeded
-                                    }
-                                }
-                            }
-                            Chunk::NotReady(request) => {
-                                let coord = [x + origin[0], y + origin[1], z + origin[2]];
-                                limit_visibility(
-                                    &mut world.visibility,
-                                    center,
-                                    [
-                                        coord[0] as f32 * world.scale,
-                                        coord[1] as f32 * world.scale,
-                                        coord[2] as f32 * world.scale,
-                                    ],
-                                    world.scale,
-                                );
-
-                                match request.take() {
-                                    Some(chunk) => {
-                                        let entity = entities.create();
-                                        let mut mesh =
-                                            DynamicVoxelMesh::new(chunk, world.atlas.clone());
-                                        let mut transform = Transform::default();
-                                        transform.set_scale(vec3(
-                                            world.scale,
-                                            world.scale,
-                                            world.scale,
-                                        ));
-                                        transform.set_translation(vec3(
-                                            coord[0] as f32 * world.scale,
-                                            coord[1] as f32 * world.scale,
-                                            coord[2] as f32 * world.scale,
-                                        ));
-                                        mesh.transform = identity();
-                                        mesh.parent = Some((world_entity, [x, y, z]));
-                                        meshes.insert(entity, mesh).ok();
-                                        transforms.insert(entity, transform).ok();
-                                        Chunk::Ready(entity)
-                                    }
-                                    None => Chunk::NotReady(request),
-                                }
-                            }
-                            Chunk::Ready(voxel) => Chunk::Ready(voxel),
-                        };
-
-                        // install the chunk
-                        match replace(&mut world.data[index], moved_chunk) {
-                            Chunk::NotReady(_future) => { /* this is a problem */ }
-                            Chunk::Ready(entity) => {
-                                let coord = [x + origin[0], y + origin[1], z + origin[2]];
-                                let voxel = replace(
-                                    meshes.get_mut(entity).unwrap().deref_mut(),
-                                    NestedVoxel::Placeholder,
-                                );
-                                entities.delete(entity).expect("Remove chunk entity failed");
-                                let job = source.drop_voxel(&mut source_data, coord, voxel);
-                                self.pool.spawn(move || job());
-                            }
-                            Chunk::NotNeeded => (),
+            // lower the detail of distant chunks, neighbours are rebuilt to fix the faces between them
+            if !world.lod.is_empty() {
+                let mut changed = Vec::new();
+                for (&coord, &index) in world.lookup.iter() {
+                    let lod = world.level_of_detail(coord, &views);
+                    if let Some(mesh) = world.data[index].get().and_then(|e| meshes.get_mut(e)) {
+                        if mesh.lod != lod {
+                            mesh.lod = lod;
+                            mesh.dirty = true;
+                            mesh.generation = next_generation();
+                            changed.push(coord);
                         }
-                    })
-                })
-            });
+                    }
+                }
+                for coord in changed {
+                    world.dirty_neighbours(coord, [[true; 2]; 3], &mut meshes);
+                }
+            }
 
-            world.origin = origin;
+            world.stats = QueueStats {
+                missing: waiting,
+                loading: 0,
+                loaded: 0,
+                ready: 0,
+                failed: 0,
+            };
+            for chunk in world.data.iter() {
+                match chunk {
+                    Chunk::NotNeeded => (),
+                    Chunk::NotReady(_) | Chunk::Polling(_) => world.stats.loading += 1,
+                    Chunk::Loaded(_) | Chunk::Cached(_) => world.stats.loaded += 1,
+                    Chunk::Failed(_) => world.stats.failed += 1,
+                    Chunk::Ready(_) => world.stats.ready += 1,
+                }
+            }
+        }
+    }
+}
 
-            // todo: find out view range
-            world.view_range = world
-                .visibility
-                .iter()
-                .enumerate()
-                .fold(1000.0, |view_range, (i, visibility)| {
-                    view_range.min((visibility - center[i / 2]).abs())
-                });
+/// The coordinates of the 26 neighbours of the chunk at `coord`.
+fn neighbours(coord: [i64; 3]) -> impl Iterator<Item = [i64; 3]> {
+    (0..27).filter(|&i| i != 13).map(move |i| {
+        [
+            coord[0] + i % 3 - 1,
+            coord[1] + i / 3 % 3 - 1,
+            coord[2] + i / 9 - 1,
+        ]
+    })
+}
+
+/// Create the entity for a chunk that finished loading.
+fn spawn_chunk<T: Data>(
+    entities: &Entities,
+    meshes: &mut WriteStorage<DynamicVoxelMesh<T>>,
+    transforms: &mut WriteStorage<Transform>,
+    world_entity: Entity,
+    world: &VoxelWorld<T>,
+    coord: [i64; 3],
+    chunk: NestedVoxel<T>,
+) -> Entity {
+    let entity = entities.create();
+    let mut mesh =
+        DynamicVoxelMesh::new(chunk, world.atlas.clone()).with_meshing_mode(world.meshing);
+    let mut transform = Transform::default();
+    transform.set_scale(vec3(world.scale, world.scale, world.scale));
+    transform.set_translation(world.chunk_translation(coord));
+    mesh.transform = identity();
+    mesh.parent = Some((world_entity, coord));
+    meshes.insert(entity, mesh).ok();
+    transforms.insert(entity, transform).ok();
+    entity
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::sync::mpsc::channel;
+    use std::thread::sleep;
+    use std::time::Instant;
+
+    /// Hands out the queued results in order and records the abandoned chunks.
+    #[derive(Default)]
+    struct MockSource {
+        results: Mutex<VecDeque<VoxelSourceResult<()>>>,
+        abandoned: Mutex<Vec<([i64; 3], bool)>>,
+    }
+
+    /// Resolves to an empty chunk once `until` has passed.
+    struct Delayed {
+        until: Instant,
+    }
+
+    impl MockSource {
+        fn new(results: Vec<VoxelSourceResult<()>>) -> Self {
+            MockSource {
+                results: Mutex::new(results.into_iter().collect()),
+                abandoned: Default::default(),
+            }
+        }
+    }
+
+    impl<'s> VoxelSource<'s, ()> for MockSource {
+        type SystemData = ();
+
+        fn load_voxel(&mut self, _: &mut (), _: [i64; 3]) -> VoxelSourceResult<()> {
+            self.results.get_mut().unwrap().pop_front().unwrap()
+        }
+
+        fn abandon_voxel(
+            &mut self,
+            _: &mut (),
+            coord: [i64; 3],
+            voxel: Option<NestedVoxel<()>>,
+        ) -> Box<dyn FnOnce() + Send> {
+            self.abandoned
+                .lock()
+                .unwrap()
+                .push((coord, voxel.is_some()));
+            Box::new(|| ())
+        }
+
+        fn limits(&self) -> Limits {
+            Limits {
+                from: [None; 3],
+                to: [None; 3],
+            }
+        }
+    }
+
+    impl Future for Delayed {
+        type Item = NestedVoxel<()>;
+        type Error = Error;
+
+        fn poll(&mut self) -> Result<Async<NestedVoxel<()>>, Error> {
+            if Instant::now() >= self.until {
+                Ok(Async::Ready(NestedVoxel::new_empty(())))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    const COORD: [i64; 3] = [1, 2, 3];
+
+    fn world() -> VoxelWorld<()> {
+        let atlas = AssetStorage::<Atlas>::new().allocate();
+        VoxelWorld::new(atlas, [1, 1, 1], 1.0).with_retry_policy(RetryPolicy {
+            max_attempts: Some(2),
+            delay: Duration::from_secs(1),
+            backoff: 2.0,
+            max_delay: Duration::from_secs(30),
+        })
+    }
+
+    fn pool() -> ThreadPool {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+    }
+
+    fn notify() -> NotifyHandle {
+        NotifyHandle::from(Arc::new(PollEveryFrame))
+    }
+
+    fn chunk(world: &VoxelWorld<()>, coord: [i64; 3]) -> Option<&Chunk<()>> {
+        world.index(coord).map(|index| &world.data[index])
+    }
+
+    fn is_loaded(world: &VoxelWorld<()>, coord: [i64; 3]) -> bool {
+        match chunk(world, coord) {
+            Some(Chunk::Loaded(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn failure(world: &VoxelWorld<()>, coord: [i64; 3]) -> Option<&ChunkFailure> {
+        match chunk(world, coord) {
+            Some(Chunk::Failed(failure)) => Some(failure),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn smooth_worlds_keep_full_detail() {
+        #[derive(Clone, Default)]
+        struct Detailed;
+
+        impl Data for Detailed {
+            const SUBDIV: usize = 2;
+            type Child = SimpleVoxel;
+        }
+
+        let views = [View {
+            position: vec3(0.5, 0.5, 0.5),
+            forward: vec3(0.0, 0.0, 1.0),
+            shape: None,
+        }];
+        let atlas = AssetStorage::<Atlas>::new().allocate();
+        let (near, far) = ([0, 0, 0], [4, 0, 0]);
+
+        // the two chunks would be meshed at different strides
+        let cubes = VoxelWorld::<Detailed>::new(atlas, [1, 1, 1], 1.0).with_lod(vec![2]);
+        assert_eq!(cubes.level_of_detail(near, &views), 0);
+        assert_eq!(cubes.level_of_detail(far, &views), 1);
+
+        let smooth = cubes.with_meshing_mode(MeshingMode::Smooth);
+        assert_eq!(smooth.level_of_detail(near, &views), 0);
+        assert_eq!(smooth.level_of_detail(far, &views), 0);
+    }
+
+    #[test]
+    fn neighbours_surround_the_chunk() {
+        let coord = [5, -3, 0];
+        let neighbours = neighbours(coord).collect::<HashSet<_>>();
+        assert_eq!(neighbours.len(), 26);
+        assert!(!neighbours.contains(&coord));
+        assert!(neighbours
+            .iter()
+            .all(|n| (0..3).all(|i| (n[i] - coord[i]).abs() <= 1)));
+    }
+
+    #[test]
+    fn future_resolves_after_delay() {
+        let until = Instant::now() + Duration::from_millis(50);
+        let mut source =
+            MockSource::new(vec![VoxelSourceResult::Future(Box::new(Delayed { until }))]);
+        let (mut world, pool, notify) = (world(), pool(), notify());
+        let now = Duration::from_secs(0);
+
+        assert!(world.request(COORD, &mut source, &mut (), &pool, now));
+        world.poll(&mut source, &mut (), &pool, &notify, now);
+        match chunk(&world, COORD) {
+            Some(Chunk::Polling(_)) => (),
+            _ => panic!("the future resolved early"),
+        }
+
+        sleep(until.saturating_duration_since(Instant::now()));
+        world.poll(&mut source, &mut (), &pool, &notify, now);
+        assert!(is_loaded(&world, COORD));
+    }
+
+    #[test]
+    fn failed_loads_are_retried() {
+        let mut source = MockSource::new(vec![
+            VoxelSourceResult::Err(Error::from_string("unavailable")),
+            VoxelSourceResult::Ok(NestedVoxel::new_empty(())),
+        ]);
+        let (mut world, pool, notify) = (world(), pool(), notify());
+
+        let now = Duration::from_secs(0);
+        assert!(!world.request(COORD, &mut source, &mut (), &pool, now));
+        let failed = failure(&world, COORD).unwrap();
+        assert_eq!(failed.attempts(), 1);
+        assert!(failed.error().is_some());
+        assert!(!failed.is_permanent());
+
+        // not retried before the delay of the retry policy
+        let now = Duration::from_millis(500);
+        world.poll(&mut source, &mut (), &pool, &notify, now);
+        assert!(failure(&world, COORD).is_some());
+
+        let now = Duration::from_secs(1);
+        world.poll(&mut source, &mut (), &pool, &notify, now);
+        assert!(!world.contains(COORD));
+        assert!(world.request(COORD, &mut source, &mut (), &pool, now));
+        assert!(is_loaded(&world, COORD));
+    }
+
+    #[test]
+    fn failed_loads_are_given_up() {
+        let mut source = MockSource::new(vec![
+            VoxelSourceResult::Loading(Box::new(|_| Err(Error::from_string("corrupted")))),
+            VoxelSourceResult::Err(Error::from_string("corrupted")),
+        ]);
+        let (mut world, pool, notify) = (world(), pool(), notify());
+
+        let now = Duration::from_secs(0);
+        assert!(world.request(COORD, &mut source, &mut (), &pool, now));
+        while failure(&world, COORD).is_none() {
+            world.poll(&mut source, &mut (), &pool, &notify, now);
+        }
+        // the failed job is abandoned without a voxel
+        assert_eq!(*source.abandoned.lock().unwrap(), vec![(COORD, false)]);
+
+        let now = Duration::from_secs(1);
+        world.poll(&mut source, &mut (), &pool, &notify, now);
+        assert!(!world.request(COORD, &mut source, &mut (), &pool, now));
+        let failed = failure(&world, COORD).unwrap();
+        assert_eq!(failed.attempts(), 2);
+        assert!(failed.is_permanent());
+
+        world.poll(
+            &mut source,
+            &mut (),
+            &pool,
+            &notify,
+            Duration::from_secs(60),
+        );
+        assert!(failure(&world, COORD).is_some());
+    }
+
+    #[test]
+    fn abandoned_while_loading() {
+        let (started, wait_started) = channel();
+        let (finish, wait_finish) = channel::<()>();
+        let (cancelled, was_cancelled) = channel();
+        let mut source =
+            MockSource::new(vec![VoxelSourceResult::Loading(Box::new(move |token| {
+                started.send(()).unwrap();
+                wait_finish.recv().unwrap();
+                cancelled.send(token.is_cancelled()).unwrap();
+                Ok(NestedVoxel::new_empty(()))
+            }))]);
+        let (mut world, pool, notify) = (world(), pool(), notify());
+        let now = Duration::from_secs(0);
+
+        assert!(world.request(COORD, &mut source, &mut (), &pool, now));
+        wait_started.recv().unwrap();
+        let chunk = world.remove(COORD);
+        world.release(COORD, chunk, &mut source, &mut (), &pool);
+        finish.send(()).unwrap();
+        assert!(was_cancelled.recv().unwrap());
+
+        // the result of the job is handed to the source once it is done
+        while source.abandoned.lock().unwrap().is_empty() {
+            world.poll(&mut source, &mut (), &pool, &notify, now);
         }
+        assert_eq!(*source.abandoned.lock().unwrap(), vec![(COORD, true)]);
+        assert!(world.abandoned.is_empty());
+        assert!(!world.contains(COORD));
     }
 }